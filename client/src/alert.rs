@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::wandb_internal;
+
+/// Normalizes an alert level to the form the backend expects.
+pub fn parse_level(level: &str) -> Result<&'static str, String> {
+    match level.to_lowercase().as_str() {
+        "info" => Ok("INFO"),
+        "warn" | "warning" => Ok("WARN"),
+        "error" => Ok("ERROR"),
+        _ => Err(format!(
+            "Invalid alert level {:?}, expected one of info, warn, error",
+            level
+        )),
+    }
+}
+
+pub fn alert_record(
+    title: &str,
+    text: &str,
+    level: &str,
+    wait_duration: Duration,
+) -> wandb_internal::AlertRecord {
+    wandb_internal::AlertRecord {
+        title: title.to_string(),
+        text: text.to_string(),
+        level: level.to_string(),
+        wait_duration: wait_duration.as_secs() as i64,
+        ..Default::default()
+    }
+}
+
+/// Remembers when alerts were last sent, so that an identical alert raised
+/// again within its wait duration is not sent twice.
+#[derive(Default)]
+pub struct AlertLimiter {
+    // when each alert was sent, and for how long repeats are dropped
+    last_sent: HashMap<(String, String, String), (Instant, Duration)>,
+}
+
+impl AlertLimiter {
+    /// Whether the alert should be sent now. If so, it counts as sent.
+    pub fn should_send(&mut self, title: &str, text: &str, level: &str, window: Duration) -> bool {
+        let now = Instant::now();
+        // forget alerts whose window has passed, the map would grow forever otherwise
+        self.last_sent
+            .retain(|_, (sent, window)| now.duration_since(*sent) < *window);
+
+        let key = (title.to_string(), text.to_string(), level.to_string());
+        if self.last_sent.contains_key(&key) {
+            return false;
+        }
+        self.last_sent.insert(key, (now, window));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level("info").unwrap(), "INFO");
+        assert_eq!(parse_level("WARN").unwrap(), "WARN");
+        assert_eq!(parse_level("warning").unwrap(), "WARN");
+        assert_eq!(parse_level("Error").unwrap(), "ERROR");
+        assert!(parse_level("fatal").is_err());
+    }
+
+    #[test]
+    fn builds_alert_records() {
+        let record = alert_record(
+            "Loss is NaN",
+            "at step 10",
+            "ERROR",
+            Duration::from_secs(300),
+        );
+        assert_eq!(record.title, "Loss is NaN");
+        assert_eq!(record.text, "at step 10");
+        assert_eq!(record.level, "ERROR");
+        assert_eq!(record.wait_duration, 300);
+    }
+
+    #[test]
+    fn drops_repeats_within_the_window() {
+        let mut limiter = AlertLimiter::default();
+        let window = Duration::from_secs(60);
+        assert!(limiter.should_send("title", "text", "INFO", window));
+        assert!(!limiter.should_send("title", "text", "INFO", window));
+        // a different alert is not a repeat
+        assert!(limiter.should_send("title", "other text", "INFO", window));
+        assert!(limiter.should_send("title", "text", "ERROR", window));
+    }
+
+    #[test]
+    fn sends_again_after_the_window() {
+        let mut limiter = AlertLimiter::default();
+        let window = Duration::from_millis(20);
+        assert!(limiter.should_send("title", "text", "INFO", window));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limiter.should_send("title", "text", "INFO", window));
+        assert!(limiter.should_send("zero", "text", "INFO", Duration::ZERO));
+        assert!(limiter.should_send("zero", "text", "INFO", Duration::ZERO));
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::files;
+use crate::md5::{self, Md5};
+use crate::wandb_internal;
+
+/// The base64 MD5 of a file, which the backend verifies uploads against, and its size.
+pub fn file_digest(path: &Path) -> io::Result<(String, i64)> {
+    let mut file = File::open(path)?;
+    let mut md5 = Md5::default();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buf[..n]);
+        size += n as i64;
+    }
+    Ok((
+        base64::engine::general_purpose::STANDARD.encode(md5.finalize()),
+        size,
+    ))
+}
+
+fn walk(dir: &Path, prefix: &str, files: &mut BTreeMap<String, PathBuf>) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        let name = format!(
+            "{}{}",
+            prefix,
+            entry.file_name().unwrap_or_default().to_string_lossy()
+        );
+        if entry.is_dir() {
+            walk(&entry, &format!("{}/", name), files)?;
+        } else if entry.is_file() {
+            add_file(files, name, entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Places the file at `name`, unless another file already is there.
+fn add_file(files: &mut BTreeMap<String, PathBuf>, name: String, path: PathBuf) -> io::Result<()> {
+    if let Some(existing) = files.get(&name) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "Both {} and {} would be {} in the artifact",
+                existing.display(),
+                path.display(),
+                name
+            ),
+        ));
+    }
+    files.insert(name, path);
+    Ok(())
+}
+
+/// The files making up an artifact, by their path in it. Files are placed
+/// at the top of the artifact, the contents of directories relative to them.
+/// Fails if two files would end up at the same path.
+pub fn collect_files(paths: &[String], cwd: &Path) -> io::Result<BTreeMap<String, PathBuf>> {
+    let mut files = BTreeMap::new();
+    for path in paths {
+        let path = cwd.join(path);
+        let metadata = fs::metadata(&path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Cannot add {}: {}", path.display(), e))
+        })?;
+        if metadata.is_dir() {
+            walk(&path, "", &mut files)?;
+        } else {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            add_file(&mut files, name.to_string(), path)?;
+        }
+    }
+    Ok(files)
+}
+
+/// Whether the start of the file looks like anything but text.
+fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut start = Vec::with_capacity(8192);
+    File::open(path)?.take(8192).read_to_end(&mut start)?;
+    Ok(start.contains(&0))
+}
+
+/// The source files to log as code, by their path relative to `root`: the
+/// ones matching `include` but not `exclude`, globs relative to `root`.
+/// Binary files and files larger than `max_size` bytes are left out.
+pub fn collect_code(
+    root: &Path,
+    include: &str,
+    exclude: Option<&str>,
+    max_size: u64,
+) -> io::Result<BTreeMap<String, PathBuf>> {
+    let (_, matched) = files::expand_glob(include, root);
+    let excluded: HashSet<PathBuf> = exclude
+        .map(|exclude| files::expand_glob(exclude, root).1)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let mut code = BTreeMap::new();
+    for path in matched {
+        if excluded.contains(&path) {
+            continue;
+        }
+        let size = fs::metadata(&path)?.len();
+        if size > max_size {
+            tracing::warn!(
+                "Not logging {} as code, it is larger than {} bytes",
+                path.display(),
+                max_size
+            );
+            continue;
+        }
+        if is_binary(&path)? {
+            tracing::warn!("Not logging {} as code, it is binary", path.display());
+            continue;
+        }
+        let name = path
+            .strip_prefix(root)
+            .ok()
+            .or_else(|| path.file_name().map(Path::new))
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+        code.insert(name, path);
+    }
+    Ok(code)
+}
+
+/// The name of the artifact the code of `program` is logged to, the same
+/// one for every run of it in the project.
+pub fn code_artifact_name(project: &str, program: &str) -> String {
+    format!("source-{}-{}", project, program)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedEntry {
+    digest: String,
+    birth_artifact_id: String,
+}
+
+/// The files of the last logged version of an artifact, remembered locally
+/// so that an unchanged file is referenced instead of uploaded again.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PreviousVersion {
+    entries: BTreeMap<String, CachedEntry>,
+}
+
+impl PreviousVersion {
+    /// A missing or unreadable cache just means everything gets uploaded.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Remembers `entries` as the version `artifact_id`. Files uploaded with
+    /// it were born in it.
+    pub fn save(
+        path: &Path,
+        entries: &[wandb_internal::ArtifactManifestEntry],
+        artifact_id: &str,
+    ) -> io::Result<()> {
+        let version = PreviousVersion {
+            entries: entries
+                .iter()
+                .map(|entry| {
+                    let birth_artifact_id = if entry.birth_artifact_id.is_empty() {
+                        artifact_id.to_string()
+                    } else {
+                        entry.birth_artifact_id.clone()
+                    };
+                    (
+                        entry.path.clone(),
+                        CachedEntry {
+                            digest: entry.digest.clone(),
+                            birth_artifact_id,
+                        },
+                    )
+                })
+                .collect(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(&version)?)
+    }
+}
+
+/// Builds the manifest entries of `files`. The ones that match the previous
+/// version keep pointing at the artifact they were uploaded with and have no
+/// local path, so nexus doesn't upload them.
+pub fn manifest_entries(
+    files: &BTreeMap<String, PathBuf>,
+    previous: &PreviousVersion,
+) -> io::Result<Vec<wandb_internal::ArtifactManifestEntry>> {
+    let mut entries = Vec::new();
+    for (name, source) in files {
+        let (digest, size) = file_digest(source)?;
+        let mut entry = wandb_internal::ArtifactManifestEntry {
+            path: name.clone(),
+            digest,
+            size,
+            ..Default::default()
+        };
+        match previous.entries.get(name) {
+            Some(cached) if cached.digest == entry.digest => {
+                entry.birth_artifact_id = cached.birth_artifact_id.clone();
+            }
+            _ => entry.local_path = source.to_string_lossy().to_string(),
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// The digest of a whole artifact, computed the way nexus does.
+pub fn manifest_digest(entries: &[wandb_internal::ArtifactManifestEntry]) -> String {
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut text = "wandb-artifact-manifest-v1\n".to_string();
+    for entry in sorted {
+        text.push_str(&format!("{}:{}\n", entry.path, entry.digest));
+    }
+    md5::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn manifest(
+    entries: Vec<wandb_internal::ArtifactManifestEntry>,
+) -> wandb_internal::ArtifactManifest {
+    wandb_internal::ArtifactManifest {
+        version: 1,
+        storage_policy: "wandb-storage-policy-v1".to_string(),
+        storage_policy_config: vec![wandb_internal::StoragePolicyConfigItem {
+            key: "storageLayout".to_string(),
+            value_json: "\"V2\"".to_string(),
+        }],
+        contents: entries,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, path: &str, contents: &[u8]) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn digests_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "hello.txt", b"hello");
+        let (digest, size) = file_digest(&dir.path().join("hello.txt")).unwrap();
+        assert_eq!(digest, "XUFAKrxLKna5cZ2REBfFkg==");
+        assert_eq!(size, 5);
+    }
+
+    #[test]
+    fn collects_directories_recursively() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "data/train/a.csv", b"a");
+        write(dir.path(), "data/test.csv", b"b");
+        write(dir.path(), "model.pt", b"c");
+        let files =
+            collect_files(&["data".to_string(), "model.pt".to_string()], dir.path()).unwrap();
+        let names: Vec<_> = files.keys().map(String::as_str).collect();
+        assert_eq!(names, ["model.pt", "test.csv", "train/a.csv"]);
+        assert_eq!(files["train/a.csv"], dir.path().join("data/train/a.csv"));
+
+        let err = collect_files(&["missing".to_string()], dir.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::create_dir(dir.path().join("empty")).unwrap();
+        assert!(collect_files(&["empty".to_string()], dir.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_files_at_the_same_path() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a/x.csv", b"a");
+        write(dir.path(), "b/x.csv", b"b");
+        write(dir.path(), "train/x.csv", b"c");
+        for paths in [
+            ["a/x.csv", "b/x.csv"],
+            // the contents of both land at the top
+            ["a", "b"],
+            ["a/x.csv", "train"],
+        ] {
+            let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+            let err = collect_files(&paths, dir.path()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists, "{:?}", paths);
+            assert!(err.to_string().contains("would be x.csv"), "{}", err);
+        }
+        let files = collect_files(&["a/x.csv".to_string()], dir.path()).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn builds_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.txt", b"hello");
+        write(dir.path(), "b.txt", b"world");
+        let files = collect_files(&["a.txt".to_string(), "b.txt".to_string()], dir.path()).unwrap();
+        let entries = manifest_entries(&files, &PreviousVersion::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "a.txt");
+        assert_eq!(entries[0].digest, "XUFAKrxLKna5cZ2REBfFkg==");
+        // new files are uploaded from where they are
+        assert!(entries.iter().all(|entry| !entry.local_path.is_empty()));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.birth_artifact_id.is_empty()));
+
+        let reversed: Vec<_> = entries.iter().rev().cloned().collect();
+        assert_eq!(manifest_digest(&entries), manifest_digest(&reversed));
+        assert_eq!(manifest_digest(&entries).len(), 32);
+        let manifest = manifest(entries);
+        assert_eq!(manifest.version, 1);
+        assert_eq!(manifest.contents.len(), 2);
+    }
+
+    #[test]
+    fn reuses_unchanged_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "same.txt", b"same");
+        write(dir.path(), "changed.txt", b"before");
+        let paths = ["same.txt".to_string(), "changed.txt".to_string()];
+        let cache = dir.path().join("cache/artifact.json");
+
+        let files = collect_files(&paths, dir.path()).unwrap();
+        let first = manifest_entries(&files, &PreviousVersion::load(&cache)).unwrap();
+        PreviousVersion::save(&cache, &first, "artifact1").unwrap();
+
+        write(dir.path(), "changed.txt", b"after");
+        let second = manifest_entries(&files, &PreviousVersion::load(&cache)).unwrap();
+        let same = second
+            .iter()
+            .find(|entry| entry.path == "same.txt")
+            .unwrap();
+        assert_eq!(same.birth_artifact_id, "artifact1");
+        assert!(same.local_path.is_empty());
+        let changed = second
+            .iter()
+            .find(|entry| entry.path == "changed.txt")
+            .unwrap();
+        assert!(changed.birth_artifact_id.is_empty());
+        assert!(!changed.local_path.is_empty());
+
+        // the unchanged file stays born in the first version
+        PreviousVersion::save(&cache, &second, "artifact2").unwrap();
+        let third = manifest_entries(&files, &PreviousVersion::load(&cache)).unwrap();
+        let born: Vec<_> = third
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.birth_artifact_id.as_str()))
+            .collect();
+        assert_eq!(
+            born,
+            [("changed.txt", "artifact2"), ("same.txt", "artifact1")]
+        );
+    }
+
+    #[test]
+    fn ignores_unreadable_caches() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "cache.json", b"{not json");
+        let previous = PreviousVersion::load(&dir.path().join("cache.json"));
+        assert!(previous.entries.is_empty());
+    }
+
+    #[test]
+    fn filters_code_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "train.py", b"print('train')");
+        write(dir.path(), "model/net.py", b"class Net: pass");
+        write(dir.path(), "model/test_net.py", b"def test(): pass");
+        write(dir.path(), "model/weights.py", b"\0\0binary");
+        write(dir.path(), "model/big.py", &[b'x'; 100]);
+        write(dir.path(), "notes.txt", b"notes");
+
+        let code = collect_code(dir.path(), "**/*.py", Some("**/test_*.py"), 50).unwrap();
+        let names: Vec<_> = code.keys().map(String::as_str).collect();
+        assert_eq!(names, ["model/net.py", "train.py"]);
+        assert_eq!(code["train.py"], dir.path().join("train.py"));
+
+        let code = collect_code(dir.path(), "train.py", None, 50).unwrap();
+        assert_eq!(code.keys().collect::<Vec<_>>(), ["train.py"]);
+        assert!(collect_code(dir.path(), "*.rs", None, 50)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn names_code_artifacts() {
+        assert_eq!(
+            code_artifact_name("proj", "train.py"),
+            "source-proj-train.py"
+        );
+        assert_eq!(
+            code_artifact_name("my proj", "scripts/train model.py"),
+            "source-my_proj-scripts_train_model.py"
+        );
+    }
+}
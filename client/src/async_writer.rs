@@ -0,0 +1,200 @@
+//! Writes history to nexus from a background task, so that logging only
+//! waits for the network once the writer falls too far behind.
+
+use std::io;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::connection::Pending;
+use crate::error;
+
+/// How many batches of messages can wait for the writer before logging blocks.
+pub const CAPACITY: usize = 64;
+
+pub struct AsyncWriter {
+    // None once closed, or if never started
+    sender: Option<mpsc::Sender<Pending>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Hands batches to a writer, or writes them itself if the writer stopped.
+pub struct Submitter(Option<mpsc::Sender<Pending>>);
+
+impl Submitter {
+    /// Waits while the writer is `CAPACITY` batches behind.
+    pub fn submit(self, pending: Pending) -> error::Result<()> {
+        let Some(sender) = self.0 else {
+            return pending.send();
+        };
+        match sender.blocking_send(pending) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::SendError(pending)) => pending.send(),
+        }
+    }
+}
+
+impl AsyncWriter {
+    /// A writer that isn't running, so that batches are written right away.
+    pub fn stopped() -> Self {
+        AsyncWriter {
+            sender: None,
+            thread: None,
+        }
+    }
+
+    pub fn start(capacity: usize) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let (sender, mut receiver) = mpsc::channel::<Pending>(capacity);
+        let thread = thread::Builder::new()
+            .name("wandb-writer".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    // one batch at a time, in the order they were submitted
+                    while let Some(pending) = receiver.recv().await {
+                        match tokio::task::spawn_blocking(move || pending.send()).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => tracing::error!("Failed to write history: {}", e),
+                            Err(e) => tracing::error!("History writer failed: {}", e),
+                        }
+                    }
+                });
+            })?;
+        Ok(AsyncWriter {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn submitter(&self) -> Submitter {
+        Submitter(self.sender.clone())
+    }
+
+    /// Waits for everything submitted to be written, and stops the writer.
+    pub fn close(&mut self) {
+        self.close_until(None);
+    }
+
+    /// Like `close`, but leaves the writer behind at `deadline` if it isn't
+    /// done by then, returning whether it was.
+    pub fn close_until(&mut self, deadline: Option<Instant>) -> bool {
+        self.sender.take();
+        let Some(thread) = self.thread.take() else {
+            return true;
+        };
+        if let Some(deadline) = deadline {
+            while !thread.is_finished() {
+                if Instant::now() >= deadline {
+                    tracing::warn!("History writer did not finish in time, leaving it behind");
+                    return false;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let _ = thread.join();
+        true
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Interface;
+    use crate::testing::MockNexus;
+    use crate::wandb_internal;
+
+    fn queue_history(interface: &Interface, step: i64) -> Pending {
+        let record = wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::History(
+                wandb_internal::HistoryRecord {
+                    step: Some(wandb_internal::HistoryStep { num: step }),
+                    ..Default::default()
+                },
+            )),
+            ..Default::default()
+        };
+        let message = wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+            ),
+        };
+        interface.queue_messages(&[message]).unwrap()
+    }
+
+    fn steps(nexus: &MockNexus) -> Vec<i64> {
+        nexus
+            .records()
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(wandb_internal::record::RecordType::History(history)) => {
+                    history.step.map(|step| step.num)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Waits for nexus to have received `n` steps, for up to a second.
+    fn received_steps(nexus: &MockNexus, n: usize) -> Vec<i64> {
+        for _ in 0..100 {
+            if steps(nexus).len() >= n {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        steps(nexus)
+    }
+
+    #[test]
+    fn writes_batches_in_order() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let mut writer = AsyncWriter::start(4).unwrap();
+        for step in 0..50 {
+            writer
+                .submitter()
+                .submit(queue_history(&interface, step))
+                .unwrap();
+        }
+        // everything submitted is written before closing returns
+        writer.close();
+        assert_eq!(interface.stats().sent["history"], 50);
+        assert_eq!(received_steps(&nexus, 50), (0..50).collect::<Vec<_>>());
+        interface.close();
+    }
+
+    #[test]
+    fn a_stopped_writer_writes_right_away() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let writer = AsyncWriter::stopped();
+        writer
+            .submitter()
+            .submit(queue_history(&interface, 0))
+            .unwrap();
+        assert_eq!(interface.stats().sent["history"], 1);
+        interface.close();
+    }
+
+    #[test]
+    fn writes_right_away_once_closed() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let mut writer = AsyncWriter::start(CAPACITY).unwrap();
+        writer.close();
+        writer
+            .submitter()
+            .submit(queue_history(&interface, 0))
+            .unwrap();
+        assert_eq!(interface.stats().sent["history"], 1);
+        // closing again is fine
+        writer.close();
+        interface.close();
+    }
+}
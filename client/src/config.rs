@@ -0,0 +1,167 @@
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+use serde_json::{Map, Value};
+
+use crate::wandb_internal;
+
+/// Run config, where dotted keys like `optimizer.lr` address nested values.
+#[derive(Clone, Default)]
+pub struct Config {
+    values: Map<String, Value>,
+}
+
+impl Config {
+    /// Sets a possibly dotted key. Fails instead of replacing a nested mapping
+    /// with a scalar or descending into a key that holds a scalar.
+    pub fn set(&mut self, key: &str, value: Value) -> Result<wandb_internal::ConfigItem, String> {
+        let path: Vec<&str> = key.split('.').collect();
+        let (last, parents) = path.split_last().unwrap();
+
+        let mut target = &mut self.values;
+        for (i, parent) in parents.iter().enumerate() {
+            let entry = target
+                .entry(parent.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            target = match entry {
+                Value::Object(map) => map,
+                _ => {
+                    return Err(format!(
+                        "Cannot set config key {:?}: {:?} is not a mapping",
+                        key,
+                        path[..=i].join(".")
+                    ))
+                }
+            };
+        }
+        if let Some(Value::Object(_)) = target.get(*last) {
+            if !value.is_object() {
+                return Err(format!(
+                    "Cannot set config key {:?}: it holds nested keys",
+                    key
+                ));
+            }
+        }
+
+        let value_json = serde_json::to_string(&value).unwrap();
+        target.insert(last.to_string(), value);
+
+        let item = if parents.is_empty() {
+            wandb_internal::ConfigItem {
+                key: key.to_string(),
+                value_json,
+                ..Default::default()
+            }
+        } else {
+            wandb_internal::ConfigItem {
+                nested_key: path.iter().map(|part| part.to_string()).collect(),
+                value_json,
+                ..Default::default()
+            }
+        };
+        Ok(item)
+    }
+
+    /// Resolves a possibly dotted key.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let mut path = key.split('.');
+        let mut value = self.values.get(path.next()?)?;
+        for part in path {
+            value = value.as_object()?.get(part)?;
+        }
+        Some(value)
+    }
+}
+
+/// Converts a JSON value into the equivalent Python object.
+pub fn to_py(py: Python<'_>, value: &Value) -> PyObject {
+    match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into_py(py),
+            None => n.as_f64().into_py(py),
+        },
+        Value::String(s) => s.into_py(py),
+        Value::Array(items) => PyList::new(py, items.iter().map(|item| to_py(py, item))).into(),
+        Value::Object(map) => {
+            let dict = PyDict::new(py);
+            for (k, v) in map {
+                dict.set_item(k, to_py(py, v)).unwrap();
+            }
+            dict.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn expands_dotted_keys() {
+        let mut config = Config::default();
+        let item = config.set("optimizer.lr", json!(0.1)).unwrap();
+        assert_eq!(item.key, "");
+        assert_eq!(item.nested_key, vec!["optimizer", "lr"]);
+        assert_eq!(item.value_json, "0.1");
+        config.set("optimizer.momentum", json!(0.9)).unwrap();
+        config.set("model.encoder.layers", json!(12)).unwrap();
+
+        assert_eq!(
+            config.get("optimizer"),
+            Some(&json!({"lr": 0.1, "momentum": 0.9}))
+        );
+        assert_eq!(config.get("model.encoder.layers"), Some(&json!(12)));
+        assert_eq!(config.get("model.decoder"), None);
+        assert_eq!(config.get("optimizer.lr.value"), None);
+    }
+
+    #[test]
+    fn sets_flat_keys() {
+        let mut config = Config::default();
+        let item = config.set("epochs", json!(10)).unwrap();
+        assert_eq!(item.key, "epochs");
+        assert!(item.nested_key.is_empty());
+        assert_eq!(config.get("epochs"), Some(&json!(10)));
+        config.set("epochs", json!(20)).unwrap();
+        assert_eq!(config.get("epochs"), Some(&json!(20)));
+    }
+
+    #[test]
+    fn rejects_collisions() {
+        let mut config = Config::default();
+        config.set("optimizer", json!("adam")).unwrap();
+        let e = config.set("optimizer.lr", json!(0.1)).unwrap_err();
+        assert!(e.contains("\"optimizer\" is not a mapping"), "{}", e);
+        assert_eq!(config.get("optimizer"), Some(&json!("adam")));
+
+        let mut config = Config::default();
+        config.set("optimizer.lr", json!(0.1)).unwrap();
+        let e = config.set("optimizer", json!("adam")).unwrap_err();
+        assert!(e.contains("holds nested keys"), "{}", e);
+        // a mapping can still replace a mapping
+        config.set("optimizer", json!({"lr": 0.2})).unwrap();
+        assert_eq!(config.get("optimizer.lr"), Some(&json!(0.2)));
+    }
+
+    #[test]
+    fn converts_to_python() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let value = to_py(py, &json!({"lr": 0.1, "layers": [1, 2], "name": null}));
+            let dict: &PyDict = value.as_ref(py).downcast().unwrap();
+            assert_eq!(
+                dict.get_item("lr")
+                    .unwrap()
+                    .unwrap()
+                    .extract::<f64>()
+                    .unwrap(),
+                0.1
+            );
+            let layers: Vec<i64> = dict.get_item("layers").unwrap().unwrap().extract().unwrap();
+            assert_eq!(layers, vec![1, 2]);
+            assert!(dict.get_item("name").unwrap().unwrap().is_none());
+        });
+    }
+}
@@ -1,17 +1,30 @@
+use crate::error::{self, Error};
 use crate::run::generate_id;
+use crate::transaction_log::{self, TransactionLog};
 use crate::wandb_internal;
 use byteorder::{LittleEndian, WriteBytesExt};
 use prost::Message;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::{
-    collections::HashMap,
-    io::{BufWriter, Write},
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, BufWriter, Read, Write},
     net::TcpStream,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
     // sync::mpsc::{channel, Receiver, RecvError, Sender},
-    sync::mpsc::{channel, Sender},
-    sync::{Arc, Mutex},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    sync::{Arc, Mutex, MutexGuard, TryLockError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 use tracing;
 
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3);
+/// Frames announcing a larger body are rejected rather than allocated.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 128 * 1024 * 1024;
+
 #[repr(C)]
 struct Header {
     magic: u8,
@@ -31,52 +44,690 @@ struct Header {
 //     }
 // }
 
-pub struct Interface {
-    pub conn: Connection,
+/// Writes a single frame: the magic byte, the body length and the body itself.
+pub fn write_frame<W: Write>(writer: &mut W, body: &[u8]) -> io::Result<()> {
+    let data_length = u32::try_from(body.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Message of {} bytes is too large to frame", body.len()),
+        )
+    })?;
+    let header = Header {
+        magic: b'W',
+        data_length,
+    };
+
+    writer.write_u8(header.magic)?;
+    writer.write_u32::<LittleEndian>(header.data_length)?;
+    writer.write_all(body)
+}
+
+/// Reads until `buf` is full or the reader hits EOF, returning how much was read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads a single frame written by [`write_frame`] and returns its body, or
+/// `None` if the stream was closed cleanly between frames.
+pub fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 5];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        5 => {}
+        n => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Stream closed after {} bytes of a frame header", n),
+            ))
+        }
+    }
+    if header[0] != b'W' {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Magic number is not 'W': {}", header[0]),
+        ));
+    }
+
+    let body_length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if body_length > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {} bytes",
+                body_length, max_size
+            ),
+        ));
+    }
+
+    let mut body = vec![0; body_length];
+    let read = read_full(reader, &mut body)?;
+    if read < body_length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "Stream closed after {} of {} bytes of a frame",
+                read, body_length
+            ),
+        ));
+    }
+    Ok(Some(body))
+}
+
+/// Where nexus listens: a TCP `host:port` or the path of a Unix domain socket.
+#[derive(Clone, Debug)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Address {
+    pub fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Address::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Address::Unix(path) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Cannot connect to {}: Unix domain sockets are not supported on this platform",
+                    path.display()
+                ),
+            )),
+        }
+    }
+}
+
+/// A connection to nexus over either transport. Both carry the same framing.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    /// Shuts down both directions, unblocking any pending reads.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(std::net::Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(std::net::Shutdown::Both),
+        }
+    }
+
+    /// Makes writes fail once blocked for `timeout`, or never with `None`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    /// The address of the other end, for logging.
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream
+                .peer_addr()
+                .map_or_else(|e| e.to_string(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream
+                .peer_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                .unwrap_or_else(|| "unnamed socket".to_string()),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+/// Connects to `addr`, retrying up to `max_retries` times with exponential backoff
+/// starting at `base_delay` and capped at a few seconds.
+pub fn connect_with_retry(
+    addr: &Address,
+    max_retries: u32,
+    base_delay: Duration,
+) -> error::Result<Stream> {
+    let mut delay = base_delay;
+    let mut attempt = 1;
+    loop {
+        match addr.connect() {
+            Ok(stream) => return Ok(stream),
+            // retrying won't help when the transport isn't available at all
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Err(Error::Connection(e)),
+            Err(e) if attempt >= max_retries => {
+                return Err(Error::Connection(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Couldn't connect to nexus at {} after {} attempts: {}",
+                        addr, attempt, e
+                    ),
+                )));
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "Connection attempt {} to {} failed: {}, retrying in {:?}",
+                    attempt,
+                    addr,
+                    e,
+                    delay
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Whether a write failed because nexus went away, as opposed to e.g. a bad message.
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// How to reach nexus again after the connection dropped.
+pub struct Reconnect {
+    pub addr: Address,
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+/// Runs a task at a fixed interval on a background thread until stopped.
+pub struct Periodic {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    pub fn start<F>(interval: Duration, mut task: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (stop, stopped) = channel();
+        let thread = thread::spawn(move || {
+            // runs until told to stop, or until the handle is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                task();
+            }
+        });
+        Periodic {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the task, waiting for a run in progress to complete.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.stop.send(());
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A handle for publishing records from background threads, through the
+/// same queue and reconnects as the writes of the [`Interface`].
+#[derive(Clone)]
+pub struct Publisher {
+    shared: Option<SharedConnection>,
+    transaction_log: Option<Arc<TransactionLog>>,
+}
+
+impl Publisher {
+    /// Like [`Interface::queue_messages`], from a background thread.
+    pub fn queue_messages(
+        &self,
+        messages: &[wandb_internal::ServerRequest],
+    ) -> error::Result<Pending> {
+        if let Some(log) = &self.transaction_log {
+            for message in messages {
+                if let Some(
+                    wandb_internal::server_request::ServerRequestType::RecordPublish(record)
+                    | wandb_internal::server_request::ServerRequestType::RecordCommunicate(record),
+                ) = &message.server_request_type
+                {
+                    if transaction_log::is_persisted(record) {
+                        log.append(record)?;
+                    }
+                }
+            }
+        }
+        if let Some(shared) = &self.shared {
+            shared.enqueue(messages);
+        }
+        Ok(Pending(self.shared.clone()))
+    }
+
+    pub fn publish(&self, record: wandb_internal::Record) -> error::Result<()> {
+        if let Some(log) = &self.transaction_log {
+            if transaction_log::is_persisted(&record) {
+                log.append(&record)?;
+            }
+        }
+        match &self.shared {
+            Some(shared) => shared.write(&[wandb_internal::ServerRequest {
+                server_request_type: Some(
+                    wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+                ),
+            }]),
+            None => Ok(()),
+        }
+    }
+}
+
+type Handles = Arc<Mutex<HashMap<String, Sender<wandb_internal::Result>>>>;
+
+/// A connection to nexus shared by all runs of a session. Records are told
+/// apart by their stream id, results by their mailbox slot. The connection is
+/// shut down once the last interface using it is closed.
+#[derive(Clone)]
+pub struct SharedConnection {
+    // locked for whole writes, so that frames from different threads never interleave
+    conn: Arc<Mutex<Connection>>,
+    // messages waiting to be written, taken all at once by whichever write
+    // locks `conn` first, so they go out in the order they were queued
+    queue: Arc<Mutex<Vec<wandb_internal::ServerRequest>>>,
     // hashmap string -> channel
-    pub handles: Arc<Mutex<HashMap<String, Sender<wandb_internal::Result>>>>,
+    handles: Handles,
+    reconnect: Option<Arc<Reconnect>>,
+    // per stream id, the messages that set a run up again on a new connection
+    handshakes: Arc<Mutex<HashMap<String, Vec<wandb_internal::ServerRequest>>>>,
+    // interfaces that haven't been closed yet, also guarding `closed`
+    open: Arc<Mutex<usize>>,
+    closed: Arc<AtomicBool>,
 }
 
-impl Interface {
-    pub fn new(conn: Connection) -> Self {
+impl SharedConnection {
+    pub fn new(conn: Connection, reconnect: Option<Reconnect>) -> error::Result<Self> {
         let handles = Arc::new(Mutex::new(HashMap::new()));
-        let interface = Interface {
-            handles: handles.clone(),
-            conn: conn.clone(),
+        spawn_receiver(&conn, &handles).map_err(Error::Connection)?;
+        Ok(SharedConnection {
+            conn: Arc::new(Mutex::new(conn)),
+            queue: Arc::new(Mutex::new(Vec::new())),
+            handles,
+            reconnect: reconnect.map(Arc::new),
+            handshakes: Arc::new(Mutex::new(HashMap::new())),
+            open: Arc::new(Mutex::new(0)),
+            closed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// A new interface for a run, which keeps the connection open until it is
+    /// closed. `None` if the connection was shut down after its last run finished.
+    pub fn interface(&self) -> Option<Interface> {
+        let mut open = self.open.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+        *open += 1;
+        Some(Interface {
+            shared: Some(self.clone()),
+            transaction_log: None,
+            stream_id: None,
+            heartbeat: None,
+            deadline: None,
+            timed_out: false,
+            stats: Some(self.conn.lock().unwrap().stats.clone()),
+        })
+    }
+
+    fn enqueue(&self, messages: &[wandb_internal::ServerRequest]) {
+        self.queue.lock().unwrap().extend_from_slice(messages);
+    }
+
+    fn flush(&self) -> error::Result<()> {
+        self.flush_until(None)
+    }
+
+    /// Writes everything queued so far to nexus. If nexus went away,
+    /// reconnects, replays the handshakes of all runs and writes the messages
+    /// again. A failure is returned to the write that took the messages,
+    /// which need not be the one that queued them. With a `deadline`, fails
+    /// with `TimedOut` instead of waiting past it for the connection or for
+    /// nexus to read.
+    fn flush_until(&self, deadline: Option<Instant>) -> error::Result<()> {
+        let mut conn = self.lock_until(deadline)?;
+        let messages = std::mem::take(&mut *self.queue.lock().unwrap());
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Some(left),
+                _ => return Err(timed_out()),
+            },
+            None => None,
+        };
+        if timeout.is_some() {
+            conn.stream
+                .set_write_timeout(timeout)
+                .map_err(Error::Connection)?;
+        }
+        let result = match conn.send_messages(&messages) {
+            Err(e) if is_disconnect(&e) && self.reconnect.is_some() => {
+                tracing::warn!("Lost connection to nexus: {}, reconnecting", e);
+                self.reconnect(&mut conn)?;
+                conn.send_messages(&messages).map_err(Error::Connection)
+            }
+            result => result.map_err(Error::Connection),
         };
+        if timeout.is_some() {
+            let _ = conn.stream.set_write_timeout(None);
+        }
+        match result {
+            Err(Error::Connection(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                // a frame may be cut short, so the stream is of no use anymore
+                tracing::warn!("nexus stopped reading, closing the connection");
+                let _ = conn.stream.shutdown();
+                Err(timed_out())
+            }
+            result => result,
+        }
+    }
 
-        std::thread::spawn(move || conn.recv(&handles));
+    /// Locks the connection, giving up at `deadline` if another write holds it.
+    fn lock_until(&self, deadline: Option<Instant>) -> error::Result<MutexGuard<'_, Connection>> {
+        let Some(deadline) = deadline else {
+            return Ok(self.conn.lock().unwrap());
+        };
+        loop {
+            match self.conn.try_lock() {
+                Ok(conn) => return Ok(conn),
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                    return Err(timed_out())
+                }
+                Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    fn write(&self, messages: &[wandb_internal::ServerRequest]) -> error::Result<()> {
+        self.write_until(messages, None)
+    }
 
-        interface
+    fn write_until(
+        &self,
+        messages: &[wandb_internal::ServerRequest],
+        deadline: Option<Instant>,
+    ) -> error::Result<()> {
+        self.enqueue(messages);
+        self.flush_until(deadline)
+    }
+
+    fn reconnect(&self, conn: &mut Connection) -> error::Result<()> {
+        let reconnect = self.reconnect.as_ref().unwrap();
+        let stream =
+            connect_with_retry(&reconnect.addr, reconnect.max_retries, reconnect.base_delay)?;
+        let new_conn = Connection {
+            stats: conn.stats.clone(),
+            ..Connection::new(stream, conn.max_frame_size)
+        };
+        new_conn.stats.lock().unwrap().reconnects += 1;
+        for handshake in self.handshakes.lock().unwrap().values() {
+            new_conn
+                .send_messages(handshake)
+                .map_err(Error::Connection)?;
+        }
+        spawn_receiver(&new_conn, &self.handles).map_err(Error::Connection)?;
+        *conn = new_conn;
+        tracing::info!("Reconnected to nexus at {}", reconnect.addr);
+        Ok(())
+    }
+
+    fn release(&self) {
+        let mut open = self.open.lock().unwrap();
+        *open -= 1;
+        if *open == 0 {
+            tracing::debug!("Last run finished, closing the connection to nexus");
+            self.closed.store(true, Ordering::SeqCst);
+            // also ends the receiving thread
+            if let Err(e) = self.conn.lock().unwrap().stream.shutdown() {
+                tracing::debug!("Failed to shut down the connection: {}", e);
+            }
+        }
     }
 }
 
-pub struct Connection {
-    pub stream: TcpStream,
+fn timed_out() -> Error {
+    Error::Connection(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "nexus did not take the messages in time",
+    ))
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        let conn = Connection { stream };
+fn spawn_receiver(conn: &Connection, handles: &Handles) -> io::Result<()> {
+    let conn = conn.try_clone()?;
+    let handles = handles.clone();
+    std::thread::spawn(move || conn.recv(&handles));
+    Ok(())
+}
+
+/// Messages queued on the connection. Sending writes them, unless another
+/// write took them along already.
+#[must_use]
+pub struct Pending(Option<SharedConnection>);
+
+impl Pending {
+    pub fn send(self) -> error::Result<()> {
+        match self.0 {
+            Some(shared) => shared.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The way a single run talks to nexus.
+pub struct Interface {
+    // None in offline and disabled modes, and once closed
+    shared: Option<SharedConnection>,
+    pub transaction_log: Option<Arc<TransactionLog>>,
+    // the run whose handshake was registered
+    stream_id: Option<String>,
+    heartbeat: Option<Periodic>,
+    // when to stop waiting for results, and whether one wasn't waited for
+    deadline: Option<Instant>,
+    timed_out: bool,
+    // kept once closed
+    stats: Option<Arc<Mutex<Stats>>>,
+}
+
+impl Interface {
+    /// An interface that never talks to nexus.
+    pub fn detached() -> Self {
+        Interface {
+            shared: None,
+            transaction_log: None,
+            stream_id: None,
+            heartbeat: None,
+            deadline: None,
+            timed_out: false,
+            stats: None,
+        }
+    }
+
+    /// Registers the messages that set the run up on a new connection to nexus.
+    pub fn set_handshake(&mut self, stream_id: &str, messages: Vec<wandb_internal::ServerRequest>) {
+        if let Some(shared) = &self.shared {
+            shared
+                .handshakes
+                .lock()
+                .unwrap()
+                .insert(stream_id.to_string(), messages);
+            self.stream_id = Some(stream_id.to_string());
+        }
+    }
+
+    /// Starts sending `message` every `interval`, queued and reconnecting like
+    /// all other messages. Does nothing without a connection.
+    pub fn start_heartbeat(&mut self, message: wandb_internal::ServerRequest, interval: Duration) {
+        self.stop_heartbeat();
+        if let Some(shared) = &self.shared {
+            let shared = shared.clone();
+            self.heartbeat = Some(Periodic::start(interval, move || {
+                if let Err(e) = shared.write(std::slice::from_ref(&message)) {
+                    tracing::debug!("Failed to send heartbeat: {}", e);
+                }
+            }));
+        }
+    }
+
+    pub fn stop_heartbeat(&mut self) {
+        if let Some(mut heartbeat) = self.heartbeat.take() {
+            heartbeat.stop();
+        }
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        Publisher {
+            shared: self.shared.clone(),
+            transaction_log: self.transaction_log.clone(),
+        }
+    }
+
+    /// Stops talking to nexus. The connection is shut down if no other run uses it.
+    pub fn close(&mut self) {
+        self.stop_heartbeat();
+        if let Some(log) = &self.transaction_log {
+            if let Err(e) = log.sync() {
+                tracing::error!("Failed to sync transaction log: {}", e);
+            }
+        }
+        if let Some(shared) = self.shared.take() {
+            if let Some(stream_id) = self.stream_id.take() {
+                shared.handshakes.lock().unwrap().remove(&stream_id);
+            }
+            shared.release();
+        }
+    }
+
+    fn persist(&self, record: &wandb_internal::Record) -> io::Result<()> {
+        match &self.transaction_log {
+            Some(log) if transaction_log::is_persisted(record) => log.append(record),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn send_message(&self, message: &wandb_internal::ServerRequest) -> error::Result<()> {
+        self.send_messages(std::slice::from_ref(message))
+    }
 
-        conn
+    /// Sends several messages with a single flush of the underlying stream,
+    /// giving up at the deadline if one is set.
+    pub fn send_messages(&self, messages: &[wandb_internal::ServerRequest]) -> error::Result<()> {
+        let pending = self.queue_messages(messages)?;
+        match pending.0 {
+            Some(shared) => shared.flush_until(self.deadline),
+            None => Ok(()),
+        }
     }
 
-    pub fn clone(&self) -> Self {
-        let stream = self.stream.try_clone().unwrap();
-        Connection { stream }
+    /// Queues messages behind the ones sent before, leaving the writing to the
+    /// returned [`Pending`], which doesn't need the GIL.
+    pub fn queue_messages(
+        &self,
+        messages: &[wandb_internal::ServerRequest],
+    ) -> error::Result<Pending> {
+        for message in messages {
+            if let Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(record)
+                | wandb_internal::server_request::ServerRequestType::RecordCommunicate(record),
+            ) = &message.server_request_type
+            {
+                self.persist(record)?;
+            }
+        }
+        if let Some(shared) = &self.shared {
+            shared.enqueue(messages);
+        }
+        Ok(Pending(self.shared.clone()))
     }
 
+    /// Sends the record and waits for its result. Returns `None` without a
+    /// connection, or if the result can't be received.
     pub fn send_and_recv_message(
         &mut self,
         message: &mut wandb_internal::Record,
-        handles: &mut Arc<Mutex<HashMap<String, Sender<wandb_internal::Result>>>>,
-    ) -> wandb_internal::Result {
+    ) -> Option<wandb_internal::Result> {
+        if let Err(e) = self.persist(message) {
+            tracing::error!("Failed to write to transaction log: {}", e);
+        }
+        // nothing to wait for without nexus
+        let shared = self.shared.as_ref()?;
+
         // TODO: generate unique id for this message
         let uuid = generate_id(16);
-        // message.server_request_type.RecordCommunicate.control.mailbox_slot = uuid.clone();
-        // update the message with the uuid
-        // let
         if let Some(ref mut control) = message.control {
             control.mailbox_slot = uuid.clone();
             control.req_resp = true;
@@ -88,7 +739,7 @@ impl Connection {
             });
         }
 
-        let message = wandb_internal::ServerRequest {
+        let request = wandb_internal::ServerRequest {
             server_request_type: Some(
                 wandb_internal::server_request::ServerRequestType::RecordCommunicate(
                     message.clone(),
@@ -98,84 +749,281 @@ impl Connection {
 
         let (sender, receiver) = channel();
         tracing::debug!(">>> Inserting sender {:?} for uuid {}", sender, uuid);
-        handles.lock().unwrap().insert(uuid, sender);
-        tracing::debug!(">>> Handles: {:?}", handles);
-        self.send_message(&message).unwrap();
+        shared.handles.lock().unwrap().insert(uuid.clone(), sender);
+        if let Err(e) = shared.write_until(std::slice::from_ref(&request), self.deadline) {
+            tracing::error!("Failed to send message to nexus: {}", e);
+            shared.handles.lock().unwrap().remove(&uuid);
+            if matches!(&e, Error::Connection(e) if e.kind() == io::ErrorKind::TimedOut) {
+                self.timed_out = true;
+            }
+            return None;
+        }
         tracing::debug!(">>> Waiting for result...");
-        // TODO: this should be recv_timeout(timeout)
-        return receiver.recv().unwrap();
-        // return receiver
-        //     .recv_timeout(std::time::Duration::from_secs(10))
-        //     .unwrap();
-    }
-
-    pub fn send_message(&self, message: &wandb_internal::ServerRequest) -> Result<(), ()> {
-        // marshal the protobuf message
-        let mut buf = Vec::new();
-        message.encode(&mut buf).unwrap();
-
-        tracing::debug!(
-            "Sending message {:?} to run {}",
-            message,
-            self.stream.peer_addr().unwrap()
-        );
-        let mut writer = BufWriter::with_capacity(16384, &self.stream);
-
-        let header = Header {
-            magic: b'W',
-            data_length: buf.len() as u32,
+        let Some(deadline) = self.deadline else {
+            return receiver.recv().ok();
         };
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(result) => Some(result),
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::warn!("No result from nexus in time for {}", uuid);
+                shared.handles.lock().unwrap().remove(&uuid);
+                self.timed_out = true;
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
 
-        // Write the header to the writer
-        writer.write_u8(header.magic).unwrap();
-        writer
-            .write_u32::<LittleEndian>(header.data_length)
-            .unwrap();
+    /// Makes [`send_and_recv_message`](Self::send_and_recv_message) stop
+    /// waiting for results at `deadline`, and writes stop waiting for nexus
+    /// to read, or wait for as long as it takes.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.timed_out = false;
+    }
 
-        // Write the protobuf to the writer
-        writer.write_all(&buf).unwrap();
-        writer.flush().unwrap();
-        Ok(())
+    /// Whether a result wasn't waited for since the deadline was set.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
     }
 
-    pub fn recv_message(&self) -> Vec<u8> {
-        // Read the magic byte
-        let mut magic_byte = [0; 1];
-        let bytes_read = std::io::Read::read(&mut &self.stream, &mut magic_byte).unwrap();
-        tracing::debug!("Read {} bytes", bytes_read);
-        tracing::debug!("Magic byte: {:?}", magic_byte);
+    /// What was written to nexus so far over the connection, which the runs
+    /// of a session share. Nothing in offline and disabled modes.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self
+            .stats
+            .as_ref()
+            .map(|stats| stats.lock().unwrap().clone())
+            .unwrap_or_default();
+        if let Some(shared) = &self.shared {
+            stats.queued = shared.queue.lock().unwrap().len();
+        }
+        stats
+    }
+}
 
-        if magic_byte != [b'W'] {
-            tracing::warn!("Magic number is not 'W': {}", magic_byte[0]);
-            return vec![];
+#[cfg(test)]
+impl Interface {
+    /// Breaks the connection as if nexus went away.
+    pub fn break_connection(&self) {
+        if let Some(shared) = &self.shared {
+            shared.conn.lock().unwrap().stream.shutdown().unwrap();
         }
+    }
+}
 
-        let mut body_length_bytes = [0; 4];
-        std::io::Read::read(&mut &self.stream, &mut body_length_bytes).unwrap();
-        let body_length = u32::from_le_bytes(body_length_bytes);
-        tracing::debug!("Body length: {}", body_length);
+impl Drop for Interface {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
 
-        // Read the body
-        let mut body = vec![0; body_length as usize];
-        std::io::Read::read(&mut &self.stream, &mut body).unwrap();
-        tracing::debug!("Body: {:?}", body);
+/// What was written to nexus over a connection, for debugging throughput.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Messages sent, by the type of record or request, e.g. `partial_history`.
+    pub sent: BTreeMap<String, u64>,
+    /// Including the frame headers.
+    pub bytes_sent: u64,
+    pub reconnects: u64,
+    /// Messages waiting for a write to take them.
+    pub queued: usize,
+}
 
-        body
+/// The snake case name of a record type, e.g. `exit`.
+fn record_type_name(value: &wandb_internal::record::RecordType) -> &'static str {
+    use wandb_internal::record::RecordType;
+    match value {
+        RecordType::History(_) => "history",
+        RecordType::Summary(_) => "summary",
+        RecordType::Output(_) => "output",
+        RecordType::Config(_) => "config",
+        RecordType::Files(_) => "files",
+        RecordType::Stats(_) => "stats",
+        RecordType::Artifact(_) => "artifact",
+        RecordType::Tbrecord(_) => "tbrecord",
+        RecordType::Alert(_) => "alert",
+        RecordType::Telemetry(_) => "telemetry",
+        RecordType::Metric(_) => "metric",
+        RecordType::OutputRaw(_) => "output_raw",
+        RecordType::Run(_) => "run",
+        RecordType::Exit(_) => "exit",
+        RecordType::Final(_) => "final",
+        RecordType::Header(_) => "header",
+        RecordType::Footer(_) => "footer",
+        RecordType::Preempting(_) => "preempting",
+        RecordType::LinkArtifact(_) => "link_artifact",
+        RecordType::UseArtifact(_) => "use_artifact",
+        RecordType::Request(_) => "request",
     }
+}
 
-    pub fn recv(&self, handles: &Arc<Mutex<HashMap<String, Sender<wandb_internal::Result>>>>) {
-        tracing::debug!(
-            "Receiving messages from run {}",
-            self.stream.peer_addr().unwrap()
-        );
+/// The snake case name of a request type, e.g. `partial_history`.
+fn request_type_name(value: &wandb_internal::request::RequestType) -> &'static str {
+    use wandb_internal::request::RequestType;
+    match value {
+        RequestType::StopStatus(_) => "stop_status",
+        RequestType::NetworkStatus(_) => "network_status",
+        RequestType::Defer(_) => "defer",
+        RequestType::GetSummary(_) => "get_summary",
+        RequestType::Login(_) => "login",
+        RequestType::Pause(_) => "pause",
+        RequestType::Resume(_) => "resume",
+        RequestType::PollExit(_) => "poll_exit",
+        RequestType::SampledHistory(_) => "sampled_history",
+        RequestType::PartialHistory(_) => "partial_history",
+        RequestType::RunStart(_) => "run_start",
+        RequestType::CheckVersion(_) => "check_version",
+        RequestType::LogArtifact(_) => "log_artifact",
+        RequestType::DownloadArtifact(_) => "download_artifact",
+        RequestType::Keepalive(_) => "keepalive",
+        RequestType::RunStatus(_) => "run_status",
+        RequestType::Cancel(_) => "cancel",
+        RequestType::Metadata(_) => "metadata",
+        RequestType::InternalMessages(_) => "internal_messages",
+        RequestType::PythonPackages(_) => "python_packages",
+        RequestType::Shutdown(_) => "shutdown",
+        RequestType::Attach(_) => "attach",
+        RequestType::Status(_) => "status",
+        RequestType::ServerInfo(_) => "server_info",
+        RequestType::SenderMark(_) => "sender_mark",
+        RequestType::SenderRead(_) => "sender_read",
+        RequestType::StatusReport(_) => "status_report",
+        RequestType::SummaryRecord(_) => "summary_record",
+        RequestType::TelemetryRecord(_) => "telemetry_record",
+        RequestType::JobInfo(_) => "job_info",
+        RequestType::GetSystemMetrics(_) => "get_system_metrics",
+        RequestType::FileTransferInfo(_) => "file_transfer_info",
+        RequestType::Sync(_) => "sync",
+        RequestType::TestInject(_) => "test_inject",
+    }
+}
+
+/// The snake case name of a message type, e.g. `inform_init`.
+fn server_request_type_name(
+    value: &wandb_internal::server_request::ServerRequestType,
+) -> &'static str {
+    use wandb_internal::server_request::ServerRequestType;
+    match value {
+        ServerRequestType::RecordPublish(_) => "record_publish",
+        ServerRequestType::RecordCommunicate(_) => "record_communicate",
+        ServerRequestType::InformInit(_) => "inform_init",
+        ServerRequestType::InformFinish(_) => "inform_finish",
+        ServerRequestType::InformAttach(_) => "inform_attach",
+        ServerRequestType::InformDetach(_) => "inform_detach",
+        ServerRequestType::InformTeardown(_) => "inform_teardown",
+        ServerRequestType::InformStart(_) => "inform_start",
+    }
+}
+
+/// The type of record or request a message carries, or the type of the
+/// message if it carries neither.
+fn message_kind(message: &wandb_internal::ServerRequest) -> &'static str {
+    use wandb_internal::server_request::ServerRequestType;
+    match &message.server_request_type {
+        Some(
+            ServerRequestType::RecordPublish(record) | ServerRequestType::RecordCommunicate(record),
+        ) => match &record.record_type {
+            Some(wandb_internal::record::RecordType::Request(wandb_internal::Request {
+                request_type: Some(request_type),
+            })) => request_type_name(request_type),
+            Some(record_type) => record_type_name(record_type),
+            None => "record",
+        },
+        Some(request_type) => server_request_type_name(request_type),
+        None => "empty",
+    }
+}
+
+pub struct Connection {
+    pub stream: Stream,
+    pub max_frame_size: usize,
+    // shared by all handles to the connection, and kept on reconnecting
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Connection {
+    pub fn new(stream: Stream, max_frame_size: usize) -> Self {
+        Connection {
+            stream,
+            max_frame_size,
+            stats: Arc::new(Mutex::new(Stats::default())),
+        }
+    }
+
+    /// Another handle to the same connection, e.g. for reading on another thread.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Connection {
+            stream: self.stream.try_clone()?,
+            max_frame_size: self.max_frame_size,
+            stats: self.stats.clone(),
+        })
+    }
+
+    pub fn send_message(&self, message: &wandb_internal::ServerRequest) -> io::Result<()> {
+        self.send_messages(std::slice::from_ref(message))
+    }
+
+    pub fn send_messages(&self, messages: &[wandb_internal::ServerRequest]) -> io::Result<()> {
+        let mut writer = BufWriter::with_capacity(16384, &self.stream);
+
+        let mut bytes = 0;
+        for message in messages {
+            // marshal the protobuf message
+            let buf = message.encode_to_vec();
+
+            tracing::debug!(
+                "Sending message {:?} to run {}",
+                message,
+                self.stream.peer()
+            );
+            write_frame(&mut writer, &buf)?;
+            // the magic number and the length
+            bytes += 5 + buf.len() as u64;
+        }
+        writer.flush()?;
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.bytes_sent += bytes;
+        for message in messages {
+            *stats
+                .sent
+                .entry(message_kind(message).to_string())
+                .or_default() += 1;
+        }
+        Ok(())
+    }
+
+    /// Reads the body of the next frame, `None` once nexus closed the connection.
+    pub fn recv_message(&self) -> io::Result<Option<Vec<u8>>> {
+        let body = read_frame(&mut &self.stream, self.max_frame_size)?;
+        tracing::debug!("Body: {:?}", body);
+        Ok(body)
+    }
+
+    pub fn recv(&self, handles: &Handles) {
+        tracing::debug!("Receiving messages from run {}", self.stream.peer());
         loop {
             tracing::debug!("Waiting for message...");
-            let msg = self.recv_message();
-            if msg.len() == 0 {
-                tracing::debug!("Connection closed");
-                break;
-            }
-            let proto_message = wandb_internal::ServerResponse::decode(msg.as_slice()).unwrap();
+            let msg = match self.recv_message() {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    tracing::debug!("Connection closed");
+                    break;
+                }
+                Err(e) => {
+                    // the stream can't be resynchronized after a bad frame
+                    tracing::error!("Failed to read message from nexus: {}", e);
+                    break;
+                }
+            };
+            let proto_message = match wandb_internal::ServerResponse::decode(msg.as_slice()) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::error!("Failed to decode message from nexus: {}", e);
+                    continue;
+                }
+            };
             tracing::debug!("Received message: {:?}", proto_message);
             tracing::debug!("Handles: {:?}", handles);
 
@@ -191,11 +1039,16 @@ impl Connection {
                         let mailbox_slot = &control.mailbox_slot;
                         tracing::debug!("Mailbox slot: {}", mailbox_slot);
                         tracing::debug!("Handles: {:?}", handles);
-                        if let Some(sender) = handles.lock().unwrap().get(mailbox_slot) {
+                        if mailbox_slot.is_empty() {
+                            // e.g. the response to a heartbeat, nobody waits for it
+                            tracing::debug!("Dropping result without mailbox slot");
+                        } else if let Some(sender) = handles.lock().unwrap().get(mailbox_slot) {
                             tracing::debug!("Sending result to sender {:?}", sender);
                             // TODO: use the result type of the result_communicate
                             // let cloned_result = result.clone();
-                            sender.send(result).expect("Failed to send result")
+                            if sender.send(result).is_err() {
+                                tracing::debug!("Nobody is waiting for the result anymore");
+                            }
                         } else {
                             tracing::warn!("Failed to send result to sender");
                         }
@@ -214,3 +1067,457 @@ impl Connection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockNexus;
+    use std::net::{SocketAddr, TcpListener};
+
+    /// An address nothing listens on, at least until it is bound again.
+    fn unused_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn retries_until_nexus_listens() {
+        let addr = unused_addr();
+        // attempts are made after 0, 100 and 300ms
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = TcpListener::bind(addr).unwrap();
+            listener.accept().unwrap();
+        });
+        let start = Instant::now();
+        let stream = connect_with_retry(
+            &Address::Tcp(addr.to_string()),
+            5,
+            Duration::from_millis(100),
+        );
+        assert!(stream.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn names_the_address_after_the_last_attempt() {
+        let addr = unused_addr();
+        let Err(Error::Connection(e)) =
+            connect_with_retry(&Address::Tcp(addr.to_string()), 3, Duration::from_millis(1))
+        else {
+            panic!("connected to {} with nothing listening", addr);
+        };
+        let message = e.to_string();
+        assert!(message.contains(&addr.to_string()), "{}", message);
+        assert!(message.contains("after 3 attempts"), "{}", message);
+    }
+
+    fn inform_init(stream_id: &str) -> wandb_internal::ServerRequest {
+        wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::InformInit(
+                    wandb_internal::ServerInformInitRequest {
+                        settings: None,
+                        info: Some(wandb_internal::RecordInfo {
+                            stream_id: stream_id.to_string(),
+                            ..Default::default()
+                        }),
+                    },
+                ),
+            ),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn round_trips_over_a_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nexus.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let frame = read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE)
+                .unwrap()
+                .unwrap();
+            // and back
+            write_frame(&mut stream, &frame).unwrap();
+            frame
+        });
+
+        let addr = Address::Unix(path.clone());
+        assert_eq!(addr.to_string(), format!("unix:{}", path.display()));
+        let conn = Connection::new(addr.connect().unwrap(), DEFAULT_MAX_FRAME_SIZE);
+        assert!(matches!(conn.stream, Stream::Unix(_)));
+        let message = inform_init("uds1");
+        conn.send_message(&message).unwrap();
+
+        let received = server.join().unwrap();
+        assert_eq!(
+            wandb_internal::ServerRequest::decode(received.as_slice()).unwrap(),
+            message
+        );
+        let echoed = conn.recv_message().unwrap().unwrap();
+        assert_eq!(echoed, message.encode_to_vec());
+    }
+
+    #[test]
+    #[cfg(not(unix))]
+    fn unix_sockets_are_unsupported() {
+        let addr = Address::Unix(PathBuf::from("nexus.sock"));
+        let Err(e) = connect_with_retry(&addr, 5, Duration::from_millis(1)) else {
+            panic!("connected to a Unix domain socket");
+        };
+        assert!(e.to_string().contains("not supported"), "{}", e);
+    }
+
+    /// Hands out at most `chunk` bytes per read, like a socket might.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        reads: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.reads += 1;
+            Ok(n)
+        }
+    }
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, body).unwrap();
+        frame
+    }
+
+    #[test]
+    fn rejects_frames_over_the_limit() {
+        let mut header = vec![b'W'];
+        header.extend_from_slice(&(u32::MAX).to_le_bytes());
+        // nothing follows the header, so reading the body would block on a socket
+        let e = read_frame(&mut header.as_slice(), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("exceeds the maximum"), "{}", e);
+
+        let frame = frame(&[0; 11]);
+        assert!(read_frame(&mut frame.as_slice(), 10).is_err());
+        assert_eq!(
+            read_frame(&mut frame.as_slice(), 11)
+                .unwrap()
+                .unwrap()
+                .len(),
+            11
+        );
+    }
+
+    #[test]
+    fn reads_frames_across_reads() {
+        let body = b"split across two reads";
+        let frame = frame(body);
+        let mut reader = Trickle {
+            data: &frame,
+            chunk: frame.len() / 2 + 1,
+            reads: 0,
+        };
+        assert_eq!(
+            read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE)
+                .unwrap()
+                .unwrap(),
+            body
+        );
+        assert!(reader.reads >= 2);
+
+        let mut reader = Trickle {
+            data: &frame,
+            chunk: 1,
+            reads: 0,
+        };
+        assert_eq!(
+            read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE)
+                .unwrap()
+                .unwrap(),
+            body
+        );
+        assert_eq!(
+            read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_bad_frames() {
+        let frame = frame(b"body");
+        let e = read_frame(&mut &frame[..3], DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = read_frame(&mut &frame[..7], DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut bad = frame.clone();
+        bad[0] = b'X';
+        let e = read_frame(&mut bad.as_slice(), DEFAULT_MAX_FRAME_SIZE).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn history(stream_id: &str, step: i64) -> wandb_internal::ServerRequest {
+        wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(
+                    wandb_internal::Record {
+                        record_type: Some(wandb_internal::record::RecordType::Request(
+                            wandb_internal::Request {
+                                request_type: Some(
+                                    wandb_internal::request::RequestType::PartialHistory(
+                                        wandb_internal::PartialHistoryRequest {
+                                            step: Some(wandb_internal::HistoryStep { num: step }),
+                                            ..Default::default()
+                                        },
+                                    ),
+                                ),
+                            },
+                        )),
+                        info: Some(wandb_internal::RecordInfo {
+                            stream_id: stream_id.to_string(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ),
+            ),
+        }
+    }
+
+    #[test]
+    fn reconnects_and_replays_the_handshake() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let handshake = vec![inform_init("reconnect1")];
+        interface.set_handshake("reconnect1", handshake.clone());
+        interface.send_message(&handshake[0]).unwrap();
+        interface.send_message(&history("reconnect1", 0)).unwrap();
+
+        interface.break_connection();
+        interface.send_message(&history("reconnect1", 1)).unwrap();
+        assert_eq!(interface.stats().reconnects, 1);
+
+        let expected = vec![
+            handshake[0].clone(),
+            history("reconnect1", 0),
+            handshake[0].clone(),
+            history("reconnect1", 1),
+        ];
+        let mut received = nexus.received();
+        // the second connection is served on a thread of its own
+        for _ in 0..100 {
+            if received.len() >= expected.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            received = nexus.received();
+        }
+        assert_eq!(received, expected);
+        interface.close();
+    }
+
+    #[test]
+    fn fails_when_nexus_does_not_come_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conn = Connection::new(
+            Stream::Tcp(TcpStream::connect(addr).unwrap()),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let (_server, _) = listener.accept().unwrap();
+        // nothing listens anymore
+        drop(listener);
+        let shared = SharedConnection::new(
+            conn,
+            Some(Reconnect {
+                addr: Address::Tcp(addr.to_string()),
+                max_retries: 2,
+                base_delay: Duration::from_millis(1),
+            }),
+        )
+        .unwrap();
+        let mut interface = shared.interface().unwrap();
+        interface.break_connection();
+        let result = interface.send_message(&history("gone1", 0));
+        assert!(matches!(result, Err(Error::Connection(_))), "{:?}", result);
+        interface.close();
+    }
+
+    /// Waits for `done`, for up to a second.
+    fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn heartbeat_reconnects() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        interface.start_heartbeat(inform_init("heartbeat2"), Duration::from_millis(10));
+        assert!(eventually(|| !nexus.received().is_empty()));
+
+        interface.break_connection();
+        assert!(eventually(|| interface.stats().reconnects == 1));
+        let received = nexus.received().len();
+        assert!(eventually(|| nexus.received().len() > received));
+        interface.close();
+    }
+
+    #[test]
+    fn publisher_reconnects() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let publisher = interface.publisher();
+        interface.break_connection();
+        let record = match history("publisher1", 0).server_request_type {
+            Some(wandb_internal::server_request::ServerRequestType::RecordPublish(record)) => {
+                record
+            }
+            _ => unreachable!(),
+        };
+        publisher.publish(record.clone()).unwrap();
+        assert_eq!(interface.stats().reconnects, 1);
+        assert!(eventually(|| nexus.records() == vec![record.clone()]));
+        interface.close();
+    }
+
+    #[test]
+    fn names_messages_by_what_they_carry() {
+        assert_eq!(message_kind(&inform_init("kind1")), "inform_init");
+        assert_eq!(message_kind(&history("kind1", 0)), "partial_history");
+        let exit = wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordCommunicate(
+                    wandb_internal::Record {
+                        record_type: Some(wandb_internal::record::RecordType::Exit(
+                            Default::default(),
+                        )),
+                        ..Default::default()
+                    },
+                ),
+            ),
+        };
+        assert_eq!(message_kind(&exit), "exit");
+        let bare = wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(Default::default()),
+            ),
+        };
+        assert_eq!(message_kind(&bare), "record");
+        assert_eq!(
+            message_kind(&wandb_internal::ServerRequest::default()),
+            "empty"
+        );
+    }
+
+    #[test]
+    fn counts_what_was_sent() {
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let messages = [
+            inform_init("stats1"),
+            history("stats1", 0),
+            history("stats1", 1),
+        ];
+        for message in &messages {
+            interface.send_message(message).unwrap();
+        }
+
+        let stats = interface.stats();
+        assert_eq!(
+            stats.sent,
+            BTreeMap::from([
+                ("inform_init".to_string(), 1),
+                ("partial_history".to_string(), 2),
+            ])
+        );
+        let bytes: usize = messages.iter().map(|m| 5 + m.encoded_len()).sum();
+        assert_eq!(stats.bytes_sent, bytes as u64);
+        assert_eq!(stats.reconnects, 0);
+        assert_eq!(stats.queued, 0);
+
+        // the runs of a session share the counters
+        let mut other = interface.shared.as_ref().unwrap().interface().unwrap();
+        other.send_message(&history("stats2", 0)).unwrap();
+        assert_eq!(interface.stats().sent["partial_history"], 3);
+        other.close();
+
+        // and they are kept once closed
+        interface.close();
+        assert_eq!(interface.stats().sent["partial_history"], 3);
+    }
+
+    #[test]
+    fn has_no_stats_without_a_connection() {
+        let stats = Interface::detached().stats();
+        assert!(stats.sent.is_empty());
+        assert_eq!(stats.bytes_sent, 0);
+    }
+
+    /// A history message of about `size` bytes.
+    fn large_history(stream_id: &str, size: usize) -> wandb_internal::ServerRequest {
+        let mut message = history(stream_id, 0);
+        if let Some(wandb_internal::server_request::ServerRequestType::RecordPublish(record)) =
+            &mut message.server_request_type
+        {
+            if let Some(wandb_internal::record::RecordType::Request(wandb_internal::Request {
+                request_type: Some(wandb_internal::request::RequestType::PartialHistory(history)),
+            })) = &mut record.record_type
+            {
+                history.item.push(wandb_internal::HistoryItem {
+                    key: "blob".to_string(),
+                    value_json: "x".repeat(size),
+                    ..Default::default()
+                });
+            }
+        }
+        message
+    }
+
+    #[test]
+    fn writes_give_up_at_the_deadline() {
+        // takes the connection, and never reads from it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = Connection::new(
+            Stream::Tcp(TcpStream::connect(listener.local_addr().unwrap()).unwrap()),
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let (_server, _) = listener.accept().unwrap();
+        let shared = SharedConnection::new(conn, None).unwrap();
+        let mut interface = shared.interface().unwrap();
+
+        interface.set_deadline(Some(Instant::now() + Duration::from_millis(200)));
+        let started = Instant::now();
+        // more than the socket buffers hold
+        let result = interface.send_message(&large_history("stalled1", 64 << 20));
+        let elapsed = started.elapsed();
+        assert!(
+            matches!(&result, Err(Error::Connection(e)) if e.kind() == io::ErrorKind::TimedOut),
+            "{:?}",
+            result
+        );
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        // past the deadline, nothing waits anymore
+        let mut record = wandb_internal::Record::default();
+        assert!(interface.send_and_recv_message(&mut record).is_none());
+        assert!(interface.timed_out());
+        interface.close();
+    }
+}
@@ -0,0 +1,102 @@
+use pyo3::exceptions::{PyConnectionError, PyRuntimeError, PyValueError};
+use pyo3::PyErr;
+use std::{fmt, io};
+
+/// Errors surfaced to users of the crate, and to Python as exceptions.
+#[derive(Debug)]
+pub enum Error {
+    /// Nexus can't be reached, or went away. Raised as `ConnectionError`.
+    Connection(io::Error),
+    /// Nexus sent something unexpected. Raised as `RuntimeError`.
+    Protocol(String),
+    /// The settings are invalid. Raised as `ValueError`.
+    Settings(String),
+    /// A local file operation failed. Raised as the matching `OSError`.
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection(e) => write!(f, "Connection to nexus failed: {}", e),
+            Error::Protocol(message) => write!(f, "Unexpected response from nexus: {}", message),
+            Error::Settings(message) => write!(f, "Invalid settings: {}", message),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connection(e) | Error::Io(e) => Some(e),
+            Error::Protocol(_) | Error::Settings(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Connection(_) => PyConnectionError::new_err(e.to_string()),
+            Error::Protocol(_) => PyRuntimeError::new_err(e.to_string()),
+            Error::Settings(_) => PyValueError::new_err(e.to_string()),
+            // keeps e.g. FileNotFoundError
+            Error::Io(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::exceptions::{PyFileNotFoundError, PyOSError};
+    use pyo3::Python;
+
+    fn raised(e: Error) -> (PyErr, String) {
+        let message = e.to_string();
+        (e.into(), message)
+    }
+
+    #[test]
+    fn raises_matching_exceptions() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+            let (err, message) = raised(Error::Connection(refused));
+            assert!(err.is_instance_of::<PyConnectionError>(py));
+            assert!(message.starts_with("Connection to nexus failed"));
+
+            let (err, message) = raised(Error::Protocol("no result".to_string()));
+            assert!(err.is_instance_of::<PyRuntimeError>(py));
+            assert_eq!(message, "Unexpected response from nexus: no result");
+
+            let (err, message) = raised(Error::Settings("bad mode".to_string()));
+            assert!(err.is_instance_of::<PyValueError>(py));
+            assert_eq!(message, "Invalid settings: bad mode");
+
+            let missing = io::Error::from(io::ErrorKind::NotFound);
+            let (err, _) = raised(missing.into());
+            assert!(err.is_instance_of::<PyFileNotFoundError>(py));
+            let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+            let (err, _) = raised(denied.into());
+            assert!(err.is_instance_of::<PyOSError>(py));
+        });
+    }
+
+    #[test]
+    fn keeps_the_source() {
+        use std::error::Error as _;
+        let e = Error::Connection(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(e.source().is_some());
+        assert!(Error::Protocol("unexpected".to_string()).source().is_none());
+    }
+}
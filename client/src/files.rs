@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::wandb_internal;
+use crate::wandb_internal::files_item::PolicyType;
+
+pub fn parse_policy(policy: &str) -> Result<PolicyType, String> {
+    match policy {
+        "now" => Ok(PolicyType::Now),
+        "end" => Ok(PolicyType::End),
+        "live" => Ok(PolicyType::Live),
+        _ => Err(format!(
+            "Invalid policy {:?}, expected one of now, end, live",
+            policy
+        )),
+    }
+}
+
+fn is_glob(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Matches a single path component against a pattern where `*` stands for
+/// any number of characters and `?` for exactly one.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn walk(dir: &Path, components: &[String], found: &mut Vec<PathBuf>) {
+    let Some((component, rest)) = components.split_first() else {
+        if dir.is_file() {
+            found.push(dir.to_path_buf());
+        }
+        return;
+    };
+
+    if !is_glob(component) {
+        walk(&dir.join(component), rest, found);
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    // like shells, wildcards don't match hidden files unless asked to
+    let show_hidden = component.starts_with('.');
+    let mut entries: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|entry| {
+            show_hidden
+                || !entry
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .starts_with('.')
+        })
+        .collect();
+    entries.sort();
+
+    if component == "**" {
+        if rest.is_empty() {
+            // everything below the directory
+            for entry in entries {
+                if entry.is_dir() {
+                    walk(&entry, components, found);
+                } else if entry.is_file() {
+                    found.push(entry);
+                }
+            }
+            return;
+        }
+        // zero directories, then any number of them
+        walk(dir, rest, found);
+        for entry in entries.iter().filter(|entry| entry.is_dir()) {
+            walk(entry, components, found);
+        }
+        return;
+    }
+    let pattern: Vec<char> = component.chars().collect();
+    for entry in entries {
+        let name: Vec<char> = entry
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .chars()
+            .collect();
+        if matches(&pattern, &name) {
+            walk(&entry, rest, found);
+        }
+    }
+}
+
+/// Expands a path or glob, relative to `cwd` unless absolute, into the files
+/// it matches. Also returns the directory the glob starts from, which matched
+/// files are placed relative to.
+pub fn expand_glob(pattern: &str, cwd: &Path) -> (PathBuf, Vec<PathBuf>) {
+    let path = cwd.join(pattern);
+    let mut base = PathBuf::new();
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                let part = part.to_string_lossy().to_string();
+                if components.is_empty() && !is_glob(&part) {
+                    base.push(part);
+                } else {
+                    components.push(part);
+                }
+            }
+            other if components.is_empty() => base.push(other),
+            // `..` and the like after the first wildcard
+            other => components.push(other.as_os_str().to_string_lossy().to_string()),
+        }
+    }
+
+    if components.is_empty() {
+        // not a glob: the file is placed at the top of the files directory
+        let found = if base.is_file() {
+            vec![base.clone()]
+        } else {
+            vec![]
+        };
+        let parent = base.parent().map(Path::to_path_buf).unwrap_or_default();
+        return (parent, found);
+    }
+    let mut found = Vec::new();
+    walk(&base, &components, &mut found);
+    (base, found)
+}
+
+/// Places `source` at `relative` in the files directory: a copy for files
+/// uploaded right away, a link otherwise, so that later changes are picked up.
+pub fn stage(source: &Path, files_dir: &Path, relative: &Path, copy: bool) -> io::Result<()> {
+    let target = files_dir.join(relative);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if target.symlink_metadata().is_ok() {
+        if fs::canonicalize(&target).ok() == fs::canonicalize(source).ok() && !copy {
+            return Ok(());
+        }
+        fs::remove_file(&target)?;
+    }
+    #[cfg(unix)]
+    if !copy {
+        return std::os::unix::fs::symlink(fs::canonicalize(source)?, &target);
+    }
+    fs::copy(source, &target).map(|_| ())
+}
+
+pub fn files_record(paths: Vec<String>, policy: PolicyType) -> wandb_internal::FilesRecord {
+    wandb_internal::FilesRecord {
+        files: paths
+            .into_iter()
+            .map(|path| wandb_internal::FilesItem {
+                path,
+                policy: policy as i32,
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+struct LiveFile {
+    // where the file is placed in the files directory
+    relative: String,
+    modified: Option<SystemTime>,
+}
+
+/// Files saved with the `live` policy, by source path.
+#[derive(Clone, Default)]
+pub struct LiveFiles {
+    files: Arc<Mutex<HashMap<PathBuf, LiveFile>>>,
+}
+
+impl LiveFiles {
+    pub fn add(&self, source: PathBuf, relative: String) {
+        let modified = modified(&source);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(source, LiveFile { relative, modified });
+    }
+
+    /// The files modified since the last call.
+    pub fn changed(&self) -> Vec<String> {
+        let mut changed = Vec::new();
+        for (source, file) in self.files.lock().unwrap().iter_mut() {
+            let modified = modified(source);
+            if modified.is_some() && modified != file.modified {
+                file.modified = modified;
+                changed.push(file.relative.clone());
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn touch(dir: &Path, path: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "content").unwrap();
+    }
+
+    fn names(base: &Path, found: &[PathBuf]) -> Vec<String> {
+        found
+            .iter()
+            .map(|path| {
+                path.strip_prefix(base)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(parse_policy("now"), Ok(PolicyType::Now));
+        assert_eq!(parse_policy("end"), Ok(PolicyType::End));
+        assert_eq!(parse_policy("live"), Ok(PolicyType::Live));
+        assert!(parse_policy("later").is_err());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let matches = |pattern: &str, name: &str| {
+            super::matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("*.pt", "model.pt"));
+        assert!(matches("*", ""));
+        assert!(matches("ckpt-?.pt", "ckpt-1.pt"));
+        assert!(!matches("ckpt-?.pt", "ckpt-10.pt"));
+        assert!(matches("*-*.png", "loss-curve.png"));
+        assert!(!matches("*.pt", "model.pth"));
+    }
+
+    #[test]
+    fn expands_relative_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [
+            "a.pt",
+            "b.pt",
+            "c.txt",
+            ".hidden.pt",
+            "ckpt/1.pt",
+            "ckpt/sub/2.pt",
+        ] {
+            touch(dir.path(), path);
+        }
+
+        let (base, found) = expand_glob("*.pt", dir.path());
+        assert_eq!(base, dir.path());
+        assert_eq!(names(&base, &found), ["a.pt", "b.pt"]);
+
+        let (base, found) = expand_glob("ckpt/*.pt", dir.path());
+        assert_eq!(base, dir.path().join("ckpt"));
+        assert_eq!(names(&base, &found), ["1.pt"]);
+
+        let (base, found) = expand_glob("**/*.pt", dir.path());
+        assert_eq!(
+            names(&base, &found),
+            ["a.pt", "b.pt", "ckpt/1.pt", "ckpt/sub/2.pt"]
+        );
+
+        let (base, found) = expand_glob(".*.pt", dir.path());
+        assert_eq!(names(&base, &found), [".hidden.pt"]);
+    }
+
+    #[test]
+    fn expands_plain_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "ckpt/1.pt");
+
+        let (base, found) = expand_glob("ckpt/1.pt", dir.path());
+        assert_eq!(base, dir.path().join("ckpt"));
+        assert_eq!(names(&base, &found), ["1.pt"]);
+
+        // absolute paths ignore the working directory
+        let absolute = dir.path().join("ckpt/1.pt");
+        let (_, found) = expand_glob(absolute.to_str().unwrap(), Path::new("/elsewhere"));
+        assert_eq!(found, [absolute]);
+    }
+
+    #[test]
+    fn expands_to_nothing_without_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(expand_glob("*.pt", dir.path()).1.is_empty());
+        assert!(expand_glob("missing.pt", dir.path()).1.is_empty());
+        assert!(expand_glob("missing/*.pt", dir.path()).1.is_empty());
+    }
+
+    #[test]
+    fn stages_copies_and_links() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "source.txt");
+        let source = dir.path().join("source.txt");
+        let files_dir = dir.path().join("files");
+
+        stage(&source, &files_dir, Path::new("copy/source.txt"), true).unwrap();
+        stage(&source, &files_dir, Path::new("link.txt"), false).unwrap();
+        // staging again replaces the file
+        stage(&source, &files_dir, Path::new("link.txt"), false).unwrap();
+        stage(&source, &files_dir, Path::new("copy/source.txt"), true).unwrap();
+
+        fs::write(&source, "changed").unwrap();
+        let copy = fs::read_to_string(files_dir.join("copy/source.txt")).unwrap();
+        assert_eq!(copy, "content");
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_to_string(files_dir.join("link.txt")).unwrap(),
+            "changed"
+        );
+    }
+
+    #[test]
+    fn builds_files_records() {
+        let record = files_record(
+            vec!["a.pt".to_string(), "b.pt".to_string()],
+            PolicyType::End,
+        );
+        let paths: Vec<_> = record.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["a.pt", "b.pt"]);
+        assert!(record
+            .files
+            .iter()
+            .all(|file| file.policy == PolicyType::End as i32));
+    }
+
+    #[test]
+    fn reports_modified_live_files() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "live.txt");
+        let source = dir.path().join("live.txt");
+        let live_files = LiveFiles::default();
+        live_files.add(source.clone(), "live.txt".to_string());
+        assert!(live_files.changed().is_empty());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(live_files.changed(), ["live.txt"]);
+        assert!(live_files.changed().is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::time::{Duration, Instant};
+
+use crate::wandb_internal;
+
+const FLUSH_INTERVAL_ENV: &str = "WANDB_FLUSH_INTERVAL_MS";
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// How long committed history steps may stay buffered before they are sent,
+/// configurable through `WANDB_FLUSH_INTERVAL_MS`.
+pub fn flush_interval() -> Duration {
+    match env::var(FLUSH_INTERVAL_ENV) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(ms) => Duration::from_millis(ms),
+            Err(_) => {
+                tracing::warn!("Invalid {} value {:?}", FLUSH_INTERVAL_ENV, value);
+                DEFAULT_FLUSH_INTERVAL
+            }
+        },
+        Err(_) => DEFAULT_FLUSH_INTERVAL,
+    }
+}
+
+/// Accumulates history items per step so that many `log` calls end up in a
+/// handful of writes to nexus.
+pub struct HistoryBuffer {
+    // the step currently being logged to, not yet committed
+    pub step: i64,
+    steps: BTreeMap<i64, Vec<wandb_internal::HistoryItem>>,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl HistoryBuffer {
+    pub fn new(flush_interval: Duration) -> Self {
+        HistoryBuffer {
+            step: 0,
+            steps: BTreeMap::new(),
+            flush_interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Adds items to the current step, replacing earlier values for the same keys.
+    pub fn add(&mut self, items: Vec<wandb_internal::HistoryItem>) {
+        let current = self.steps.entry(self.step).or_default();
+        for item in items {
+            match current.iter_mut().find(|existing| existing.key == item.key) {
+                Some(existing) => *existing = item,
+                None => current.push(item),
+            }
+        }
+    }
+
+    /// Moves to `step`, committing the current one if it is a later step.
+    /// Steps can't go backward.
+    pub fn set_step(&mut self, step: i64) -> Result<(), String> {
+        if step < self.step {
+            return Err(format!(
+                "Step {} is before the current step {}, steps must not decrease",
+                step, self.step
+            ));
+        }
+        self.step = step;
+        Ok(())
+    }
+
+    /// Closes the current step; subsequent items go to the next one.
+    pub fn commit(&mut self) {
+        self.step += 1;
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Whether committed steps have been sitting in the buffer for longer than the flush interval.
+    pub fn should_flush(&self) -> bool {
+        self.steps.keys().any(|&step| step < self.step)
+            && self.last_flush.elapsed() >= self.flush_interval
+    }
+
+    /// Takes the committed steps out of the buffer. With `include_current`, the
+    /// current step is committed first so that it is sent as well.
+    pub fn drain(&mut self, include_current: bool) -> Vec<wandb_internal::PartialHistoryRequest> {
+        if include_current && self.steps.contains_key(&self.step) {
+            self.commit();
+        }
+        let uncommitted = self.steps.split_off(&self.step);
+        let committed = std::mem::replace(&mut self.steps, uncommitted);
+        self.last_flush = Instant::now();
+
+        committed
+            .into_iter()
+            .map(|(step, item)| wandb_internal::PartialHistoryRequest {
+                item,
+                step: Some(wandb_internal::HistoryStep { num: step }),
+                action: Some(wandb_internal::HistoryAction { flush: true }),
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(key: &str, value_json: &str) -> wandb_internal::HistoryItem {
+        wandb_internal::HistoryItem {
+            key: key.to_string(),
+            value_json: value_json.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn add_replaces_values_of_the_step() {
+        let mut buffer = HistoryBuffer::new(Duration::ZERO);
+        buffer.add(vec![item("loss", "1"), item("acc", "0.5")]);
+        buffer.add(vec![item("loss", "2")]);
+        let steps = buffer.drain(true);
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].item, vec![item("loss", "2"), item("acc", "0.5")]);
+    }
+
+    #[test]
+    fn steps_must_not_decrease() {
+        let mut buffer = HistoryBuffer::new(Duration::ZERO);
+        buffer.set_step(5).unwrap();
+        buffer.set_step(5).unwrap();
+        assert!(buffer.set_step(4).is_err());
+        assert_eq!(buffer.step, 5);
+    }
+
+    #[test]
+    fn drains_committed_steps() {
+        let mut buffer = HistoryBuffer::new(Duration::ZERO);
+        buffer.add(vec![item("loss", "1")]);
+        buffer.commit();
+        buffer.add(vec![item("loss", "2")]);
+
+        let steps = buffer.drain(false);
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].step.as_ref().unwrap().num, 0);
+        assert!(!buffer.is_empty());
+
+        let steps = buffer.drain(true);
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].step.as_ref().unwrap().num, 1);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.step, 2);
+    }
+
+    #[test]
+    fn flushes_committed_steps_after_the_interval() {
+        let mut buffer = HistoryBuffer::new(Duration::ZERO);
+        buffer.add(vec![item("loss", "1")]);
+        // the current step can still change
+        assert!(!buffer.should_flush());
+        buffer.commit();
+        assert!(buffer.should_flush());
+
+        let mut buffer = HistoryBuffer::new(Duration::from_secs(3600));
+        buffer.add(vec![item("loss", "1")]);
+        buffer.commit();
+        assert!(!buffer.should_flush());
+    }
+
+    #[test]
+    fn reads_flush_interval() {
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        env::set_var(FLUSH_INTERVAL_ENV, "250");
+        assert_eq!(flush_interval(), Duration::from_millis(250));
+        env::set_var(FLUSH_INTERVAL_ENV, "soon");
+        assert_eq!(flush_interval(), DEFAULT_FLUSH_INTERVAL);
+        env::remove_var(FLUSH_INTERVAL_ENV);
+        assert_eq!(flush_interval(), DEFAULT_FLUSH_INTERVAL);
+    }
+}
@@ -1,61 +1,316 @@
-use fork::{fork, Fork};
-use sentry;
 use std::fs;
-use std::process::Command;
-use std::{thread, time};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::NamedTempFile;
 use tracing;
 
+use crate::error::{self, Error};
+
+// how much of the end of its stderr to keep for errors about nexus failing to start
+const STDERR_TAIL: usize = 16 * 1024;
+const STDERR_GRACE: Duration = Duration::from_millis(500);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
 pub struct Launcher {
-    pub command: String,
-}
-
-fn wait_for_port(port_filename: &str) -> i32 {
-    let delay_time = time::Duration::from_millis(20);
-    loop {
-        thread::sleep(delay_time);
-        let contents =
-            fs::read_to_string(port_filename).expect("Should have been able to read the file");
-        let lines = contents.lines().collect::<Vec<_>>();
-        if lines.last().copied() == Some("EOF") {
-            for item in lines.iter() {
-                match item.split_once("=") {
-                    None => continue,
-                    Some((param, val)) => {
-                        if param == "sock" {
-                            let my_int = val.to_string().parse::<i32>().unwrap();
-                            return my_int;
-                        }
-                    }
+    pub command: PathBuf,
+    // passed after the ones the launcher needs
+    pub args: Vec<String>,
+    // variables set for nexus on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    // how long nexus may take to announce its port
+    pub timeout: Duration,
+}
+
+/// Checks that `path` is a file this process may run.
+pub fn check_executable(path: &Path) -> error::Result<()> {
+    let metadata = fs::metadata(path).map_err(|e| {
+        Error::Settings(format!("nexus binary {} not found: {}", path.display(), e))
+    })?;
+    if !metadata.is_file() {
+        return Err(Error::Settings(format!(
+            "nexus binary {} is not a file",
+            path.display()
+        )));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(Error::Settings(format!(
+                "nexus binary {} is not executable",
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the port out of the port file, once nexus has finished writing it.
+fn read_port(port_filename: &Path) -> error::Result<Option<u16>> {
+    // not written yet
+    let Ok(contents) = fs::read_to_string(port_filename) else {
+        return Ok(None);
+    };
+    let lines = contents.lines().collect::<Vec<_>>();
+    if lines.last().copied() != Some("EOF") {
+        return Ok(None);
+    }
+    for item in lines.iter() {
+        if let Some(("sock", val)) = item.split_once('=') {
+            return val
+                .parse()
+                .map(Some)
+                .map_err(|_| Error::Protocol(format!("Invalid port {:?} in the port file", val)));
+        }
+    }
+    Err(Error::Protocol("No port in the port file".to_string()))
+}
+
+/// The end of what nexus wrote to stderr. Stderr keeps being drained so
+/// that nexus never blocks on it.
+struct StderrTail {
+    tail: Arc<Mutex<Vec<u8>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+impl StderrTail {
+    fn capture(mut stderr: ChildStderr) -> Self {
+        let tail = Arc::new(Mutex::new(Vec::new()));
+        let captured = tail.clone();
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = stderr.read(&mut buf) {
+                let mut tail = captured.lock().unwrap();
+                tail.extend_from_slice(&buf[..n]);
+                if tail.len() > STDERR_TAIL {
+                    let excess = tail.len() - STDERR_TAIL;
+                    tail.drain(..excess);
                 }
             }
+        });
+        StderrTail { tail, reader }
+    }
+
+    /// Waits briefly for the rest of stderr. It ends with the exited process,
+    /// unless something it started still holds on to it.
+    fn into_string(self) -> String {
+        let started = Instant::now();
+        while !self.reader.is_finished() && started.elapsed() < STDERR_GRACE {
+            thread::sleep(POLL_INTERVAL);
         }
+        let tail = self.tail.lock().unwrap();
+        String::from_utf8_lossy(&tail).trim().to_string()
     }
 }
 
 impl Launcher {
-    pub fn start(&self) -> i32 {
-        let port_file = NamedTempFile::new().expect("tempfile should be created");
-        let port_filename = port_file.path().as_os_str().to_str().unwrap();
-        match fork() {
-            Ok(Fork::Parent(_child)) => {
-                let port = wait_for_port(port_filename);
-                return port;
+    /// Starts nexus and waits for it to listen, returning its port. If it
+    /// exits or doesn't announce the port in time, it is killed and the error
+    /// includes what it wrote to stderr.
+    pub fn start(&self) -> error::Result<u16> {
+        check_executable(&self.command)?;
+        // removed when dropped, whichever way this returns
+        let port_file = NamedTempFile::new()?;
+        let mut child = Command::new(&self.command)
+            .envs(self.env.clone())
+            .arg("--port-filename")
+            .arg(port_file.path())
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                Error::Connection(io::Error::new(
+                    e.kind(),
+                    format!("Failed to launch nexus {}: {}", self.command.display(), e),
+                ))
+            })?;
+        let stderr = child.stderr.take().map(StderrTail::capture);
+
+        let started = Instant::now();
+        let failure = loop {
+            match read_port(port_file.path()) {
+                Ok(Some(port)) => {
+                    tracing::debug!("nexus is listening on port {}", port);
+                    return Ok(port);
+                }
+                Ok(None) => {}
+                Err(e) => break e,
             }
-            Ok(Fork::Child) => {
-                let _command = Command::new(self.command.clone())
-                    .arg("--port-filename")
-                    .arg(port_filename)
-                    .output();
+            if let Ok(Some(status)) = child.try_wait() {
+                break Error::Connection(io::Error::other(format!(
+                    "nexus exited on startup with {}",
+                    status
+                )));
             }
-            Err(e) => {
-                sentry::capture_error(&std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Fork failed: {}", e),
+            if started.elapsed() >= self.timeout {
+                break Error::Connection(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "nexus did not start listening within {:.1}s",
+                        self.timeout.as_secs_f64()
+                    ),
                 ));
-                tracing::error!("Fork failed");
             }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        stop(&mut child);
+        let stderr = stderr.map(StderrTail::into_string).unwrap_or_default();
+        tracing::error!("{}", failure);
+        if stderr.is_empty() {
+            return Err(failure);
         }
-        0
+        Err(match failure {
+            Error::Connection(e) => Error::Connection(io::Error::new(
+                e.kind(),
+                format!("{}, stderr:\n{}", e, stderr),
+            )),
+            Error::Protocol(message) => {
+                Error::Protocol(format!("{}, stderr:\n{}", message, stderr))
+            }
+            other => other,
+        })
+    }
+}
+
+fn stop(child: &mut Child) {
+    if let Err(e) = child.kill() {
+        // most likely, it exited already
+        tracing::debug!("Failed to kill nexus: {}", e);
+    }
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `nexus` running `script`, called with `--port-filename <file>` first.
+    #[cfg(unix)]
+    fn fake_nexus(dir: &Path, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join("wandb-core");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    fn launcher(command: PathBuf, timeout: Duration) -> Launcher {
+        Launcher {
+            command,
+            args: vec![],
+            env: vec![],
+            timeout,
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_port_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("port");
+        assert!(matches!(read_port(&path), Ok(None)));
+        fs::write(&path, "sock=1234\n").unwrap();
+        assert!(matches!(read_port(&path), Ok(None)));
+        fs::write(&path, "sock=1234\nEOF").unwrap();
+        assert!(matches!(read_port(&path), Ok(Some(1234))));
+        fs::write(&path, "sock=http\nEOF").unwrap();
+        assert!(matches!(read_port(&path), Err(Error::Protocol(_))));
+        fs::write(&path, "pid=1\nEOF").unwrap();
+        assert!(matches!(read_port(&path), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn checks_the_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = check_executable(&dir.path().join("missing")).unwrap_err();
+        assert!(matches!(missing, Error::Settings(ref message) if message.contains("not found")));
+        assert!(matches!(
+            check_executable(dir.path()),
+            Err(Error::Settings(_))
+        ));
+
+        #[cfg(unix)]
+        {
+            let plain = dir.path().join("plain");
+            fs::write(&plain, "").unwrap();
+            let err = check_executable(&plain).unwrap_err();
+            assert!(
+                matches!(err, Error::Settings(ref message) if message.contains("not executable"))
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn returns_the_announced_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("seen");
+        let nexus = fake_nexus(
+            dir.path(),
+            &format!(
+                "echo \"$3 $4 $EXTRA\" > {}\nprintf 'sock=4321\\nEOF\\n' > \"$2\"",
+                seen.display()
+            ),
+        );
+        let launcher = Launcher {
+            command: nexus,
+            args: vec!["--debug".to_string(), "--verbose".to_string()],
+            env: vec![("EXTRA".to_string(), "value".to_string())],
+            timeout: Duration::from_secs(10),
+        };
+        assert_eq!(launcher.start().unwrap(), 4321);
+        assert_eq!(
+            fs::read_to_string(seen).unwrap(),
+            "--debug --verbose value\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_out_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let port_file = dir.path().join("port_file");
+        let nexus = fake_nexus(
+            dir.path(),
+            &format!(
+                "echo \"$2\" > {}\necho 'still starting' >&2\nexec sleep 30",
+                port_file.display()
+            ),
+        );
+        let started = Instant::now();
+        let err = launcher(nexus, Duration::from_millis(300))
+            .start()
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let Error::Connection(e) = err else {
+            panic!("expected a connection error, got {}", err);
+        };
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("within 0.3s"), "{}", e);
+        assert!(e.to_string().contains("still starting"), "{}", e);
+
+        let port_file = fs::read_to_string(port_file).unwrap();
+        assert!(!Path::new(port_file.trim()).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_nexus_exiting() {
+        let dir = tempfile::tempdir().unwrap();
+        let nexus = fake_nexus(dir.path(), "echo 'bad flag' >&2\nexit 3");
+        let err = launcher(nexus, Duration::from_secs(10))
+            .start()
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(matches!(err, Error::Connection(_)));
+        assert!(message.contains("exited on startup"), "{}", message);
+        assert!(message.contains("bad flag"), "{}", message);
     }
 }
@@ -1,27 +1,112 @@
+//! Communication layer between user code and nexus
+
+// the #[pymethods] of pyo3 0.20 expand to impls that newer compilers warn about
+#![allow(non_local_definitions)]
+
 use pyo3::prelude::*;
 
-use sentry;
-use tracing;
-use tracing_subscriber;
+use std::env;
+use std::io;
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
 
+pub mod alert;
+pub mod artifact;
+#[cfg(feature = "async-writer")]
+pub mod async_writer;
+pub mod config;
 pub mod connection;
+pub mod error;
+pub mod files;
+pub mod history;
 pub mod launcher;
+pub mod md5;
+pub mod media;
+pub mod metadata;
+pub mod metric;
 pub mod printer;
+pub mod proxy;
+pub mod rate_limit;
+pub mod redact;
 pub mod run;
 pub mod session;
 pub mod settings;
+pub mod sync;
+pub mod system_monitor;
+#[cfg(test)]
+mod testing;
+pub mod transaction_log;
+#[allow(clippy::large_enum_variant)]
 pub mod wandb_internal;
 
-/// Communication layer between user code and nexus
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const LOG_LEVEL_ENV: &str = "WANDB_CORE_LOG_LEVEL";
+const DISABLE_SENTRY_ENV: &str = "WANDB_DISABLE_SENTRY";
+const SENTRY_DSN_ENV: &str = "WANDB_SENTRY_DSN";
+const DEFAULT_SENTRY_DSN: &str =
+    "https://9e9d0694aa7ccd41aeb5bc34aadd716a@o151352.ingest.sentry.io/4506068829470720";
+
+pub(crate) fn is_truthy(value: &str) -> bool {
+    matches!(
+        value.trim().to_lowercase().as_str(),
+        "1" | "true" | "yes" | "y" | "on"
+    )
+}
+
+/// Parses a log level name (case-insensitive), e.g. the value of `WANDB_CORE_LOG_LEVEL`.
+pub fn parse_log_level(level: &str) -> Option<LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "trace" => Some(LevelFilter::TRACE),
+        "debug" => Some(LevelFilter::DEBUG),
+        "info" => Some(LevelFilter::INFO),
+        "warn" => Some(LevelFilter::WARN),
+        "error" => Some(LevelFilter::ERROR),
+        _ => None,
+    }
+}
+
+/// Decides which DSN Sentry should report to given the values of
+/// `WANDB_DISABLE_SENTRY` and `WANDB_SENTRY_DSN`, or `None` if it is disabled.
+pub fn sentry_dsn(disable: Option<&str>, dsn: Option<&str>) -> Option<String> {
+    if disable.is_some_and(is_truthy) {
+        return None;
+    }
+    Some(dsn.unwrap_or(DEFAULT_SENTRY_DSN).to_string())
+}
 
-pub static VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub fn init_sentry() -> Option<sentry::ClientInitGuard> {
+    let disable = env::var(DISABLE_SENTRY_ENV).ok();
+    let dsn = env::var(SENTRY_DSN_ENV).ok();
+    sentry_dsn(disable.as_deref(), dsn.as_deref()).map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                before_send: Some(Arc::new(redact::redact_event)),
+                ..Default::default()
+            },
+        ))
+    })
+}
 
 #[pyfunction]
-pub fn init(settings: Option<settings::Settings>) -> run::Run {
+pub fn init(py: Python<'_>, settings: Option<settings::Settings>) -> PyResult<Py<run::Run>> {
     let actual_settings =
-        settings.unwrap_or_else(|| settings::Settings::new(None, None, None, None, None));
-    let sess = session::Session::new(actual_settings);
-    sess.init_run(None)
+        settings.unwrap_or_else(|| settings::Settings::from_env(None, None, None, None, None));
+    let sess = session::Session::new(actual_settings)?;
+    sess.init_run(py, None)
+}
+
+/// Uploads the offline run in `path`, unless it was synced already.
+#[pyfunction]
+#[pyo3(name = "sync")]
+pub fn sync_run(path: &str, settings: Option<settings::Settings>) -> PyResult<bool> {
+    let mut actual_settings =
+        settings.unwrap_or_else(|| settings::Settings::from_env(None, None, None, None, None));
+    // the run was recorded in offline mode, but is synced online
+    actual_settings.proto.mode = Some("online".to_string());
+    let sess = session::Session::new(actual_settings)?;
+    sess.sync(path)
 }
 
 /// A Python module implemented in Rust. The name of this function must match
@@ -30,18 +115,70 @@ pub fn init(settings: Option<settings::Settings>) -> run::Run {
 #[pymodule]
 fn wandb(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     // TODO: this doesn't work
-    let _guard = sentry::init(
-        "https://9e9d0694aa7ccd41aeb5bc34aadd716a@o151352.ingest.sentry.io/4506068829470720",
-    );
+    let _guard = init_sentry();
 
-    let log_level = tracing::Level::INFO;
-    // let log_level = tracing::Level::DEBUG;
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+    let log_level_var = env::var(LOG_LEVEL_ENV).ok();
+    let log_level = log_level_var
+        .as_deref()
+        .and_then(parse_log_level)
+        .unwrap_or(LevelFilter::INFO);
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_writer(|| redact::RedactingWriter(io::stdout()))
+        .init();
+    if let Some(value) = log_level_var {
+        if parse_log_level(&value).is_none() {
+            tracing::warn!("Invalid {} value {:?}, using INFO", LOG_LEVEL_ENV, value);
+        }
+    }
 
     m.add("__version__", VERSION)?;
     m.add_function(wrap_pyfunction!(init, m)?)?;
+    m.add_function(wrap_pyfunction!(sync_run, m)?)?;
     m.add_class::<settings::Settings>()?;
     m.add_class::<session::Session>()?;
     m.add_class::<run::Run>()?;
+    m.add_class::<media::Image>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_levels() {
+        assert_eq!(parse_log_level("debug"), Some(LevelFilter::DEBUG));
+        assert_eq!(parse_log_level("warn"), Some(LevelFilter::WARN));
+        assert_eq!(parse_log_level("TRACE"), Some(LevelFilter::TRACE));
+        assert_eq!(parse_log_level("Error"), Some(LevelFilter::ERROR));
+        assert_eq!(parse_log_level("iNfO"), Some(LevelFilter::INFO));
+        assert_eq!(parse_log_level("verbose"), None);
+        assert_eq!(parse_log_level(""), None);
+        assert_eq!(parse_log_level(" info"), None);
+    }
+
+    #[test]
+    fn disables_sentry() {
+        for disable in ["1", "true", "TRUE", " yes "] {
+            assert_eq!(sentry_dsn(Some(disable), None), None);
+            assert_eq!(
+                sentry_dsn(Some(disable), Some("https://key@example.com/1")),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn picks_sentry_dsn() {
+        assert_eq!(sentry_dsn(None, None).as_deref(), Some(DEFAULT_SENTRY_DSN));
+        assert_eq!(
+            sentry_dsn(Some("false"), None).as_deref(),
+            Some(DEFAULT_SENTRY_DSN)
+        );
+        assert_eq!(
+            sentry_dsn(Some("0"), Some("https://key@example.com/1")).as_deref(),
+            Some("https://key@example.com/1")
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use pyo3::exceptions::{PyFileNotFoundError, PyValueError};
+use pyo3::prelude::*;
+
+use serde_json::{json, Value};
+use sha2::Digest;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// An image to log, read from a file or given as encoded bytes.
+#[pyclass]
+#[derive(Clone)]
+pub struct Image {
+    data: Vec<u8>,
+    format: image::ImageFormat,
+    width: u32,
+    height: u32,
+}
+
+#[pymethods]
+impl Image {
+    /// Exactly one of `path` and `data` must be given. The format, `png` or
+    /// `jpeg`, is detected from the contents unless passed explicitly.
+    #[new]
+    #[pyo3(signature = (path=None, data=None, format=None))]
+    pub fn new(
+        path: Option<String>,
+        data: Option<Vec<u8>>,
+        format: Option<String>,
+    ) -> PyResult<Self> {
+        let data = match (path, data) {
+            (Some(path), None) => {
+                if !Path::new(&path).is_file() {
+                    return Err(PyFileNotFoundError::new_err(format!(
+                        "Image file {} does not exist",
+                        path
+                    )));
+                }
+                fs::read(&path)?
+            }
+            (None, Some(data)) => data,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "Expected either the path or the data of an image",
+                ))
+            }
+        };
+
+        let format = match format.as_deref().map(str::to_lowercase).as_deref() {
+            Some("png") => image::ImageFormat::Png,
+            Some("jpg") | Some("jpeg") => image::ImageFormat::Jpeg,
+            Some(other) => {
+                return Err(PyValueError::new_err(format!(
+                    "Unsupported image format {:?}, expected png or jpeg",
+                    other
+                )))
+            }
+            None => match image::guess_format(&data) {
+                Ok(format @ (image::ImageFormat::Png | image::ImageFormat::Jpeg)) => format,
+                _ => {
+                    return Err(PyValueError::new_err(
+                        "Unsupported image data, expected a png or jpeg image",
+                    ))
+                }
+            },
+        };
+
+        let decoded = image::load_from_memory_with_format(&data, format)
+            .map_err(|e| PyValueError::new_err(format!("Invalid image: {}", e)))?;
+        Ok(Image {
+            width: decoded.width(),
+            height: decoded.height(),
+            data,
+            format,
+        })
+    }
+
+    #[getter]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[getter]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+impl Image {
+    fn extension(&self) -> &'static str {
+        match self.format {
+            image::ImageFormat::Jpeg => "jpg",
+            _ => "png",
+        }
+    }
+
+    fn sha256(&self) -> String {
+        format!("{:x}", sha2::Sha256::digest(&self.data))
+    }
+
+    /// The history value describing the image, without the location of its file.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "_type": "image-file",
+            "format": self.extension(),
+            "sha256": self.sha256(),
+            "size": self.data.len(),
+            "width": self.width,
+            "height": self.height,
+        })
+    }
+
+    /// Writes the image into the files directory of a run, named by its hash
+    /// so that logging the same image twice stores it once. Returns the path
+    /// of the file relative to `files_dir` and the history value referencing it.
+    pub fn stage(&self, files_dir: &str) -> io::Result<(String, Value)> {
+        let sha256 = self.sha256();
+        let path = format!("media/images/{}.{}", &sha256[..20], self.extension());
+        let full_path = Path::new(files_dir).join(&path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&full_path, &self.data)?;
+
+        let mut value = self.to_json();
+        value["path"] = Value::String(path.clone());
+        Ok((path, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+        let buffer = image::RgbImage::from_pixel(width, height, image::Rgb([200, 10, 10]));
+        let mut data = io::Cursor::new(Vec::new());
+        buffer.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn detects_the_format() {
+        let png = Image::new(None, Some(encoded(4, 3, image::ImageFormat::Png)), None).unwrap();
+        assert_eq!((png.width(), png.height()), (4, 3));
+        assert_eq!(png.to_json()["format"], "png");
+
+        let jpeg = Image::new(None, Some(encoded(2, 5, image::ImageFormat::Jpeg)), None).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (2, 5));
+        assert_eq!(jpeg.to_json()["format"], "jpg");
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        let png = encoded(1, 1, image::ImageFormat::Png);
+        assert!(Image::new(None, None, None).is_err());
+        assert!(Image::new(Some("image.png".to_string()), Some(png.clone()), None).is_err());
+        assert!(Image::new(Some("/nonexistent/image.png".to_string()), None, None).is_err());
+        assert!(Image::new(None, Some(png.clone()), Some("gif".to_string())).is_err());
+        assert!(Image::new(None, Some(png), Some("jpeg".to_string())).is_err());
+        assert!(Image::new(None, Some(b"not an image".to_vec()), None).is_err());
+    }
+
+    #[test]
+    fn reads_image_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, encoded(3, 3, image::ImageFormat::Png)).unwrap();
+        let image = Image::new(Some(path.display().to_string()), None, None).unwrap();
+        assert_eq!((image.width(), image.height()), (3, 3));
+    }
+
+    #[test]
+    fn stages_images_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let files_dir = dir.path().display().to_string();
+        let data = encoded(4, 4, image::ImageFormat::Png);
+        let image = Image::new(None, Some(data.clone()), None).unwrap();
+
+        let (path, value) = image.stage(&files_dir).unwrap();
+        assert!(path.starts_with("media/images/"));
+        assert!(path.ends_with(".png"));
+        assert_eq!(fs::read(dir.path().join(&path)).unwrap(), data);
+        assert_eq!(value["_type"], "image-file");
+        assert_eq!(value["path"], path.as_str());
+        assert_eq!(value["size"], data.len());
+        assert_eq!(value["width"], 4);
+        assert_eq!(value["sha256"], image.sha256());
+
+        // the same image again is the same file
+        let (again, _) = image.clone().stage(&files_dir).unwrap();
+        assert_eq!(again, path);
+        assert_eq!(
+            fs::read_dir(dir.path().join("media/images"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! What the run's overview page shows about the environment it runs in.
+//! Nexus adds it to the metadata it gathers itself, like GPU details.
+
+use pyo3::prelude::*;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::wandb_internal;
+
+/// The repository the program runs in. The metadata only has room for the
+/// commit and the remote, so the branch and whether there are uncommitted
+/// changes go into the config, under `_wandb`; nexus uploads the changes
+/// themselves as `diff.patch`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Git {
+    pub commit: String,
+    pub remote_url: Option<String>,
+    // `None` on a detached HEAD
+    pub branch: Option<String>,
+    // changes to tracked files that aren't committed
+    pub dirty: bool,
+}
+
+/// Runs git in `dir`, `None` if it fails, e.g. outside of a repository or
+/// without git installed.
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Git {
+    /// The repository `dir` is in, if any.
+    pub fn detect(dir: &Path) -> Option<Git> {
+        // fails outside of a repository, and before the first commit
+        let commit = git(dir, &["rev-parse", "HEAD"])?;
+        Some(Git {
+            commit,
+            remote_url: git(dir, &["remote", "get-url", "origin"]),
+            branch: git(dir, &["rev-parse", "--abbrev-ref", "HEAD"])
+                .filter(|branch| branch != "HEAD"),
+            dirty: git(dir, &["status", "--porcelain", "--untracked-files=no"])
+                .is_some_and(|status| !status.is_empty()),
+        })
+    }
+
+    pub fn record(&self) -> wandb_internal::GitRepoRecord {
+        wandb_internal::GitRepoRecord {
+            remote_url: self.remote_url.clone().unwrap_or_default(),
+            commit: self.commit.clone(),
+        }
+    }
+
+    /// The config update recording what the metadata has no room for.
+    pub fn config_item(&self) -> wandb_internal::ConfigItem {
+        let value = serde_json::json!({"branch": self.branch, "dirty": self.dirty});
+        wandb_internal::ConfigItem {
+            nested_key: vec!["_wandb".to_string(), "git".to_string()],
+            value_json: value.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The number of physical cores, `None` where it can't be told, e.g. on
+/// Linux machines whose `/proc/cpuinfo` doesn't list cores.
+fn physical_cpu_count() -> Option<usize> {
+    if cfg!(target_os = "macos") {
+        let output = Command::new("sysctl")
+            .args(["-n", "hw.physicalcpu"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        return String::from_utf8_lossy(&output.stdout).trim().parse().ok();
+    }
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    let count = physical_cores(&cpuinfo);
+    (count > 0).then_some(count)
+}
+
+/// Counts the distinct cores of `/proc/cpuinfo`, whose logical processors
+/// name their socket and their core on it.
+fn physical_cores(cpuinfo: &str) -> usize {
+    let mut cores = std::collections::HashSet::new();
+    for processor in cpuinfo.split("\n\n") {
+        let field = |name: &str| {
+            processor.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+        if let (Some(socket), Some(core)) = (field("physical id"), field("core id")) {
+            cores.insert((socket, core));
+        }
+    }
+    cores.len()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub host: String,
+    pub os: String,
+    pub python: String,
+    pub executable: String,
+    pub args: Vec<String>,
+    pub program: Option<String>,
+    pub root: String,
+    pub username: Option<String>,
+    // physical cores, 0 if unknown
+    pub cpu_count: usize,
+    // logical processors
+    pub cpu_count_logical: usize,
+    pub git: Option<Git>,
+}
+
+impl Metadata {
+    /// Gathers the metadata of this process, leaving git out if `with_git`
+    /// is false or the working directory isn't in a repository.
+    pub fn collect(py: Python<'_>, with_git: bool) -> PyResult<Metadata> {
+        let sys = py.import("sys")?;
+        let platform = py.import("platform")?;
+        let argv: Vec<String> = sys.getattr("argv")?.extract()?;
+        let cwd = std::env::current_dir()?;
+        Ok(Metadata {
+            host: py
+                .import("socket")?
+                .call_method0("gethostname")?
+                .extract()?,
+            os: platform.call_method0("platform")?.extract()?,
+            python: platform.call_method0("python_version")?.extract()?,
+            executable: sys.getattr("executable")?.extract()?,
+            // e.g. empty in an interactive interpreter
+            program: argv.first().filter(|program| !program.is_empty()).cloned(),
+            args: argv.into_iter().skip(1).collect(),
+            root: cwd.to_string_lossy().to_string(),
+            // fails without a user name, e.g. in some containers
+            username: py
+                .import("getpass")?
+                .call_method0("getuser")
+                .and_then(|user| user.extract())
+                .ok(),
+            cpu_count: physical_cpu_count().unwrap_or_default(),
+            cpu_count_logical: std::thread::available_parallelism().map_or(0, |count| count.get()),
+            git: if with_git { Git::detect(&cwd) } else { None },
+        })
+    }
+
+    pub fn request(&self) -> wandb_internal::MetadataRequest {
+        let code_path = self.program.as_ref().map(|program| {
+            Path::new(&self.root)
+                .join(program)
+                .to_string_lossy()
+                .to_string()
+        });
+        wandb_internal::MetadataRequest {
+            host: self.host.clone(),
+            os: self.os.clone(),
+            python: self.python.clone(),
+            executable: self.executable.clone(),
+            args: self.args.clone(),
+            program: self.program.clone().unwrap_or_default(),
+            code_path: code_path.unwrap_or_default(),
+            root: self.root.clone(),
+            username: self.username.clone().unwrap_or_default(),
+            cpu_count: self.cpu_count as u32,
+            cpu_count_logical: self.cpu_count_logical as u32,
+            git: self.git.as_ref().map(Git::record),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempCwd;
+
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    }
+
+    #[test]
+    fn detects_the_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        run_git(dir.path(), &["init", "-q", "-b", "main"]);
+        // no commit yet
+        assert_eq!(Git::detect(dir.path()), None);
+
+        run_git(dir.path(), &["commit", "-q", "--allow-empty", "-m", "init"]);
+        let git = Git::detect(dir.path()).unwrap();
+        assert_eq!(git.commit.len(), 40);
+        assert_eq!(git.remote_url, None);
+
+        run_git(
+            dir.path(),
+            &[
+                "remote",
+                "add",
+                "origin",
+                "https://github.com/example/repo.git",
+            ],
+        );
+        let record = Git::detect(dir.path()).unwrap().record();
+        assert_eq!(record.remote_url, "https://github.com/example/repo.git");
+        assert_eq!(record.commit, git.commit);
+    }
+
+    #[test]
+    fn detects_the_branch_and_uncommitted_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        run_git(dir.path(), &["init", "-q", "-b", "main"]);
+        std::fs::write(dir.path().join("train.py"), "lr = 0.1\n").unwrap();
+        run_git(dir.path(), &["add", "train.py"]);
+        run_git(dir.path(), &["commit", "-q", "-m", "init"]);
+        let git = Git::detect(dir.path()).unwrap();
+        assert_eq!(git.branch.as_deref(), Some("main"));
+        assert!(!git.dirty);
+
+        // untracked files don't count
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        assert!(!Git::detect(dir.path()).unwrap().dirty);
+        std::fs::write(dir.path().join("train.py"), "lr = 0.2\n").unwrap();
+        let git = Git::detect(dir.path()).unwrap();
+        assert!(git.dirty);
+        let item = git.config_item();
+        assert_eq!(item.nested_key, ["_wandb", "git"]);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&item.value_json).unwrap(),
+            serde_json::json!({"branch": "main", "dirty": true})
+        );
+
+        run_git(dir.path(), &["checkout", "-q", "--detach"]);
+        assert_eq!(Git::detect(dir.path()).unwrap().branch, None);
+    }
+
+    #[test]
+    fn counts_physical_cores() {
+        let processor = |socket: u32, core: u32| {
+            format!(
+                "processor\t: 0\nphysical id\t: {}\nsiblings\t: 4\ncore id\t\t: {}\n",
+                socket, core
+            )
+        };
+        // two sockets of two cores, each with two threads
+        let cpuinfo = [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (1, 1),
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (1, 1),
+        ]
+        .map(|(socket, core)| processor(socket, core))
+        .join("\n");
+        assert_eq!(physical_cores(&cpuinfo), 4);
+        // e.g. on ARM, without cores listed
+        assert_eq!(physical_cores("processor\t: 0\nBogoMIPS\t: 50.00\n"), 0);
+    }
+
+    #[test]
+    fn leaves_git_out_outside_a_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Git::detect(dir.path()), None);
+    }
+
+    #[test]
+    fn collects_host_and_os() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let metadata = Metadata::collect(py, true).unwrap();
+            assert!(!metadata.host.is_empty());
+            assert!(!metadata.os.is_empty());
+            assert!(metadata.python.starts_with('3'));
+            assert!(metadata.cpu_count_logical > 0);
+            assert!(metadata.cpu_count <= metadata.cpu_count_logical);
+            assert_eq!(metadata.git, None);
+
+            let request = metadata.request();
+            assert_eq!(request.host, metadata.host);
+            assert_eq!(request.os, metadata.os);
+            assert_eq!(request.git, None);
+        });
+    }
+
+    #[test]
+    fn builds_metadata_requests() {
+        let metadata = Metadata {
+            host: "host".to_string(),
+            os: "Linux".to_string(),
+            args: vec!["--lr".to_string(), "0.1".to_string()],
+            program: Some("train.py".to_string()),
+            root: "/work".to_string(),
+            cpu_count: 4,
+            cpu_count_logical: 8,
+            git: Some(Git {
+                commit: "abc".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let request = metadata.request();
+        assert_eq!(request.program, "train.py");
+        assert_eq!(request.code_path, "/work/train.py");
+        assert_eq!(request.args, ["--lr", "0.1"]);
+        assert_eq!(request.cpu_count, 4);
+        assert_eq!(request.cpu_count_logical, 8);
+        assert_eq!(request.username, "");
+        assert_eq!(request.git.unwrap().commit, "abc");
+
+        let interactive = Metadata::default().request();
+        assert_eq!(interactive.program, "");
+        assert_eq!(interactive.code_path, "");
+    }
+}
@@ -0,0 +1,105 @@
+use crate::wandb_internal;
+
+/// Parses a comma-separated list of summary aggregations, e.g. `"min,max"`.
+pub fn parse_summary(summary: &str) -> Result<wandb_internal::MetricSummary, String> {
+    let mut metric_summary = wandb_internal::MetricSummary::default();
+    for aggregation in summary.split(',').map(|s| s.trim().to_lowercase()) {
+        match aggregation.as_str() {
+            "min" => metric_summary.min = true,
+            "max" => metric_summary.max = true,
+            "mean" => metric_summary.mean = true,
+            "best" => metric_summary.best = true,
+            "last" => metric_summary.last = true,
+            "copy" => metric_summary.copy = true,
+            "none" => metric_summary.none = true,
+            _ => return Err(format!("Unknown summary aggregation {:?}", aggregation)),
+        }
+    }
+    Ok(metric_summary)
+}
+
+/// Whether nexus computes the summary of a metric from its history.
+pub fn is_aggregated(summary: &wandb_internal::MetricSummary) -> bool {
+    !summary.none
+        && (summary.min
+            || summary.max
+            || summary.mean
+            || summary.best
+            || summary.last
+            || summary.copy)
+}
+
+/// Builds the record defining a metric. Names containing `*` are sent as globs.
+/// A metric with a step metric is plotted against it, and nexus adds the
+/// step metric's last value to steps logging the metric without it.
+pub fn metric_record(
+    name: &str,
+    step_metric: Option<String>,
+    summary: Option<wandb_internal::MetricSummary>,
+) -> wandb_internal::MetricRecord {
+    let (name, glob_name) = if name.contains('*') {
+        (String::new(), name.to_string())
+    } else {
+        (name.to_string(), String::new())
+    };
+    wandb_internal::MetricRecord {
+        name,
+        glob_name,
+        options: Some(wandb_internal::MetricOptions {
+            step_sync: step_metric.is_some(),
+            defined: true,
+            ..Default::default()
+        }),
+        step_metric: step_metric.unwrap_or_default(),
+        summary,
+        control: Some(wandb_internal::MetricControl { overwrite: true }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_summaries() {
+        let summary = parse_summary("min, MAX").unwrap();
+        assert!(summary.min && summary.max);
+        assert!(!summary.mean && !summary.last && !summary.none);
+        assert!(parse_summary("none").unwrap().none);
+        assert!(parse_summary("median").is_err());
+    }
+
+    #[test]
+    fn aggregates_unless_none() {
+        assert!(is_aggregated(&parse_summary("last").unwrap()));
+        assert!(!is_aggregated(&parse_summary("none").unwrap()));
+        assert!(!is_aggregated(&parse_summary("min,none").unwrap()));
+        assert!(!is_aggregated(&wandb_internal::MetricSummary::default()));
+    }
+
+    #[test]
+    fn builds_metric_records() {
+        let record = metric_record(
+            "loss",
+            Some("epoch".to_string()),
+            Some(parse_summary("min").unwrap()),
+        );
+        assert_eq!(record.name, "loss");
+        assert_eq!(record.glob_name, "");
+        assert_eq!(record.step_metric, "epoch");
+        assert!(record.summary.unwrap().min);
+        let options = record.options.unwrap();
+        assert!(options.step_sync && options.defined);
+        assert!(record.control.unwrap().overwrite);
+    }
+
+    #[test]
+    fn sends_globs_as_is() {
+        let record = metric_record("grad/*", None, None);
+        assert_eq!(record.name, "");
+        assert_eq!(record.glob_name, "grad/*");
+        assert_eq!(record.step_metric, "");
+        assert!(!record.options.unwrap().step_sync);
+    }
+}
@@ -1,9 +1,101 @@
 use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::io::{self, IsTerminal};
 use std::time::Duration;
-use std::{cmp::min, fmt::Write};
 
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 
+use crate::is_truthy;
+
+/// How output should be rendered, derived from stdout and the environment.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputMode {
+    // stdout is a terminal, so spinners and in-place updates make sense
+    pub interactive: bool,
+    // ANSI colors and hyperlinks are allowed, see https://no-color.org
+    pub color: bool,
+    // WANDB_SILENT suppresses everything but errors
+    pub silent: bool,
+}
+
+impl OutputMode {
+    pub fn detect() -> Self {
+        let interactive = io::stdout().is_terminal();
+        OutputMode {
+            interactive,
+            color: interactive && !no_color(),
+            silent: env::var("WANDB_SILENT").is_ok_and(|value| is_truthy(&value)),
+        }
+    }
+}
+
+/// Whether NO_COLOR asks for no colors, which it only does when set to
+/// something other than an empty string.
+fn no_color() -> bool {
+    env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Removes ANSI escape sequences, keeping the text of hyperlinks.
+pub fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1B' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI, e.g. colors: ends with a byte in @..~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, e.g. hyperlinks: ends with BEL or ST
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1B' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Writes a line of output, dropping ANSI codes unless colors are allowed.
+pub fn write_line<W: io::Write>(out: &mut W, mode: &OutputMode, line: &str) -> io::Result<()> {
+    if mode.silent {
+        return Ok(());
+    }
+    if mode.color {
+        writeln!(out, "{}", line)
+    } else {
+        writeln!(out, "{}", strip_ansi(line))
+    }
+}
+
+fn emit(mode: &OutputMode, line: &str) {
+    let _ = write_line(&mut io::stdout().lock(), mode, line);
+}
+
+fn link(mode: &OutputMode, name: &str, url: &str) -> String {
+    if mode.color {
+        styled_string::create_hyperlink(name, url)
+    } else {
+        format!("{} ({})", name, url)
+    }
+}
+
 pub mod styled_string {
     use colored::*;
 
@@ -169,13 +261,18 @@ fn truncate(s: &str, max_chars: usize) -> &str {
 
 struct Printer;
 impl Printer {
+    #[allow(dead_code)]
     const PROGRESS_COLOR: &'static str = "magenta";
+    #[allow(dead_code)]
     const PROGRESS_BLANK_COLOR: &'static str = "white.dim";
     // For more spinners check out the cli-spinners project:
     // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+    #[allow(dead_code)]
     const SPINNERS: [&'static str; 10] = ["⠋", "⠙", "⠚", "⠞", "⠖", "⠦", "⠴", "⠲", "⠳", "⠓"];
+    #[allow(dead_code)]
     const PROGRESS: &'static str = "⣿⡇";
 
+    #[allow(dead_code)]
     fn start_spinner(active_msg: String) -> ProgressBar {
         let pb = ProgressBar::new_spinner();
         pb.enable_steady_tick(Duration::from_millis(120));
@@ -192,6 +289,7 @@ impl Printer {
         pb
     }
 
+    #[allow(dead_code)]
     fn start_progress_bar(total_size: u64, progress_msg: String) -> ProgressBar {
         let pb: ProgressBar = ProgressBar::new(total_size);
         let mut prefix = styled_string::new("");
@@ -216,51 +314,80 @@ impl Printer {
 }
 
 pub fn print_header(name: &str, url: &str) {
+    let mode = OutputMode::detect();
+    let _ = write_header(&mut io::stdout().lock(), &mode, name, url);
+}
+
+/// Writes the lines announcing a run that was just created.
+pub fn write_header<W: io::Write>(
+    out: &mut W,
+    mode: &OutputMode,
+    name: &str,
+    url: &str,
+) -> io::Result<()> {
     let mut head = styled_string::new("");
     styled_string::add_header(&mut head);
     styled_string::add_prefix(&mut head);
-    println!("{}", head);
-
-    let active_msg = format!("Creating run...");
-    let pb = Printer::start_spinner(active_msg);
-
-    // TODO: this is for the demo and should be implemented properly
-    // std::thread::sleep(Duration::from_millis(800));
-    pb.finish_and_clear();
+    write_line(out, mode, &head.to_string())?;
 
     let mut run = styled_string::new(&format!(
         "Run created - {} {}",
         styled_string::custom_chars::ROCKET_ICON,
-        styled_string::create_hyperlink(name, url),
+        link(mode, name, url),
     ));
     styled_string::add_success(&mut run);
     styled_string::add_prefix(&mut run);
-    println!("{}", run);
+    write_line(out, mode, &run.to_string())?;
+
+    let mut view = styled_string::new_dim(&format!("View run at {}", url));
+    styled_string::add_prefix(&mut view);
+    write_line(out, mode, &view.to_string())
+}
+
+/// Announces the new name of a run renamed after its header was printed.
+pub fn print_rename(name: &str, url: &str) {
+    let mode = OutputMode::detect();
+    let mut renamed = styled_string::new(&format!("Run renamed to {}", link(&mode, name, url)));
+    styled_string::add_prefix(&mut renamed);
+    emit(&mode, &renamed.to_string());
+}
+
+/// Points out that anyone with the link can see an anonymous run.
+pub fn print_anonymous(url: &str) {
+    let mode = OutputMode::detect();
+    let mut anonymous = styled_string::new(&format!(
+        "Logging anonymously, anyone with this link can view the run: {}",
+        url
+    ));
+    styled_string::add_prefix(&mut anonymous);
+    emit(&mode, &anonymous.to_string());
 }
 
 pub fn print_offline_header() {
+    let mode = OutputMode::detect();
     let mut head = styled_string::new("");
     styled_string::add_header(&mut head);
     styled_string::add_prefix(&mut head);
-    println!("{}", head);
+    emit(&mode, &head.to_string());
 
     let mut offline = styled_string::new("offline mode is enabled");
     styled_string::add_prefix(&mut offline);
-    println!("{}", offline);
+    emit(&mode, &offline.to_string());
 
     let mut info = styled_string::new_dim("run `wandb online` to enable cloud syncing");
     styled_string::add_prefix(&mut info);
-    println!("{}", info);
+    emit(&mode, &info.to_string());
 }
 
 pub fn print_offline_footer(
     run_dir: &str,
     sparklines: HashMap<String, (Vec<f32>, Option<String>)>,
 ) {
+    let mode = OutputMode::detect();
     let mut head = styled_string::new("");
     styled_string::add_header(&mut head);
     styled_string::add_prefix(&mut head);
-    println!("{}", head);
+    emit(&mode, &head.to_string());
 
     for (key, (values, summary)) in sparklines {
         if key.starts_with("_") {
@@ -280,21 +407,21 @@ pub fn print_offline_footer(
 
         let mut spark = styled_string::new_dim(&formatted);
         styled_string::add_prefix(&mut spark);
-        println!("{}", spark);
+        emit(&mode, &spark.to_string());
     }
 
     let mut empty = styled_string::new("");
     styled_string::add_prefix(&mut empty);
-    println!("{}", empty);
+    emit(&mode, &empty.to_string());
 
     let mut offline = styled_string::new("offline mode is enabled");
     styled_string::add_prefix(&mut offline);
-    println!("{}", offline);
+    emit(&mode, &offline.to_string());
 
     let sync_info = format!("run `wandb sync {}` to sync offline run", run_dir);
     let mut info = styled_string::new_dim(&sync_info);
     styled_string::add_prefix(&mut info);
-    println!("{}", info);
+    emit(&mode, &info.to_string());
 }
 
 pub fn print_footer(
@@ -303,10 +430,11 @@ pub fn print_footer(
     run_dir: &str,
     sparklines: HashMap<String, (Vec<f32>, Option<String>)>,
 ) {
+    let mode = OutputMode::detect();
     let mut head = styled_string::new("");
     styled_string::add_header(&mut head);
     styled_string::add_prefix(&mut head);
-    println!("{}", head);
+    emit(&mode, &head.to_string());
 
     for (key, (values, summary)) in sparklines {
         if key.starts_with("_") {
@@ -326,12 +454,12 @@ pub fn print_footer(
 
         let mut spark = styled_string::new_dim(&formatted);
         styled_string::add_prefix(&mut spark);
-        println!("{}", spark);
+        emit(&mode, &spark.to_string());
     }
 
     let mut empty = styled_string::new("");
     styled_string::add_prefix(&mut empty);
-    println!("{}", empty);
+    emit(&mode, &empty.to_string());
 
     // TODO: this is for the demo and should be implemented properly
     // let total_size = 23123123;
@@ -348,14 +476,97 @@ pub fn print_footer(
     let mut run = styled_string::new(&format!(
         "Run synced - {} {}",
         styled_string::custom_chars::ROCKET_ICON,
-        styled_string::create_hyperlink(name, url),
+        link(&mode, name, url),
     ));
     styled_string::add_success(&mut run);
     styled_string::add_prefix(&mut run);
-    println!("{}", run);
+    emit(&mode, &run.to_string());
 
     let sync_info = format!("Run dir - {}", run_dir);
     let mut info = styled_string::new_dim(&sync_info);
     styled_string::add_prefix(&mut info);
-    println!("{}", info);
+    emit(&mode, &info.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: OutputMode = OutputMode {
+        interactive: false,
+        color: false,
+        silent: false,
+    };
+
+    fn header(mode: &OutputMode) -> String {
+        let mut out = Vec::new();
+        write_header(
+            &mut out,
+            mode,
+            "sunny-dawn-1",
+            "https://wandb.ai/e/p/runs/abc",
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn strips_colors_and_hyperlinks() {
+        let link = styled_string::create_hyperlink("run", "https://wandb.ai");
+        assert_eq!(strip_ansi(&format!("see {}!", link)), "see run!");
+        assert_eq!(strip_ansi("\x1B[1;31mred\x1B[0m"), "red");
+        assert_eq!(strip_ansi("\x1B]8;;url\x1B\\text\x1B]8;;\x1B\\"), "text");
+        assert_eq!(strip_ansi("plain ▍ text"), "plain ▍ text");
+    }
+
+    #[test]
+    fn writes_no_escapes_without_a_terminal() {
+        let out = header(&PLAIN);
+        assert!(!out.contains('\x1B'), "{:?}", out);
+        assert!(out.contains("sunny-dawn-1 (https://wandb.ai/e/p/runs/abc)"));
+        assert!(out.contains("View run at https://wandb.ai/e/p/runs/abc"));
+        // the run exists by the time the header is written
+        assert!(!out.contains("Creating run"));
+        // one statement per line, nothing rewritten in place
+        assert!(!out.contains('\r'));
+        assert_eq!(out.lines().count(), 3);
+    }
+
+    #[test]
+    fn writes_hyperlinks_with_colors() {
+        let out = header(&OutputMode {
+            interactive: true,
+            color: true,
+            silent: false,
+        });
+        assert!(out.contains("\x1B]8;;https://wandb.ai/e/p/runs/abc\x07"));
+    }
+
+    #[test]
+    fn writes_nothing_when_silent() {
+        let out = header(&OutputMode {
+            silent: true,
+            ..PLAIN
+        });
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn detects_silent_and_no_color() {
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        env::set_var("WANDB_SILENT", "true");
+        env::set_var("NO_COLOR", "1");
+        let mode = OutputMode::detect();
+        assert!(mode.silent);
+        assert!(!mode.color);
+        assert!(no_color());
+        env::set_var("NO_COLOR", "");
+        assert!(!no_color());
+        env::set_var("WANDB_SILENT", "false");
+        assert!(!OutputMode::detect().silent);
+        env::remove_var("WANDB_SILENT");
+        env::remove_var("NO_COLOR");
+    }
 }
@@ -0,0 +1,137 @@
+use std::env;
+
+/// The first of the upper and lower case spellings that is set and not
+/// empty, the precedence Go, and therefore nexus, uses.
+fn proxy_env_var(key: &str) -> Option<String> {
+    [key.to_uppercase(), key.to_string()]
+        .into_iter()
+        .filter_map(|key| env::var(key).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Proxies for outbound HTTP(S) connections, following the conventions of
+/// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY`. Nexus makes the connections,
+/// so choosing the proxy for each is left to its HTTP client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Proxies {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    // hosts reached directly, e.g. `example.com`, `.example.com` or `10.0.0.1:8080`
+    pub no_proxy: Vec<String>,
+}
+
+impl Proxies {
+    pub fn new(http: Option<String>, https: Option<String>, no_proxy: Option<&str>) -> Self {
+        Proxies {
+            http,
+            https,
+            no_proxy: no_proxy
+                .unwrap_or_default()
+                .split(',')
+                .map(|entry| entry.trim().to_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Proxies::new(
+            proxy_env_var("http_proxy"),
+            proxy_env_var("https_proxy"),
+            proxy_env_var("no_proxy").as_deref(),
+        )
+    }
+
+    /// The environment variables passing these proxies on to nexus.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(http) = &self.http {
+            vars.push(("HTTP_PROXY".to_string(), http.clone()));
+        }
+        if let Some(https) = &self.https {
+            vars.push(("HTTPS_PROXY".to_string(), https.clone()));
+        }
+        if !self.no_proxy.is_empty() {
+            vars.push(("NO_PROXY".to_string(), self.no_proxy.join(",")));
+        }
+        vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: [&str; 6] = [
+        "HTTP_PROXY",
+        "http_proxy",
+        "HTTPS_PROXY",
+        "https_proxy",
+        "NO_PROXY",
+        "no_proxy",
+    ];
+
+    fn from_env(vars: &[(&str, &str)]) -> Proxies {
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for var in VARS {
+            env::remove_var(var);
+        }
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        let proxies = Proxies::from_env();
+        for var in VARS {
+            env::remove_var(var);
+        }
+        proxies
+    }
+
+    #[test]
+    fn reads_proxies_from_env() {
+        assert_eq!(from_env(&[]), Proxies::default());
+
+        let proxies = from_env(&[
+            ("HTTP_PROXY", "http://proxy:3128"),
+            ("https_proxy", "http://secure-proxy:3129"),
+            ("NO_PROXY", " internal.example.com, .corp ,,10.0.0.1:8080"),
+        ]);
+        assert_eq!(proxies.http.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(proxies.https.as_deref(), Some("http://secure-proxy:3129"));
+        assert_eq!(
+            proxies.no_proxy,
+            ["internal.example.com", ".corp", "10.0.0.1:8080"]
+        );
+    }
+
+    #[test]
+    fn prefers_upper_case_variables() {
+        let proxies = from_env(&[
+            ("HTTPS_PROXY", "http://upper:1"),
+            ("https_proxy", "http://lower:2"),
+            ("HTTP_PROXY", ""),
+            ("http_proxy", "http://lower:3"),
+        ]);
+        assert_eq!(proxies.https.as_deref(), Some("http://upper:1"));
+        // an empty variable counts as unset
+        assert_eq!(proxies.http.as_deref(), Some("http://lower:3"));
+    }
+
+    #[test]
+    fn passes_proxies_on_to_nexus() {
+        let proxies = Proxies::new(
+            Some("http://proxy:3128".to_string()),
+            None,
+            Some("a.com, b.com"),
+        );
+        assert_eq!(
+            proxies.env(),
+            [
+                ("HTTP_PROXY".to_string(), "http://proxy:3128".to_string()),
+                ("NO_PROXY".to_string(), "a.com,b.com".to_string()),
+            ]
+        );
+        assert!(Proxies::default().env().is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::{Duration, Instant};
+
+/// What `log` does with history logged faster than the rate limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Waits until the limit allows it.
+    Block,
+    /// Drops it, warning the first time.
+    Drop,
+}
+
+pub fn parse_policy(policy: &str) -> Result<Policy, String> {
+    match policy {
+        "block" => Ok(Policy::Block),
+        "drop" => Ok(Policy::Drop),
+        _ => Err(format!(
+            "Invalid rate limit policy {:?}, expected one of block, drop",
+            policy
+        )),
+    }
+}
+
+/// A token bucket allowing `rate` events per second on average, and bursts
+/// of up to a second's worth of them.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate,
+            tokens: rate.max(1.0),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.last_refill = now;
+    }
+
+    /// Takes a token if there is one.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Takes a token, returning how long to wait until it is due. Tokens
+    /// taken ahead are owed, so that the callers waiting go in turn.
+    pub fn reserve(&mut self) -> Duration {
+        self.refill();
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(parse_policy("block"), Ok(Policy::Block));
+        assert_eq!(parse_policy("drop"), Ok(Policy::Drop));
+        assert!(parse_policy("queue").is_err());
+    }
+
+    #[test]
+    fn throttles_a_burst() {
+        let mut limiter = RateLimiter::new(50.0);
+        let allowed = (0..200).filter(|_| limiter.try_acquire()).count();
+        // a second's worth, and maybe one more refilled meanwhile
+        assert!((50..=51).contains(&allowed), "{}", allowed);
+        assert!(!limiter.try_acquire());
+
+        std::thread::sleep(Duration::from_millis(100));
+        let refilled = (0..200).filter(|_| limiter.try_acquire()).count();
+        assert!((4..=10).contains(&refilled), "{}", refilled);
+    }
+
+    #[test]
+    fn bursts_at_least_one() {
+        let mut limiter = RateLimiter::new(0.5);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn blocks_until_the_rate_allows() {
+        let mut limiter = RateLimiter::new(100.0);
+        let started = Instant::now();
+        for _ in 0..120 {
+            std::thread::sleep(limiter.reserve());
+        }
+        // the burst right away, the other 20 at 100 per second
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn reserves_in_turn() {
+        let mut limiter = RateLimiter::new(10.0);
+        for _ in 0..10 {
+            assert_eq!(limiter.reserve(), Duration::ZERO);
+        }
+        // each waiting caller is owed the token after the previous one's
+        let first = limiter.reserve();
+        let second = limiter.reserve();
+        assert!(first <= Duration::from_millis(100), "{:?}", first);
+        assert!(second > first + Duration::from_millis(90), "{:?}", second);
+        assert!(!limiter.try_acquire());
+    }
+}
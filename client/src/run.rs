@@ -1,6 +1,9 @@
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple};
 
-use crate::connection::Interface;
+use crate::connection::{Interface, Pending, Periodic};
+use crate::error::{self, Error};
 use crate::wandb_internal;
 use chrono;
 use image;
@@ -8,12 +11,33 @@ use numpy::PyReadonlyArrayDyn;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use serde::{Serialize, Serializer};
+use serde_json::Map;
 use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use tracing;
+use wandb_internal::files_item::PolicyType;
 
+use crate::alert::{self, AlertLimiter};
+use crate::artifact::{self, PreviousVersion};
+#[cfg(feature = "async-writer")]
+use crate::async_writer::{self, AsyncWriter};
+use crate::config::{self, Config};
+use crate::files::{self, LiveFiles};
+use crate::history::{self, HistoryBuffer};
+use crate::media::Image;
+use crate::metadata::Metadata;
+use crate::metric;
 use crate::printer;
-use crate::settings::Settings;
+use crate::rate_limit::{self, RateLimiter};
+use crate::session::SignalRuns;
+use crate::settings::{self, Mode, Settings};
+use crate::system_monitor::{self, SystemMonitor};
+use crate::transaction_log::{self, TransactionLog};
 
 // #[pyfunction]
 pub fn generate_id(length: usize) -> String {
@@ -26,21 +50,38 @@ pub fn generate_id(length: usize) -> String {
         .collect()
 }
 
-fn normalize(data: &Vec<f64>) -> Vec<f64> {
-    let min = data
+const LIVE_FILES_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_CODE_FILE_SIZE: u64 = 10 * 1024 * 1024;
+pub const FINISH_TIMEOUT_SECS: f64 = 60.0;
+// logged timestamps later than this from now are taken for mistakes
+const MAX_TIMESTAMP_AHEAD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Scales the values to [0, 1]. NaN and infinities don't count towards the
+/// range, and become 0 like the values of a constant array.
+fn normalize(data: &[f64]) -> PyResult<Vec<f64>> {
+    if data.is_empty() {
+        return Err(PyValueError::new_err(
+            "Cannot make an image of an empty array",
+        ));
+    }
+    let (min, max) = data
         .iter()
-        .cloned()
-        .min_by(|a, b| a.partial_cmp(b).unwrap())
-        .unwrap();
-    let max = data
+        .copied()
+        .filter(|value| value.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+            (min.min(value), max.max(value))
+        });
+    let range = max - min;
+    Ok(data
         .iter()
-        .cloned()
-        .max_by(|a, b| a.partial_cmp(b).unwrap())
-        .unwrap();
-
-    data.iter()
-        .map(|&value| (value - min) / (max - min))
-        .collect()
+        .map(|&value| {
+            if !value.is_finite() || range <= 0.0 {
+                0.0
+            } else {
+                (value - min) / range
+            }
+        })
+        .collect())
 }
 
 // #[derive(FromPyObject, Deserialize, Serialize, Clone)]
@@ -50,6 +91,36 @@ pub enum Value<'py> {
     Int(i32),
     Str(String),
     Ndarray(PyReadonlyArrayDyn<'py, f64>),
+    Image(Image),
+}
+
+impl<'py> Value<'py> {
+    /// Whether it is or contains NaN or an infinity.
+    pub fn is_nonfinite(&self) -> bool {
+        match self {
+            Value::Float(f) => !f.is_finite(),
+            Value::Ndarray(arr) => arr.as_array().iter().any(|element| !element.is_finite()),
+            _ => false,
+        }
+    }
+}
+
+/// A float as JSON. NaN and the infinities, which JSON has no numbers for,
+/// become the strings nexus and the backend take for them.
+struct JsonFloat(f64);
+
+impl Serialize for JsonFloat {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0 {
+            f if f.is_nan() => serializer.serialize_str("NaN"),
+            f if f == f64::INFINITY => serializer.serialize_str("Infinity"),
+            f if f == f64::NEG_INFINITY => serializer.serialize_str("-Infinity"),
+            f => serializer.serialize_f64(f),
+        }
+    }
 }
 
 impl<'py> Serialize for Value<'py> {
@@ -58,24 +129,121 @@ impl<'py> Serialize for Value<'py> {
         S: Serializer,
     {
         match self {
-            Value::Float(f) => serializer.serialize_f64(*f),
+            Value::Float(f) => JsonFloat(*f).serialize(serializer),
             Value::Int(i) => serializer.serialize_i32(*i),
             Value::Str(s) => serializer.serialize_str(s),
             Value::Ndarray(arr) => {
                 // TODO: keep the shape intact
-                let vec_data: Vec<f64> = arr.as_slice().unwrap().to_vec();
-                vec_data.serialize(serializer)
+                // in logical order, which works for views that aren't contiguous too
+                serializer.collect_seq(arr.as_array().iter().map(|&f| JsonFloat(f)))
+            }
+            Value::Image(image) => image.to_json().serialize(serializer),
+        }
+    }
+}
+
+/// A value the run config can hold. numpy scalars and arrays are taken as
+/// the Python numbers and lists they stand for, tuples as lists.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<ConfigValue>),
+    Map(BTreeMap<String, ConfigValue>),
+}
+
+impl ConfigValue {
+    /// Converts `value`, raising a `TypeError` naming the offending part of
+    /// `key`, e.g. `layers[1].units`, if it can't be represented.
+    pub fn from_py(key: &str, value: &PyAny) -> PyResult<ConfigValue> {
+        if value.is_none() {
+            return Ok(ConfigValue::None);
+        }
+        // before ints, which bools are
+        if let Ok(b) = value.downcast::<PyBool>() {
+            return Ok(ConfigValue::Bool(b.is_true()));
+        }
+        if value.is_instance_of::<PyLong>() {
+            return value.extract().map(ConfigValue::Int).map_err(|_| {
+                PyTypeError::new_err(format!(
+                    "Config value of {} is too large for an integer",
+                    key
+                ))
+            });
+        }
+        if let Ok(f) = value.downcast::<PyFloat>() {
+            return Ok(ConfigValue::Float(f.value()));
+        }
+        if let Ok(s) = value.downcast::<PyString>() {
+            return Ok(ConfigValue::Str(s.to_str()?.to_string()));
+        }
+        if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+            return value
+                .iter()?
+                .enumerate()
+                .map(|(i, item)| ConfigValue::from_py(&format!("{}[{}]", key, i), item?))
+                .collect::<PyResult<_>>()
+                .map(ConfigValue::List);
+        }
+        if let Ok(dict) = value.downcast::<PyDict>() {
+            let mut map = BTreeMap::new();
+            for (k, v) in dict {
+                let Ok(k) = k.downcast::<PyString>() else {
+                    return Err(PyTypeError::new_err(format!(
+                        "Config keys must be strings, {} has the key {}",
+                        key,
+                        k.repr()?
+                    )));
+                };
+                let k = k.to_str()?;
+                map.insert(
+                    k.to_string(),
+                    ConfigValue::from_py(&format!("{}.{}", key, k), v)?,
+                );
             }
+            return Ok(ConfigValue::Map(map));
+        }
+        let type_ = value.get_type();
+        if type_.getattr("__module__")?.extract::<&str>()? == "numpy" && value.hasattr("tolist")? {
+            return ConfigValue::from_py(key, value.call_method0("tolist")?);
+        }
+        Err(PyTypeError::new_err(format!(
+            "Config value of {} has the unsupported type {}, expected bool, int, float, str, list or dict",
+            key,
+            type_.name()?
+        )))
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ConfigValue::None => serde_json::Value::Null,
+            ConfigValue::Bool(b) => serde_json::Value::Bool(*b),
+            ConfigValue::Int(i) => serde_json::Value::from(*i),
+            ConfigValue::Float(f) => serde_json::to_value(JsonFloat(*f)).unwrap(),
+            ConfigValue::Str(s) => serde_json::Value::String(s.clone()),
+            ConfigValue::List(items) => items.iter().map(ConfigValue::to_json).collect(),
+            ConfigValue::Map(map) => serde_json::Value::Object(
+                map.iter().map(|(k, v)| (k.clone(), v.to_json())).collect(),
+            ),
         }
     }
 }
 
-fn ndarray_to_image(arr: PyReadonlyArrayDyn<'_, f64>, path: &String) -> HashMap<String, String> {
+fn ndarray_to_image(
+    arr: PyReadonlyArrayDyn<'_, f64>,
+    path: &String,
+) -> PyResult<HashMap<String, String>> {
     let shape = arr.shape();
     // Convert the ndarray to a Vec<f64> for serialization
-    let vec_data: Vec<f64> = arr.as_slice().unwrap().to_vec();
+    let vec_data: Vec<f64> = arr
+        .as_slice()
+        .map_err(|e| PyValueError::new_err(format!("Invalid image array: {}", e)))?
+        .to_vec();
     // convert to Vec<u8> for image serialization
-    let normalized = normalize(&vec_data);
+    let normalized = normalize(&vec_data)?;
     let byte_values: Vec<u8> = normalized.iter().map(|&v| (v * 255.0) as u8).collect();
 
     // compute sha256 of the image
@@ -85,30 +253,119 @@ fn ndarray_to_image(arr: PyReadonlyArrayDyn<'_, f64>, path: &String) -> HashMap<
     let image_sha256_str = format!("{:x}", image_sha256);
 
     let img: image::ImageBuffer<image::Rgb<u8>, Vec<u8>> =
-        image::ImageBuffer::from_vec(shape[0] as u32, shape[1] as u32, byte_values).unwrap();
+        image::ImageBuffer::from_vec(shape[0] as u32, shape[1] as u32, byte_values)
+            .ok_or_else(|| PyValueError::new_err("Image array must have 3 channels"))?;
 
-    std::fs::create_dir_all(format!("{}/media/images", path)).unwrap();
+    std::fs::create_dir_all(format!("{}/media/images", path))?;
     // You can now save or manipulate the ImageBuffer
     // use sha256 as the filename.png
     let image_path = format!("media/images/{}.png", &image_sha256_str[..20]);
     let full_path = format!("{}/{}", path, image_path);
-    img.save(&full_path).unwrap();
+    img.save(&full_path).map_err(io::Error::other)?;
 
     let mut json = HashMap::new();
     json.insert("_type".to_string(), "image-file".to_string());
     json.insert("path".to_string(), image_path.to_string());
     json.insert("sha256".to_string(), image_sha256_str.to_string());
 
-    json
+    Ok(json)
+}
+
+/// Takes the committed steps out of `history`, as the messages sending them.
+fn history_messages(
+    history: &mut HistoryBuffer,
+    stream_id: &str,
+    include_current: bool,
+) -> Vec<wandb_internal::ServerRequest> {
+    let messages: Vec<_> = history
+        .drain(include_current)
+        .into_iter()
+        .map(|partial_history_request| wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(
+                    wandb_internal::Record {
+                        record_type: Some(wandb_internal::record::RecordType::Request(
+                            wandb_internal::Request {
+                                request_type: Some(
+                                    wandb_internal::request::RequestType::PartialHistory(
+                                        partial_history_request,
+                                    ),
+                                ),
+                            },
+                        )),
+                        info: Some(wandb_internal::RecordInfo {
+                            stream_id: stream_id.to_string(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ),
+            ),
+        })
+        .collect();
+    tracing::debug!("Flushing {} history steps", messages.len());
+    messages
 }
 
 #[pyclass]
 pub struct Run {
     pub settings: Settings,
     pub interface: Interface,
+    // shared with the thread sending the steps left waiting by an idle loop
+    pub history: Arc<Mutex<HistoryBuffer>>,
+    history_flusher: Option<Periodic>,
+    // metric definitions, keyed by name or glob
+    pub metrics: HashMap<String, wandb_internal::MetricRecord>,
+    pub config: Config,
+    // summary values set explicitly or resumed, as opposed to derived from history
+    pub summary: Map<String, serde_json::Value>,
+    pub finished: bool,
+    preempting: bool,
+    // None without a limit
+    rate_limiter: Option<RateLimiter>,
+    rate_limit_policy: rate_limit::Policy,
+    // whether dropping history was warned about
+    warned_rate_limit: bool,
+    system_monitor: Option<Periodic>,
+    alerts: AlertLimiter,
+    live_files: LiveFiles,
+    file_watcher: Option<Periodic>,
+    // the runs finished on SIGINT/SIGTERM, which this one leaves once finished
+    pub signal_runs: Option<SignalRuns>,
+    #[cfg(feature = "async-writer")]
+    writer: AsyncWriter,
 }
 
 impl Run {
+    pub fn new(settings: Settings, interface: Interface) -> Self {
+        let rate_limiter = (settings.history_rate_limit > 0.0)
+            .then(|| RateLimiter::new(settings.history_rate_limit));
+        // validated with the other settings
+        let rate_limit_policy = rate_limit::parse_policy(&settings.history_rate_limit_policy)
+            .unwrap_or(rate_limit::Policy::Block);
+        Run {
+            settings,
+            interface,
+            history: Arc::new(Mutex::new(HistoryBuffer::new(history::flush_interval()))),
+            history_flusher: None,
+            metrics: HashMap::new(),
+            config: Config::default(),
+            summary: Map::new(),
+            finished: false,
+            preempting: false,
+            rate_limiter,
+            rate_limit_policy,
+            warned_rate_limit: false,
+            system_monitor: None,
+            alerts: AlertLimiter::default(),
+            live_files: LiveFiles::default(),
+            file_watcher: None,
+            signal_runs: None,
+            #[cfg(feature = "async-writer")]
+            writer: AsyncWriter::stopped(),
+        }
+    }
+
     fn id(&self) -> String {
         self.settings.proto.run_id.clone().unwrap()
     }
@@ -116,7 +373,7 @@ impl Run {
 
 #[pymethods]
 impl Run {
-    pub fn init(&mut self, id: Option<String>) {
+    pub fn init(&mut self, id: Option<String>) -> PyResult<()> {
         // generate a random string of length 8 if run_id is None:
         let run_id = match id {
             Some(id) => id,
@@ -125,18 +382,20 @@ impl Run {
         tracing::debug!("Initializing run {}", run_id);
         self.settings.proto.run_id = Some(run_id.clone());
 
+        let mode = self.settings.mode_kind();
+        if mode == Mode::Disabled {
+            tracing::debug!("Run {} is disabled", run_id);
+            return Ok(());
+        }
+
         // generate timespec in YYYYMMDD_HHMMSS format
         let timespec = chrono::Local::now().format("%Y%m%d_%H%M%S").to_string();
         self.settings.proto.timespec = Some(timespec.clone());
 
-        self.settings.proto.offline = if self.settings.proto.mode == Some("offline".to_string()) {
-            Some(true)
-        } else {
-            Some(false)
-        };
+        self.settings.proto.offline = Some(mode == Mode::Offline);
 
         // if offline, "offline-run", else "run"
-        let run_mode = if self.settings.proto.offline.is_some() {
+        let run_mode = if mode == Mode::Offline {
             "offline-run".to_string()
         } else {
             "run".to_string()
@@ -144,16 +403,37 @@ impl Run {
         self.settings.proto.run_mode = Some(run_mode.clone());
 
         // <get_cwd>/.wandb
-        let wandb_dir = format!("{}/.wandb", std::env::current_dir().unwrap().display());
+        let wandb_dir = format!("{}/.wandb", std::env::current_dir()?.display());
         self.settings.proto.wandb_dir = Some(wandb_dir.clone());
 
         let sync_dir = format!("{}/{}-{}-{}", wandb_dir, run_mode, timespec, run_id);
-        std::fs::create_dir_all(&sync_dir).unwrap();
+        std::fs::create_dir_all(&sync_dir)?;
         self.settings.proto.sync_dir = Some(sync_dir.clone());
 
-        self.settings.proto.sync_file = Some(format!("{}/run-{}.wandb", sync_dir, run_id));
+        let sync_file = format!("{}/run-{}.wandb", sync_dir, run_id);
+        self.settings.proto.sync_file = Some(sync_file.clone());
         self.settings.proto.files_dir = Some(format!("{}/files", sync_dir));
 
+        // two monitors would report everything twice
+        if self.settings.sample_system_metrics {
+            self.settings.proto.disable_stats = Some(true);
+        }
+
+        // the run must not end up in the account of the key
+        if self.settings.is_anonymous() {
+            self.settings.proto.api_key = None;
+        }
+
+        // records are kept locally before they are sent, in case they never
+        // make it; without nexus, this is the only record of the run
+        let log_file = if mode == Mode::Offline {
+            sync_file
+        } else {
+            transaction_log::online_path(&sync_dir, &run_id)
+        };
+        let log = TransactionLog::create(&log_file)?;
+        self.interface.transaction_log = Some(Arc::new(log));
+
         let server_inform_init_request = wandb_internal::ServerRequest {
             server_request_type: Some(
                 wandb_internal::server_request::ServerRequestType::InformInit(
@@ -168,15 +448,19 @@ impl Run {
             ),
         };
 
-        self.interface
-            .conn
-            .send_message(&server_inform_init_request)
-            .unwrap();
+        self.interface.send_message(&server_inform_init_request)?;
 
         let mut server_publish_run_request = wandb_internal::Record {
             record_type: Some(wandb_internal::record::RecordType::Run(
                 wandb_internal::RunRecord {
                     run_id: self.id(),
+                    project: self.settings.proto.project.clone().unwrap_or_default(),
+                    entity: self.settings.proto.entity.clone().unwrap_or_default(),
+                    tags: self.settings.tags(),
+                    run_group: self.settings.proto.run_group.clone().unwrap_or_default(),
+                    job_type: self.settings.proto.run_job_type.clone().unwrap_or_default(),
+                    notes: self.settings.proto.run_notes.clone().unwrap_or_default(),
+                    sweep_id: self.settings.proto.sweep_id.clone().unwrap_or_default(),
                     // display_name: "gooba-gaba".to_string(),
                     info: Some(wandb_internal::RecordInfo {
                         stream_id: self.id(),
@@ -195,13 +479,26 @@ impl Run {
         // update settings with backend's response
         let result = self
             .interface
-            .conn
-            .send_and_recv_message(&mut server_publish_run_request, &mut self.interface.handles);
+            .send_and_recv_message(&mut server_publish_run_request);
 
-        match result.result_type {
+        match result.and_then(|result| result.result_type) {
             Some(wandb_internal::result::ResultType::RunResult(run_result)) => {
+                // e.g. resuming with "must" a run that does not exist
+                if let Some(error) = run_result.error {
+                    tracing::error!("Failed to initialize run {}: {}", run_id, error.message);
+                    return Err(PyRuntimeError::new_err(error.message));
+                }
                 // TODO: this should be properly done in the settings module, like in python
-                let run = run_result.run.unwrap();
+                let run = run_result.run.ok_or_else(|| {
+                    Error::Protocol(format!("No run in the result for run {}", run_id))
+                })?;
+                if self
+                    .settings
+                    .resume()
+                    .is_some_and(|resume| resume != "never")
+                {
+                    self.restore(&run);
+                }
                 let entity = run.entity;
                 let display_name = run.display_name;
                 let project = run.project;
@@ -209,17 +506,14 @@ impl Run {
                 self.settings.proto.project = Some(project.clone());
                 self.settings.proto.run_name = Some(display_name.clone());
 
-                let url = format!(
-                    "https://wandb.ai/{}/{}/runs/{}",
-                    entity,
-                    project,
-                    &self.id()
-                );
+                let url =
+                    settings::run_url(&self.settings.base_url(), &entity, &project, &self.id());
                 self.settings.proto.run_url = Some(url.clone());
             }
             Some(_) => {
                 tracing::warn!("Unexpected result type");
             }
+            None if mode == Mode::Offline => {}
             None => {
                 tracing::warn!("No result type, me is puzzled");
             }
@@ -236,7 +530,6 @@ impl Run {
                             }),
                             info: Some(wandb_internal::RequestInfo {
                                 stream_id: self.id(),
-                                ..Default::default()
                             }),
                         },
                     )),
@@ -254,103 +547,593 @@ impl Run {
         };
         let result = self
             .interface
-            .conn
-            .send_and_recv_message(&mut server_publish_run_start, &mut self.interface.handles);
+            .send_and_recv_message(&mut server_publish_run_start);
 
         tracing::debug!("Result: {:?}", result);
 
+        let handshake = self.handshake(server_publish_run_request, server_publish_run_start);
+        self.interface.set_handshake(&run_id, handshake);
+
+        let interval = self.settings.heartbeat_interval_secs;
+        if interval > 0.0 {
+            self.interface
+                .start_heartbeat(self.keepalive_request(), Duration::from_secs_f64(interval));
+        }
+        if self.settings.sample_system_metrics {
+            self.start_system_monitor();
+        }
+        self.start_history_flusher();
+        #[cfg(feature = "async-writer")]
+        if !self.settings.offline() {
+            match AsyncWriter::start(async_writer::CAPACITY) {
+                Ok(writer) => self.writer = writer,
+                Err(e) => tracing::warn!("Failed to start the history writer: {}", e),
+            }
+        }
+
         if self.settings.offline() {
             printer::print_offline_header();
+        } else if let (Some(name), Some(url)) =
+            (&self.settings.proto.run_name, &self.settings.proto.run_url)
+        {
+            printer::print_header(name, url);
+            if self.settings.is_anonymous() {
+                printer::print_anonymous(url);
+            }
         } else {
-            printer::print_header(&self.settings.run_name(), &self.settings.run_url());
+            tracing::warn!("Run {} was not confirmed by nexus", run_id);
         }
 
-        // printer::print_header(&self.settings.run_name(), &self.settings.run_url());
+        Ok(())
     }
 
     // pub fn log_json(&self, data: String) {
     //     self.log(serde_json::from_str(&data).unwrap_or(HashMap::new()));
     // }
 
-    pub fn log(&self, data: HashMap<String, Value>) {
-        tracing::debug!("Logging to run {}", self.id());
+    /// Logs values to the current step. Like `wandb.log`, an explicit `step`
+    /// moves to that step, committing the previous one, and values logged
+    /// with `commit=False` wait for more values for the same step. `commit`
+    /// defaults to true unless a step is given. Metrics given a step metric by
+    /// `define_metric` are plotted against it instead. Logging faster than
+    /// `history_rate_limit` blocks or drops the values, depending on
+    /// `history_rate_limit_policy`. NaN and infinities are logged as the
+    /// strings `NaN`, `Infinity` and `-Infinity`, or with
+    /// `raise_on_nonfinite` raise a `ValueError` once the other values are logged.
+    /// The values are logged at `timestamp`, in seconds since the epoch, or
+    /// else now, e.g. to replay historical data with its original times.
+    ///
+    /// The run can be logged to from several threads. Each thread's values
+    /// are sent in the order it logged them, with the GIL released while
+    /// sending. Between threads, which values share a step depends on the
+    /// order the calls happen to reach the run in.
+    ///
+    /// With the `async-writer` feature, the history is written by a
+    /// background task, and logging only waits for it once it is
+    /// `async_writer::CAPACITY` batches behind. Failing writes are then
+    /// logged instead of raised.
+    #[pyo3(signature = (data, step=None, commit=None, timestamp=None))]
+    pub fn log(
+        mut slf: PyRefMut<'_, Self>,
+        data: HashMap<String, Value>,
+        step: Option<i64>,
+        commit: Option<bool>,
+        timestamp: Option<f64>,
+    ) -> PyResult<()> {
+        let py = slf.py();
+        let wait = slf.rate_limit_wait(step, commit);
+        let mut slf = if wait.is_zero() {
+            slf
+        } else {
+            // other threads go on while this one is held back
+            let run: Py<Self> = slf.into();
+            py.allow_threads(|| std::thread::sleep(wait));
+            run.into_ref(py).try_borrow_mut()?
+        };
+        let (pending, mut nonfinite) = slf.add_history(data, step, commit, timestamp)?;
+        #[cfg(feature = "async-writer")]
+        let submitter = slf.writer.submitter();
+        // leaves the run to other threads while writing
+        drop(slf);
+        if let Some(pending) = pending {
+            #[cfg(feature = "async-writer")]
+            py.allow_threads(|| submitter.submit(pending))?;
+            #[cfg(not(feature = "async-writer"))]
+            py.allow_threads(|| pending.send())?;
+        }
+        if !nonfinite.is_empty() {
+            nonfinite.sort();
+            return Err(PyValueError::new_err(format!(
+                "Not logging non-finite values of {}",
+                nonfinite.join(", ")
+            )));
+        }
+        Ok(())
+    }
 
-        // TODO: make it work with steps
-        // let history_record = wandb_internal::HistoryRecord {
-        //     item: data
-        //         .iter()
-        //         .map(|(k, v)| wandb_internal::HistoryItem {
-        //             key: k.clone(),
-        //             value_json: v.to_string(),
-        //             ..Default::default()
-        //         })
-        //         .collect(),
-        //     ..Default::default()
-        // };
+    /// Defines how a metric is summarized and which metric it is plotted against.
+    /// Defining the same metric again updates the previous definition. The
+    /// step metric can be logged along with the metric, otherwise its last
+    /// logged value is used, so that e.g. `val/acc` logged every epoch is
+    /// plotted against `epoch` while the other metrics use the global step.
+    pub fn define_metric(
+        &mut self,
+        name: String,
+        step_metric: Option<String>,
+        summary: Option<String>,
+    ) -> PyResult<()> {
+        let summary = summary
+            .map(|summary| metric::parse_summary(&summary))
+            .transpose()
+            .map_err(PyValueError::new_err)?;
 
-        // let record = wandb_internal::Record {
-        //     record_type: Some(wandb_internal::record::RecordType::History(history_record)),
-        //     info: Some(wandb_internal::RecordInfo {
-        //         stream_id: self.id.clone(),
-        //         ..Default::default()
-        //     }),
-        //     ..Default::default()
-        // };
+        let record = match self.metrics.get(&name) {
+            Some(existing) => {
+                let mut options = existing.options.clone().unwrap_or_default();
+                options.step_sync |= step_metric.is_some();
+                wandb_internal::MetricRecord {
+                    step_metric: step_metric.unwrap_or_else(|| existing.step_metric.clone()),
+                    summary: summary.or_else(|| existing.summary.clone()),
+                    options: Some(options),
+                    ..existing.clone()
+                }
+            }
+            None => metric::metric_record(&name, step_metric, summary),
+        };
+        self.metrics.insert(name, record.clone());
 
-        // let message = wandb_internal::ServerRequest {
-        //     server_request_type: Some(
-        //         wandb_internal::server_request::ServerRequestType::RecordPublish(record),
-        //     ),
-        // };
+        if self.settings.mode_kind() != Mode::Disabled {
+            self.publish(wandb_internal::record::RecordType::Metric(record))?;
+        }
+        Ok(())
+    }
 
-        // self.interface.conn.send_message(&message).unwrap();
+    /// Updates the run config. Dotted keys like `optimizer.lr` are expanded
+    /// into nested values. Values must be `None`, bools, ints, floats,
+    /// strings, or lists and dicts of them, else a `TypeError` is raised
+    /// and nothing is set. Nothing is set either if a key collides with
+    /// another, raising a `ValueError`.
+    pub fn update_config(&mut self, data: &PyDict) -> PyResult<()> {
+        let mut values = Vec::new();
+        for (key, value) in data {
+            let key: String = key.extract().map_err(|_| {
+                PyTypeError::new_err(format!("Config keys must be strings, got {}", key))
+            })?;
+            let value = ConfigValue::from_py(&key, value)?;
+            values.push((key, value));
+        }
 
-        let mut partial_history_request = wandb_internal::PartialHistoryRequest {
-            ..Default::default()
+        // a key colliding with an earlier one leaves the config as it was
+        let mut config = self.config.clone();
+        let items = values
+            .into_iter()
+            .map(|(key, value)| config.set(&key, value.to_json()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(PyValueError::new_err)?;
+        self.config = config;
+
+        if !items.is_empty() && self.settings.mode_kind() != Mode::Disabled {
+            self.publish(wandb_internal::record::RecordType::Config(
+                wandb_internal::ConfigRecord {
+                    update: items,
+                    ..Default::default()
+                },
+            ))?;
+        }
+        Ok(())
+    }
+
+    #[getter]
+    pub fn tags(&self) -> Vec<String> {
+        self.settings.tags()
+    }
+
+    /// Replaces the tags of the run. Repeated tags are dropped.
+    pub fn set_tags(&mut self, tags: Vec<String>) -> PyResult<()> {
+        self.settings.set_tags(tags);
+        let tags = self.settings.tags();
+        self.update_run(|run| run.tags = tags)
+    }
+
+    /// Renames the run. The name must not be empty.
+    pub fn set_name(&mut self, name: String) -> PyResult<()> {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err(PyValueError::new_err("Run name must not be empty"));
+        }
+        self.settings.proto.run_name = Some(name.clone());
+        self.update_run(|run| run.display_name = name.clone())?;
+
+        if !self.finished {
+            if let Some(url) = &self.settings.proto.run_url {
+                printer::print_rename(&name, url);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the notes of the run. Empty notes leave the previous ones in place.
+    pub fn set_notes(&mut self, notes: String) -> PyResult<()> {
+        // an update with empty notes would read as one leaving them as they are
+        if notes.is_empty() {
+            return Ok(());
+        }
+        self.settings.proto.run_notes = Some(notes.clone());
+        self.update_run(|run| run.notes = notes)
+    }
+
+    /// Sends a notification, e.g. by email or Slack. The same alert raised
+    /// again within `wait_duration` seconds (one minute by default) is dropped.
+    pub fn alert(
+        &mut self,
+        title: String,
+        text: String,
+        level: Option<String>,
+        wait_duration: Option<f64>,
+    ) -> PyResult<()> {
+        let level = alert::parse_level(level.as_deref().unwrap_or("info"))
+            .map_err(PyValueError::new_err)?;
+        let wait_duration = wait_duration.unwrap_or(60.0);
+        if !wait_duration.is_finite() || wait_duration < 0.0 {
+            return Err(PyValueError::new_err(format!(
+                "Invalid wait duration {}",
+                wait_duration
+            )));
+        }
+        let wait_duration = Duration::from_secs_f64(wait_duration);
+
+        if self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        if !self.alerts.should_send(&title, &text, level, wait_duration) {
+            tracing::debug!("Dropping repeated alert {:?}", title);
+            return Ok(());
+        }
+        self.publish(wandb_internal::record::RecordType::Alert(
+            alert::alert_record(&title, &text, level, wait_duration),
+        ))?;
+        Ok(())
+    }
+
+    /// Uploads the files matching a path or glob, relative to the working
+    /// directory unless absolute. With the `now` policy they are uploaded right
+    /// away, with `end` when the run finishes, and with `live` both and whenever
+    /// they change. Returns where the files are placed in the files directory.
+    #[pyo3(signature = (glob_str, policy="live"))]
+    pub fn save(&mut self, glob_str: &str, policy: &str) -> PyResult<Vec<String>> {
+        let policy = files::parse_policy(policy).map_err(PyValueError::new_err)?;
+        if self.settings.mode_kind() == Mode::Disabled {
+            return Ok(vec![]);
+        }
+
+        let (base, matched) = files::expand_glob(glob_str, &std::env::current_dir()?);
+        if matched.is_empty() {
+            tracing::warn!("No files matched {:?}, nothing to save", glob_str);
+            return Ok(vec![]);
+        }
+
+        let files_dir = PathBuf::from(self.settings.files_dir());
+        let mut paths = Vec::new();
+        for source in matched {
+            let relative = source
+                .strip_prefix(&base)
+                .ok()
+                .or_else(|| source.file_name().map(Path::new))
+                .unwrap_or(&source)
+                .to_path_buf();
+            files::stage(&source, &files_dir, &relative, policy == PolicyType::Now)?;
+
+            let relative = relative.to_string_lossy().to_string();
+            if policy == PolicyType::Live {
+                self.live_files.add(source, relative.clone());
+            }
+            paths.push(relative);
+        }
+
+        self.publish(wandb_internal::record::RecordType::Files(
+            files::files_record(paths.clone(), policy),
+        ))?;
+        if policy == PolicyType::Live && self.file_watcher.is_none() {
+            self.start_file_watcher();
+        }
+        Ok(paths)
+    }
+
+    /// Logs files and directories, recursively, as a new version of artifact
+    /// `name`. Files unchanged since the version logged last from this
+    /// directory are not uploaded again. Returns the id of the new version.
+    pub fn log_artifact(
+        &mut self,
+        name: &str,
+        r#type: &str,
+        paths: Vec<String>,
+    ) -> PyResult<Option<String>> {
+        match self.settings.mode_kind() {
+            Mode::Disabled => return Ok(None),
+            Mode::Offline => {
+                return Err(PyRuntimeError::new_err(
+                    "Artifacts can't be logged in offline mode",
+                ))
+            }
+            Mode::Online => {}
+        }
+        let files = artifact::collect_files(&paths, &std::env::current_dir()?)?;
+        if files.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "Artifact {} has no files",
+                name
+            )));
+        }
+
+        self.upload_artifact(name, r#type, &files).map(Some)
+    }
+
+    /// Logs the source code of the run as an artifact of type `code`: by
+    /// default the script that was run, or else the files matching
+    /// `include_glob` and not `exclude_glob`, both relative to `root`, the
+    /// working directory by default. Binary files and files over 10 MiB are
+    /// skipped. Returns the id of the artifact version.
+    #[pyo3(signature = (root=None, include_glob=None, exclude_glob=None))]
+    pub fn log_code(
+        &mut self,
+        py: Python<'_>,
+        root: Option<String>,
+        include_glob: Option<String>,
+        exclude_glob: Option<String>,
+    ) -> PyResult<Option<String>> {
+        match self.settings.mode_kind() {
+            Mode::Disabled => return Ok(None),
+            Mode::Offline => {
+                return Err(PyRuntimeError::new_err(
+                    "Code can't be logged in offline mode",
+                ))
+            }
+            Mode::Online => {}
+        }
+        let root = match root {
+            Some(root) => PathBuf::from(root),
+            None => std::env::current_dir()?,
+        };
+        let argv: Vec<String> = py.import("sys")?.getattr("argv")?.extract()?;
+        let program = argv.first().filter(|program| !program.is_empty());
+        let include = match (&include_glob, program) {
+            (Some(include), _) => include.clone(),
+            (None, Some(program)) => program.clone(),
+            (None, None) => {
+                return Err(PyValueError::new_err(
+                    "No script was run, pass the files to log with include_glob",
+                ))
+            }
         };
 
-        for (k, v) in data {
-            let mut item = wandb_internal::HistoryItem {
-                key: k.clone(),
+        let files =
+            artifact::collect_code(&root, &include, exclude_glob.as_deref(), MAX_CODE_FILE_SIZE)?;
+        if files.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "No source files match {:?}",
+                include
+            )));
+        }
+        let run_id = self.id();
+        let name = artifact::code_artifact_name(
+            self.settings.proto.project.as_deref().unwrap_or_default(),
+            program.map_or(&run_id, |program| program),
+        );
+        self.upload_artifact(&name, "code", &files).map(Some)
+    }
+
+    /// Looks up a config value, resolving dotted keys into nested values.
+    pub fn get_config(&self, py: Python<'_>, key: &str) -> Option<PyObject> {
+        self.config.get(key).map(|value| config::to_py(py, value))
+    }
+
+    /// Sets a summary value of the run without logging it to history,
+    /// replacing the previous value. Metrics summarized by `define_metric`
+    /// keep their aggregations unless `force` is set.
+    #[pyo3(signature = (key, value, force=false))]
+    pub fn set_summary(&mut self, key: String, value: Value, force: bool) -> PyResult<()> {
+        let aggregated = self
+            .metrics
+            .get(&key)
+            .and_then(|metric| metric.summary.as_ref())
+            .is_some_and(metric::is_aggregated);
+        if aggregated && !force {
+            return Err(PyValueError::new_err(format!(
+                "The summary of {:?} is defined by define_metric, pass force=True to replace it",
+                key
+            )));
+        }
+        let value = serde_json::to_value(&value)
+            .map_err(|e| PyValueError::new_err(format!("Invalid summary value: {}", e)))?;
+        let value_json = value.to_string();
+        self.summary.insert(key.clone(), value);
+
+        if self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        self.publish(wandb_internal::record::RecordType::Summary(
+            wandb_internal::SummaryRecord {
+                update: vec![wandb_internal::SummaryItem {
+                    key,
+                    value_json,
+                    ..Default::default()
+                }],
                 ..Default::default()
-            };
-            match v {
-                Value::Ndarray(arr) => {
-                    // TODO: convert to image if shape is valid, otherwise just serialize
-                    let shape = arr.shape();
-                    if shape.len() == 3 {
-                        let value_json = ndarray_to_image(arr, &self.settings.files_dir());
-                        item.value_json = serde_json::to_string(&value_json).unwrap();
-                        // TODO: tell nexus to upload the image
-                        self.save_files(&value_json.get("path").unwrap().to_string());
-                    } else {
-                        item.value_json = serde_json::to_string(&Value::Ndarray(arr)).unwrap();
-                    }
+            },
+        ))?;
+        Ok(())
+    }
+
+    /// The current summary of the run, including the values nexus derived
+    /// from history.
+    #[getter]
+    pub fn summary(&mut self, py: Python<'_>) -> PyObject {
+        let mut summary = self.summary.clone();
+        // nexus forgets about the run once it is finished
+        if !self.finished && self.settings.mode_kind() != Mode::Disabled {
+            for item in self.get_summary().unwrap_or_default() {
+                if item.key == "_wandb" {
+                    continue;
                 }
-                _ => {
-                    item.value_json = serde_json::to_string(&v).unwrap();
+                if let Ok(value) = serde_json::from_str(&item.value_json) {
+                    summary.insert(item.key, value);
                 }
             }
-            partial_history_request.item.push(item);
         }
+        config::to_py(py, &serde_json::Value::Object(summary))
+    }
 
-        // let partial_history_request = wandb_internal::PartialHistoryRequest {
-        //     item: data
-        //         .iter()
-        //         .map(|(k, v)| wandb_internal::HistoryItem {
-        //             key: k.clone(),
-        //             value_json: serde_json::to_string(&v).unwrap(),
-        //             ..Default::default()
-        //         })
-        //         .collect(),
-        //     ..Default::default()
-        // };
+    /// Counters of what was written to nexus, for debugging throughput:
+    /// `sent`, the messages by record or request type, `bytes_sent`,
+    /// `reconnects` and `queued`, the messages waiting to be written. The
+    /// runs of a session share the connection, and so the counters.
+    pub fn get_stats(&self, py: Python<'_>) -> PyObject {
+        let stats = self.interface.stats();
+        let stats = serde_json::json!({
+            "sent": stats.sent,
+            "bytes_sent": stats.bytes_sent,
+            "reconnects": stats.reconnects,
+            "queued": stats.queued,
+        });
+        config::to_py(py, &stats)
+    }
 
-        let record = wandb_internal::Record {
+    /// Tells nexus that the run is about to be preempted, e.g. on a spot
+    /// instance, so that it is marked preempted and can be requeued rather
+    /// than marked crashed. It still has to be finished as usual.
+    pub fn mark_preempting(&mut self) -> PyResult<()> {
+        if self.preempting || self.finished || self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        self.publish(wandb_internal::record::RecordType::Preempting(
+            wandb_internal::RunPreemptingRecord::default(),
+        ))?;
+        self.preempting = true;
+        Ok(())
+    }
+
+    /// Sends all buffered history to nexus.
+    pub fn flush(&mut self) -> PyResult<()> {
+        if self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        self.send_history(true)?;
+        Ok(())
+    }
+
+    pub fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    /// Finishes the run when leaving a `with` block, as crashed if it is left
+    /// through an exception. The exception is not suppressed.
+    pub fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        exc_type: Option<&PyAny>,
+        _exc_value: Option<&PyAny>,
+        _traceback: Option<&PyAny>,
+    ) -> PyResult<bool> {
+        self.py_finish(
+            py,
+            Some(if exc_type.is_some() { 1 } else { 0 }),
+            Some(FINISH_TIMEOUT_SECS),
+        )?;
+        Ok(false)
+    }
+
+    /// Finishes the run. A non-zero `exit_code` marks the run as crashed.
+    /// Finishing a run again does nothing. If nexus doesn't take the rest of
+    /// the run or confirm its end within `timeout` seconds, the connection is closed and a
+    /// `ConnectionError` raised; the transaction log in the run's directory
+    /// keeps what was logged. A `timeout` of `None` waits for as long as it takes.
+    /// Other threads go on meanwhile.
+    #[pyo3(name = "finish", signature = (exit_code=None, timeout=Some(FINISH_TIMEOUT_SECS)))]
+    pub fn py_finish(
+        &mut self,
+        py: Python<'_>,
+        exit_code: Option<i32>,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        py.allow_threads(|| self.finish(exit_code, timeout))
+    }
+}
+
+impl Run {
+    /// [`finish`](Self::py_finish), without the GIL.
+    pub fn finish(&mut self, exit_code: Option<i32>, timeout: Option<f64>) -> PyResult<()> {
+        if let Some(timeout) = timeout.filter(|timeout| !timeout.is_finite() || *timeout <= 0.0) {
+            return Err(PyValueError::new_err(format!(
+                "timeout must be a positive number of seconds, got {}",
+                timeout
+            )));
+        }
+        if self.finished || self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        self.finished = true;
+        if let Some(runs) = self.signal_runs.take() {
+            let id = self.id();
+            runs.lock().unwrap().retain(|(run_id, _)| *run_id != id);
+        }
+        self.interface.stop_heartbeat();
+        if let Some(mut monitor) = self.system_monitor.take() {
+            monitor.stop();
+        }
+        // nexus uploads live files once more at the end
+        if let Some(mut watcher) = self.file_watcher.take() {
+            watcher.stop();
+        }
+        // the rest of the history goes with the exit
+        if let Some(mut flusher) = self.history_flusher.take() {
+            flusher.stop();
+        }
+        let exit_code = exit_code.unwrap_or(0);
+        tracing::debug!("Finishing run {} with exit code {}", self.id(), exit_code);
+
+        // covers everything from here on, writing included
+        let deadline = timeout.map(|timeout| Instant::now() + Duration::from_secs_f64(timeout));
+        self.interface.set_deadline(deadline);
+        // the history still waiting for the writer goes first
+        #[cfg(feature = "async-writer")]
+        self.writer.close_until(deadline);
+        self.shutdown(exit_code);
+        let timed_out = self.interface.timed_out();
+        // other runs may still be using the connection
+        self.interface.close();
+        if timed_out {
+            return Err(Error::Connection(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "nexus did not confirm the end of run {} within {:.1}s, its records are kept in {}",
+                    self.id(),
+                    timeout.unwrap_or_default(),
+                    self.settings.sync_dir()
+                ),
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Tells nexus that the run is over and prints its summary.
+    fn shutdown(&mut self, exit_code: i32) {
+        if let Err(e) = self.send_history(true) {
+            tracing::error!("Failed to send history: {}", e);
+        }
+
+        let mut record = self.exit_record(exit_code);
+        self.interface.send_and_recv_message(&mut record);
+
+        let mut record = wandb_internal::Record {
             record_type: Some(wandb_internal::record::RecordType::Request(
                 wandb_internal::Request {
-                    request_type: Some(wandb_internal::request::RequestType::PartialHistory(
-                        partial_history_request,
+                    request_type: Some(wandb_internal::request::RequestType::SampledHistory(
+                        wandb_internal::SampledHistoryRequest {
+                            info: Some(wandb_internal::RequestInfo {
+                                stream_id: self.id(),
+                            }),
+                        },
                     )),
                 },
             )),
@@ -361,65 +1144,9 @@ impl Run {
             ..Default::default()
         };
 
-        let message = wandb_internal::ServerRequest {
-            server_request_type: Some(
-                wandb_internal::server_request::ServerRequestType::RecordPublish(record),
-            ),
-        };
-
-        self.interface.conn.send_message(&message).unwrap();
-    }
-
-    pub fn finish(&mut self) {
-        tracing::debug!("Finishing run {}", self.id());
+        let sampled_history = self.interface.send_and_recv_message(&mut record);
 
-        let mut record = wandb_internal::Record {
-            record_type: Some(wandb_internal::record::RecordType::Exit(
-                wandb_internal::RunExitRecord {
-                    exit_code: 0,
-                    info: Some(wandb_internal::RecordInfo {
-                        stream_id: self.id(),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                },
-            )),
-            info: Some(wandb_internal::RecordInfo {
-                stream_id: self.id(),
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
-        self.interface
-            .conn
-            .send_and_recv_message(&mut record, &mut self.interface.handles);
-
-        let mut record = wandb_internal::Record {
-            record_type: Some(wandb_internal::record::RecordType::Request(
-                wandb_internal::Request {
-                    request_type: Some(wandb_internal::request::RequestType::SampledHistory(
-                        wandb_internal::SampledHistoryRequest {
-                            info: Some(wandb_internal::RequestInfo {
-                                stream_id: self.id(),
-                                ..Default::default()
-                            }),
-                        },
-                    )),
-                },
-            )),
-            info: Some(wandb_internal::RecordInfo {
-                stream_id: self.id(),
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
-
-        let sampled_history = self
-            .interface
-            .conn
-            .send_and_recv_message(&mut record, &mut self.interface.handles);
-
-        let sampled_history = match sampled_history.result_type {
+        let sampled_history = match sampled_history.and_then(|result| result.result_type) {
             Some(wandb_internal::result::ResultType::Response(response)) => {
                 match response.response_type {
                     Some(wandb_internal::response::ResponseType::SampledHistoryResponse(
@@ -435,6 +1162,8 @@ impl Run {
                 tracing::warn!("Unexpected result type");
                 return;
             }
+            // nothing to sample without nexus
+            None if self.settings.offline() => vec![],
             None => {
                 tracing::warn!("No result type, me is puzzled");
                 return;
@@ -448,51 +1177,8 @@ impl Run {
             history.insert(key, (value, None));
         }
 
-        let mut record = wandb_internal::Record {
-            record_type: Some(wandb_internal::record::RecordType::Request(
-                wandb_internal::Request {
-                    request_type: Some(wandb_internal::request::RequestType::GetSummary(
-                        wandb_internal::GetSummaryRequest {
-                            info: Some(wandb_internal::RequestInfo {
-                                stream_id: self.id(),
-                                ..Default::default()
-                            }),
-                        },
-                    )),
-                },
-            )),
-            info: Some(wandb_internal::RecordInfo {
-                stream_id: self.id(),
-                ..Default::default()
-            }),
-            ..Default::default()
-        };
-
-        let summary = self
-            .interface
-            .conn
-            .send_and_recv_message(&mut record, &mut self.interface.handles);
-
-        let summary = match summary.result_type {
-            Some(wandb_internal::result::ResultType::Response(response)) => {
-                match response.response_type {
-                    Some(wandb_internal::response::ResponseType::GetSummaryResponse(
-                        summary_response,
-                    )) => summary_response.item,
-                    _ => {
-                        tracing::warn!("Unexpected response type");
-                        return;
-                    }
-                }
-            }
-            Some(_) => {
-                tracing::warn!("Unexpected result type");
-                return;
-            }
-            None => {
-                tracing::warn!("No result type, me is puzzled");
-                return;
-            }
+        let Some(summary) = self.get_summary() else {
+            return;
         };
 
         for item in summary {
@@ -511,7 +1197,6 @@ impl Run {
                         wandb_internal::ShutdownRequest {
                             info: Some(wandb_internal::RequestInfo {
                                 stream_id: self.id(),
-                                ..Default::default()
                             }),
                         },
                     )),
@@ -524,10 +1209,7 @@ impl Run {
             ..Default::default()
         };
 
-        let result = self
-            .interface
-            .conn
-            .send_and_recv_message(&mut shutdown_request, &mut self.interface.handles);
+        let result = self.interface.send_and_recv_message(&mut shutdown_request);
 
         tracing::debug!("Result: {:?}", result);
 
@@ -544,10 +1226,9 @@ impl Run {
             ),
         };
         tracing::debug!("Sending inform finish request {:?}", inform_finish_request);
-        self.interface
-            .conn
-            .send_message(&inform_finish_request)
-            .unwrap();
+        if let Err(e) = self.interface.send_message(&inform_finish_request) {
+            tracing::error!("Failed to send inform finish request: {}", e);
+        }
 
         if self.settings.offline() {
             printer::print_offline_footer(&self.settings.sync_dir(), history);
@@ -560,18 +1241,16 @@ impl Run {
             );
         }
     }
-}
 
-impl Run {
-    fn save_files(&self, path: &String) {
-        let record = wandb_internal::Record {
-            record_type: Some(wandb_internal::record::RecordType::Files(
-                wandb_internal::FilesRecord {
-                    files: vec![wandb_internal::FilesItem {
-                        path: path.clone(),
-                        policy: 0,
+    pub fn exit_record(&self, exit_code: i32) -> wandb_internal::Record {
+        wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::Exit(
+                wandb_internal::RunExitRecord {
+                    exit_code,
+                    info: Some(wandb_internal::RecordInfo {
+                        stream_id: self.id(),
                         ..Default::default()
-                    }],
+                    }),
                     ..Default::default()
                 },
             )),
@@ -580,13 +1259,2729 @@ impl Run {
                 ..Default::default()
             }),
             ..Default::default()
+        }
+    }
+
+    fn start_system_monitor(&mut self) {
+        let interval = self.settings.proto.stats_sample_rate_seconds.unwrap_or(5.0);
+        if interval <= 0.0 {
+            tracing::warn!(
+                "Invalid stats sample rate {}, not sampling system metrics",
+                interval
+            );
+            return;
+        }
+        let pid = self
+            .settings
+            .proto
+            .stats_pid
+            .unwrap_or(std::process::id() as i32);
+        let mut monitor = SystemMonitor::new(pid);
+        let publisher = self.interface.publisher();
+        let stream_id = self.id();
+        self.system_monitor = Some(Periodic::start(
+            Duration::from_secs_f64(interval),
+            move || {
+                let metrics = monitor.sample();
+                if metrics.is_empty() {
+                    return;
+                }
+                if let Err(e) = publisher.publish(system_monitor::stats_record(metrics, &stream_id))
+                {
+                    tracing::debug!("Failed to send system metrics: {}", e);
+                }
+            },
+        ));
+    }
+
+    /// Sends the committed steps once they waited for the flush interval,
+    /// when no `log` comes along to send them, so that an idle or slow loop
+    /// doesn't keep them back. Without an interval every log sends them.
+    fn start_history_flusher(&mut self) {
+        let interval = self.history.lock().unwrap().flush_interval();
+        if interval.is_zero() {
+            return;
+        }
+        let history = self.history.clone();
+        let publisher = self.interface.publisher();
+        let stream_id = self.id();
+        self.history_flusher = Some(Periodic::start(interval, move || {
+            let pending = {
+                let mut history = history.lock().unwrap();
+                if !history.should_flush() {
+                    return;
+                }
+                publisher.queue_messages(&history_messages(&mut history, &stream_id, false))
+            };
+            if let Err(e) = pending.and_then(Pending::send) {
+                tracing::debug!("Failed to send history: {}", e);
+            }
+        }));
+    }
+
+    /// Sends an update of the run record to nexus, once the run is initialized.
+    /// Fields left empty by `update` are not changed.
+    fn update_run<F>(&self, update: F) -> PyResult<()>
+    where
+        F: FnOnce(&mut wandb_internal::RunRecord),
+    {
+        if self.settings.proto.run_id.is_none() || self.settings.mode_kind() == Mode::Disabled {
+            return Ok(());
+        }
+        let mut run = wandb_internal::RunRecord {
+            run_id: self.id(),
+            project: self.settings.proto.project.clone().unwrap_or_default(),
+            entity: self.settings.proto.entity.clone().unwrap_or_default(),
+            info: Some(wandb_internal::RecordInfo {
+                stream_id: self.id(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        update(&mut run);
+        self.publish(wandb_internal::record::RecordType::Run(run))?;
+        Ok(())
+    }
+
+    /// Asks nexus for the summary of the run. Offline, there is none.
+    fn get_summary(&mut self) -> Option<Vec<wandb_internal::SummaryItem>> {
+        let mut record = wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::Request(
+                wandb_internal::Request {
+                    request_type: Some(wandb_internal::request::RequestType::GetSummary(
+                        wandb_internal::GetSummaryRequest {
+                            info: Some(wandb_internal::RequestInfo {
+                                stream_id: self.id(),
+                            }),
+                        },
+                    )),
+                },
+            )),
+            info: Some(wandb_internal::RecordInfo {
+                stream_id: self.id(),
+                ..Default::default()
+            }),
+            ..Default::default()
         };
-        let message = wandb_internal::ServerRequest {
+
+        let summary = self.interface.send_and_recv_message(&mut record);
+
+        match summary.and_then(|result| result.result_type) {
+            Some(wandb_internal::result::ResultType::Response(response)) => {
+                match response.response_type {
+                    Some(wandb_internal::response::ResponseType::GetSummaryResponse(
+                        summary_response,
+                    )) => Some(summary_response.item),
+                    _ => {
+                        tracing::warn!("Unexpected response type");
+                        None
+                    }
+                }
+            }
+            Some(_) => {
+                tracing::warn!("Unexpected result type");
+                None
+            }
+            // no summary without nexus
+            None if self.settings.offline() => Some(vec![]),
+            None => {
+                tracing::warn!("No result type, me is puzzled");
+                None
+            }
+        }
+    }
+
+    fn keepalive_request(&self) -> wandb_internal::ServerRequest {
+        wandb_internal::ServerRequest {
             server_request_type: Some(
-                wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+                wandb_internal::server_request::ServerRequestType::RecordPublish(
+                    wandb_internal::Record {
+                        record_type: Some(wandb_internal::record::RecordType::Request(
+                            wandb_internal::Request {
+                                request_type: Some(
+                                    wandb_internal::request::RequestType::Keepalive(
+                                        wandb_internal::KeepaliveRequest {
+                                            info: Some(wandb_internal::RequestInfo {
+                                                stream_id: self.id(),
+                                            }),
+                                        },
+                                    ),
+                                ),
+                            },
+                        )),
+                        info: Some(wandb_internal::RecordInfo {
+                            stream_id: self.id(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ),
             ),
+        }
+    }
+
+    /// The messages that set this run up on a fresh connection to nexus, which
+    /// has forgotten about it if it restarted. The run exists by now, so the
+    /// replayed init always resumes it.
+    fn handshake(
+        &self,
+        mut run_record: wandb_internal::Record,
+        mut run_start: wandb_internal::Record,
+    ) -> Vec<wandb_internal::ServerRequest> {
+        let mut settings = self.settings.proto.clone();
+        settings.resume = Some("allow".to_string());
+        let inform_init = wandb_internal::server_request::ServerRequestType::InformInit(
+            wandb_internal::ServerInformInitRequest {
+                settings: Some(settings),
+                info: Some(wandb_internal::RecordInfo {
+                    stream_id: self.id(),
+                    ..Default::default()
+                }),
+            },
+        );
+
+        if let Some(wandb_internal::record::RecordType::Run(run)) = &mut run_record.record_type {
+            run.project = self.settings.proto.project.clone().unwrap_or_default();
+            run.entity = self.settings.proto.entity.clone().unwrap_or_default();
+        }
+        // nobody is waiting for the results this time
+        run_record.control = None;
+        run_start.control = Some(wandb_internal::Control {
+            local: true,
+            ..Default::default()
+        });
+
+        vec![
+            inform_init,
+            wandb_internal::server_request::ServerRequestType::RecordPublish(run_record),
+            wandb_internal::server_request::ServerRequestType::RecordPublish(run_start),
+        ]
+        .into_iter()
+        .map(|request| wandb_internal::ServerRequest {
+            server_request_type: Some(request),
+        })
+        .collect()
+    }
+
+    /// Whether logging with `step` and `commit` completes a step of history,
+    /// which is what the rate limit counts, as opposed to adding values to
+    /// the current one with `commit=False`.
+    fn adds_step(&self, step: Option<i64>, commit: Option<bool>) -> bool {
+        commit.unwrap_or(step.is_none())
+            || step.is_some_and(|step| step > self.history.lock().unwrap().step)
+    }
+
+    /// How long a log with `step` and `commit` has to wait for the rate limit
+    /// under the block policy, taking its turn.
+    fn rate_limit_wait(&mut self, step: Option<i64>, commit: Option<bool>) -> Duration {
+        if self.rate_limit_policy != rate_limit::Policy::Block
+            || self.settings.mode_kind() == Mode::Disabled
+            || !self.adds_step(step, commit)
+        {
+            return Duration::ZERO;
+        }
+        self.rate_limiter
+            .as_mut()
+            .map_or(Duration::ZERO, RateLimiter::reserve)
+    }
+
+    /// Adds the values to the history, returning what is to be sent of it
+    /// and the keys of non-finite values left out.
+    fn add_history(
+        &mut self,
+        mut data: HashMap<String, Value>,
+        step: Option<i64>,
+        commit: Option<bool>,
+        timestamp: Option<f64>,
+    ) -> PyResult<(Option<Pending>, Vec<String>)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let timestamp = match timestamp {
+            Some(timestamp)
+                if !timestamp.is_finite()
+                    || timestamp < 0.0
+                    || timestamp > now + MAX_TIMESTAMP_AHEAD.as_secs_f64() =>
+            {
+                return Err(PyValueError::new_err(format!(
+                    "Invalid timestamp {}, expected seconds since the epoch, at most a day from now",
+                    timestamp
+                )));
+            }
+            Some(timestamp) => timestamp,
+            None => now,
         };
+        if self.settings.mode_kind() == Mode::Disabled {
+            return Ok((None, Vec::new()));
+        }
+        // blocking is left to `log`, which waits without holding the run
+        if self.rate_limit_policy == rate_limit::Policy::Drop && self.adds_step(step, commit) {
+            if let Some(limiter) = &mut self.rate_limiter {
+                if !limiter.try_acquire() {
+                    if !self.warned_rate_limit {
+                        self.warned_rate_limit = true;
+                        tracing::warn!(
+                            "Logging faster than {} times per second, dropping history",
+                            self.settings.history_rate_limit
+                        );
+                    }
+                    return Ok((None, Vec::new()));
+                }
+            }
+        }
+        // only once the log is let through, so that a dropped one leaves the step as is
+        if let Some(step) = step {
+            self.history
+                .lock()
+                .unwrap()
+                .set_step(step)
+                .map_err(PyValueError::new_err)?;
+        }
+        let commit = commit.unwrap_or(step.is_none());
+        tracing::debug!("Logging to run {}", self.id());
+
+        // TODO: make it work with steps
+        // let history_record = wandb_internal::HistoryRecord {
+        //     item: data
+        //         .iter()
+        //         .map(|(k, v)| wandb_internal::HistoryItem {
+        //             key: k.clone(),
+        //             value_json: v.to_string(),
+        //             ..Default::default()
+        //         })
+        //         .collect(),
+        //     ..Default::default()
+        // };
+
+        // let record = wandb_internal::Record {
+        //     record_type: Some(wandb_internal::record::RecordType::History(history_record)),
+        //     info: Some(wandb_internal::RecordInfo {
+        //         stream_id: self.id.clone(),
+        //         ..Default::default()
+        //     }),
+        //     ..Default::default()
+        // };
+
+        // let message = wandb_internal::ServerRequest {
+        //     server_request_type: Some(
+        //         wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+        //     ),
+        // };
+
+        // self.interface.conn.send_message(&message).unwrap();
+
+        let mut nonfinite = Vec::new();
+        if self.settings.raise_on_nonfinite {
+            data.retain(|key, value| {
+                let keep = !value.is_nonfinite();
+                if !keep {
+                    nonfinite.push(key.clone());
+                }
+                keep
+            });
+        }
+
+        let mut items = Vec::new();
+
+        for (k, v) in data {
+            let mut item = wandb_internal::HistoryItem {
+                key: k.clone(),
+                ..Default::default()
+            };
+            match v {
+                Value::Ndarray(arr) => {
+                    // TODO: convert to image if shape is valid, otherwise just serialize
+                    let shape = arr.shape();
+                    if shape.len() == 3 {
+                        let value_json = ndarray_to_image(arr, &self.settings.files_dir())?;
+                        item.value_json = serde_json::to_string(&value_json).unwrap();
+                        // TODO: tell nexus to upload the image
+                        self.save_files(&value_json["path"])?;
+                    } else {
+                        item.value_json = serde_json::to_string(&Value::Ndarray(arr)).unwrap();
+                    }
+                }
+                Value::Image(image) => {
+                    let (path, value_json) = image.stage(&self.settings.files_dir())?;
+                    item.value_json = value_json.to_string();
+                    self.save_files(&path)?;
+                }
+                _ => {
+                    item.value_json = serde_json::to_string(&v).unwrap();
+                }
+            }
+            items.push(item);
+        }
+
+        // nexus derives the runtime of the step from it
+        items.push(wandb_internal::HistoryItem {
+            key: "_timestamp".to_string(),
+            value_json: serde_json::to_string(&timestamp).unwrap(),
+            ..Default::default()
+        });
+        let mut history = self.history.lock().unwrap();
+        history.add(items);
+        if commit {
+            history.commit();
+        }
+
+        if !history.should_flush() {
+            return Ok((None, nonfinite));
+        }
+        // queued before letting go of the history, so that steps go out in order
+        let messages = history_messages(&mut history, &self.id(), false);
+        let pending = self.interface.queue_messages(&messages)?;
+        Ok((Some(pending), nonfinite))
+    }
+
+    /// Picks up the step and summary of the resumed run, as nexus found them.
+    fn restore(&mut self, run: &wandb_internal::RunRecord) {
+        let summary = run
+            .summary
+            .as_ref()
+            .map_or(&[][..], |summary| &summary.update[..]);
+        if run.starting_step == 0 && summary.is_empty() {
+            tracing::warn!(
+                "Nothing to resume for run {}, starting it fresh",
+                run.run_id
+            );
+            return;
+        }
+        if let Err(e) = self.history.lock().unwrap().set_step(run.starting_step) {
+            tracing::warn!("Cannot resume run {} at its step: {}", run.run_id, e);
+        }
+        for item in summary {
+            if item.key == "_wandb" {
+                continue;
+            }
+            match serde_json::from_str(&item.value_json) {
+                Ok(value) => {
+                    self.summary.insert(item.key.clone(), value);
+                }
+                Err(e) => tracing::warn!("Invalid summary value of {:?}: {}", item.key, e),
+            }
+        }
+        tracing::info!("Resuming run {} at step {}", run.run_id, run.starting_step);
+    }
+
+    fn send_history(&mut self, include_current: bool) -> error::Result<()> {
+        let mut history = self.history.lock().unwrap();
+        if history.is_empty() {
+            return Ok(());
+        }
+        let messages = history_messages(&mut history, &self.id(), include_current);
+        self.interface.send_messages(&messages)
+    }
 
-        self.interface.conn.send_message(&message).unwrap();
+    fn publish(&self, record_type: wandb_internal::record::RecordType) -> error::Result<()> {
+        let record = wandb_internal::Record {
+            record_type: Some(record_type),
+            info: Some(wandb_internal::RecordInfo {
+                stream_id: self.id(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let message = wandb_internal::ServerRequest {
+            server_request_type: Some(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+            ),
+        };
+
+        self.interface.send_message(&message)
+    }
+
+    /// Uploads `files` as a new version of artifact `name`, returning its id.
+    fn upload_artifact(
+        &mut self,
+        name: &str,
+        r#type: &str,
+        files: &BTreeMap<String, PathBuf>,
+    ) -> PyResult<String> {
+        let artifacts_dir = Path::new(self.settings.proto.wandb_dir.as_deref().unwrap_or(".wandb"))
+            .join("artifacts");
+        let cache = artifacts_dir
+            .join(self.settings.proto.project.as_deref().unwrap_or_default())
+            .join(format!("{}.json", name));
+        let mut entries = artifact::manifest_entries(files, &PreviousVersion::load(&cache))?;
+
+        // copies, so that files can't change while they are uploaded; nexus
+        // removes them afterwards
+        let staging_dir = artifacts_dir.join("staging");
+        std::fs::create_dir_all(&staging_dir)?;
+        for entry in entries
+            .iter_mut()
+            .filter(|entry| !entry.local_path.is_empty())
+        {
+            let staged = staging_dir.join(generate_id(16));
+            std::fs::copy(&entry.local_path, &staged)?;
+            entry.local_path = staged.to_string_lossy().to_string();
+        }
+
+        let record = wandb_internal::ArtifactRecord {
+            run_id: self.id(),
+            project: self.settings.proto.project.clone().unwrap_or_default(),
+            entity: self.settings.proto.entity.clone().unwrap_or_default(),
+            r#type: r#type.to_string(),
+            name: name.to_string(),
+            digest: artifact::manifest_digest(&entries),
+            aliases: vec!["latest".to_string()],
+            manifest: Some(artifact::manifest(entries.clone())),
+            client_id: generate_id(128),
+            sequence_client_id: generate_id(128),
+            ..Default::default()
+        };
+        let mut request = wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::Request(
+                wandb_internal::Request {
+                    request_type: Some(wandb_internal::request::RequestType::LogArtifact(
+                        wandb_internal::LogArtifactRequest {
+                            artifact: Some(record),
+                            history_step: self.history.lock().unwrap().step,
+                            staging_dir: staging_dir.to_string_lossy().to_string(),
+                            ..Default::default()
+                        },
+                    )),
+                },
+            )),
+            info: Some(wandb_internal::RecordInfo {
+                stream_id: self.id(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let response = self.interface.send_and_recv_message(&mut request);
+        let artifact_id = match response.and_then(|result| result.result_type) {
+            Some(wandb_internal::result::ResultType::Response(wandb_internal::Response {
+                response_type:
+                    Some(wandb_internal::response::ResponseType::LogArtifactResponse(response)),
+            })) => {
+                if !response.error_message.is_empty() {
+                    return Err(PyRuntimeError::new_err(format!(
+                        "Failed to log artifact {}: {}",
+                        name, response.error_message
+                    )));
+                }
+                response.artifact_id
+            }
+            _ => {
+                return Err(
+                    Error::Protocol(format!("No response logging artifact {}", name)).into(),
+                )
+            }
+        };
+
+        if let Err(e) = PreviousVersion::save(&cache, &entries, &artifact_id) {
+            tracing::warn!("Failed to remember the files of artifact {}: {}", name, e);
+        }
+        Ok(artifact_id)
+    }
+
+    /// Tells nexus about the environment of the run, for its overview page.
+    pub fn send_metadata(&self, metadata: &Metadata) -> error::Result<()> {
+        self.publish(wandb_internal::record::RecordType::Request(
+            wandb_internal::Request {
+                request_type: Some(wandb_internal::request::RequestType::Metadata(
+                    metadata.request(),
+                )),
+            },
+        ))?;
+        if let Some(git) = &metadata.git {
+            self.publish(wandb_internal::record::RecordType::Config(
+                wandb_internal::ConfigRecord {
+                    update: vec![git.config_item()],
+                    ..Default::default()
+                },
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn save_files(&self, path: &str) -> error::Result<()> {
+        self.publish(wandb_internal::record::RecordType::Files(
+            files::files_record(vec![path.to_string()], PolicyType::Now),
+        ))
+    }
+
+    /// Polls the files saved with the `live` policy, uploading them again when modified.
+    fn start_file_watcher(&mut self) {
+        let live_files = self.live_files.clone();
+        let publisher = self.interface.publisher();
+        let stream_id = self.id();
+        self.file_watcher = Some(Periodic::start(LIVE_FILES_POLL_INTERVAL, move || {
+            let changed = live_files.changed();
+            if changed.is_empty() {
+                return;
+            }
+            let record = wandb_internal::Record {
+                record_type: Some(wandb_internal::record::RecordType::Files(
+                    files::files_record(changed, PolicyType::Now),
+                )),
+                info: Some(wandb_internal::RecordInfo {
+                    stream_id: stream_id.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            if let Err(e) = publisher.publish(record) {
+                tracing::debug!("Failed to send modified files: {}", e);
+            }
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync;
+    use crate::testing::{self, MockNexus, TempCwd};
+    use crate::wandb_internal::record::RecordType;
+
+    fn run_in_mode(mode: &str) -> Run {
+        let settings = Settings::new(None, Some(mode.to_string()), None, None, None);
+        Run::new(settings, Interface::detached())
+    }
+
+    fn sync_file_records(run: &Run) -> Vec<wandb_internal::Record> {
+        let path = run.settings.proto.sync_file.clone().unwrap();
+        sync::read_log(Path::new(&path), run.settings.max_frame_size).unwrap()
+    }
+
+    #[test]
+    fn offline_run_writes_sync_file() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("offline1".to_string())).unwrap();
+        assert_eq!(run.settings.proto.offline, Some(true));
+        assert_eq!(run.settings.proto.run_mode.as_deref(), Some("offline-run"));
+        run.finish(None, None).unwrap();
+
+        let records = sync_file_records(&run);
+        assert!(matches!(
+            records.first().and_then(|r| r.record_type.as_ref()),
+            Some(RecordType::Run(run)) if run.run_id == "offline1"
+        ));
+        assert!(records
+            .iter()
+            .any(|r| matches!(r.record_type, Some(RecordType::Exit(_)))));
+    }
+
+    #[test]
+    fn disabled_run_writes_nothing() {
+        let cwd = TempCwd::new();
+        let mut run = run_in_mode("disabled");
+        run.init(Some("disabled1".to_string())).unwrap();
+        run.finish(None, None).unwrap();
+        assert_eq!(run.settings.proto.sync_dir, None);
+        assert!(!cwd.path().join(".wandb").exists());
+    }
+
+    #[test]
+    fn finish_records_exit_code() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("crashed1".to_string())).unwrap();
+        run.finish(Some(1), None).unwrap();
+
+        let exits: Vec<_> = sync_file_records(&run)
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Exit(exit)) => Some(exit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(exits.len(), 1);
+        assert_eq!(exits[0].exit_code, 1);
+        assert_eq!(exits[0].info.as_ref().unwrap().stream_id, "crashed1");
+    }
+
+    #[test]
+    fn finishing_again_does_nothing() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("twice1".to_string())).unwrap();
+        run.finish(Some(1), None).unwrap();
+        let written = sync_file_records(&run).len();
+        run.finish(Some(2), None).unwrap();
+        assert_eq!(sync_file_records(&run).len(), written);
+    }
+
+    #[test]
+    fn exit_code_defaults_to_zero() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("finished1".to_string())).unwrap();
+        run.finish(None, None).unwrap();
+        assert!(sync_file_records(&run).iter().any(|record| matches!(
+            &record.record_type,
+            Some(RecordType::Exit(exit)) if exit.exit_code == 0
+        )));
+    }
+
+    fn scalars(values: &[(&str, f64)]) -> HashMap<String, Value<'static>> {
+        values
+            .iter()
+            .map(|&(key, value)| (key.to_string(), Value::Float(value)))
+            .collect()
+    }
+
+    fn history_steps(records: &[wandb_internal::Record]) -> Vec<i64> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Request(wandb_internal::Request {
+                    request_type:
+                        Some(wandb_internal::request::RequestType::PartialHistory(history)),
+                })) => history.step.as_ref().map(|step| step.num),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_history_writes() {
+        const STEPS: i64 = 1000;
+        let _cwd = TempCwd::new();
+        let mut settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+        settings.history_rate_limit = 0.0;
+        let mut run = Run::new(settings, Interface::detached());
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::from_secs(3600));
+        run.init(Some("batched1".to_string())).unwrap();
+
+        let mut writes = 0;
+        for i in 0..STEPS {
+            let (pending, _) = run
+                .add_history(scalars(&[("loss", i as f64)]), None, None, None)
+                .unwrap();
+            if let Some(pending) = pending {
+                pending.send().unwrap();
+                writes += 1;
+            }
+        }
+        assert_eq!(writes, 0);
+        assert!(history_steps(&sync_file_records(&run)).is_empty());
+
+        // a single write for all of them
+        run.flush().unwrap();
+        assert_eq!(
+            history_steps(&sync_file_records(&run)),
+            (0..STEPS).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sends_the_steps_left_by_an_idle_loop() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |_| {});
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::from_millis(50));
+        run.init(Some("idle1".to_string())).unwrap();
+        for i in 0..3 {
+            let (pending, _) = run
+                .add_history(scalars(&[("loss", i as f64)]), None, None, None)
+                .unwrap();
+            if let Some(pending) = pending {
+                pending.send().unwrap();
+            }
+        }
+
+        // no other log, flush or finish comes to send them
+        let mut steps = Vec::new();
+        for _ in 0..100 {
+            steps = history_steps(&nexus.records());
+            if steps.len() == 3 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(steps, [0, 1, 2]);
+        // and they are in the log, should the process die now
+        let sync_dir = run.settings.proto.sync_dir.clone().unwrap();
+        let path = transaction_log::online_path(&sync_dir, "idle1");
+        let logged = sync::read_log(Path::new(&path), run.settings.max_frame_size).unwrap();
+        assert_eq!(history_steps(&logged), [0, 1, 2]);
+
+        run.finish(None, Some(5.0)).unwrap();
+        assert_eq!(history_steps(&nexus.records()), [0, 1, 2]);
+    }
+
+    #[test]
+    fn finish_flushes_history() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::from_secs(3600));
+        run.init(Some("flushed1".to_string())).unwrap();
+        run.add_history(scalars(&[("loss", 1.0)]), None, Some(false), None)
+            .unwrap();
+        run.finish(None, None).unwrap();
+        assert_eq!(history_steps(&sync_file_records(&run)), vec![0]);
+    }
+
+    fn metric_records(records: &[wandb_internal::Record]) -> Vec<&wandb_internal::MetricRecord> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Metric(metric)) => Some(metric),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn redefining_a_metric_updates_it() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("metrics1".to_string())).unwrap();
+        run.define_metric(
+            "loss".to_string(),
+            Some("epoch".to_string()),
+            Some("min".to_string()),
+        )
+        .unwrap();
+        run.define_metric("loss".to_string(), None, Some("max".to_string()))
+            .unwrap();
+        assert_eq!(run.metrics.len(), 1);
+
+        let records = sync_file_records(&run);
+        let metrics = metric_records(&records);
+        assert_eq!(metrics.len(), 2);
+        let updated = metrics[1];
+        assert_eq!(updated.name, "loss");
+        // the step metric of the first definition is kept
+        assert_eq!(updated.step_metric, "epoch");
+        assert!(updated.options.as_ref().unwrap().step_sync);
+        let summary = updated.summary.as_ref().unwrap();
+        assert!(summary.max && !summary.min);
+    }
+
+    #[test]
+    fn rejects_unknown_summaries() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("metrics2".to_string())).unwrap();
+        assert!(run
+            .define_metric("loss".to_string(), None, Some("median".to_string()))
+            .is_err());
+        assert!(run.metrics.is_empty());
+        assert!(metric_records(&sync_file_records(&run)).is_empty());
+    }
+
+    fn config_records(records: &[wandb_internal::Record]) -> Vec<&wandb_internal::ConfigRecord> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Config(config)) => Some(config),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Converts the Python expression `value` for the config key `key`.
+    fn config_value(key: &str, value: &str) -> PyResult<ConfigValue> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            py.run(
+                "class Scalar:\n    \
+                     __module__ = 'numpy'\n    \
+                     def tolist(self):\n        \
+                         return [1, 2.5]\n",
+                Some(globals),
+                None,
+            )
+            .unwrap();
+            let value = py.eval(value, Some(globals), None).unwrap();
+            ConfigValue::from_py(key, value)
+        })
+    }
+
+    #[test]
+    fn converts_config_values() {
+        use ConfigValue::*;
+        assert_eq!(config_value("a", "None").unwrap(), None);
+        // not taken as an int
+        assert_eq!(config_value("a", "True").unwrap(), Bool(true));
+        assert_eq!(config_value("a", "3").unwrap(), Int(3));
+        assert_eq!(config_value("a", "0.5").unwrap(), Float(0.5));
+        assert_eq!(
+            config_value("a", "'adam'").unwrap(),
+            Str("adam".to_string())
+        );
+        assert_eq!(
+            config_value("a", "(1, [2, 'x'])").unwrap(),
+            List(vec![Int(1), List(vec![Int(2), Str("x".to_string())])])
+        );
+        assert_eq!(
+            config_value("a", "{'lr': 0.1, 'betas': {'b1': 0.9}}").unwrap(),
+            Map(BTreeMap::from([
+                ("lr".to_string(), Float(0.1)),
+                (
+                    "betas".to_string(),
+                    Map(BTreeMap::from([("b1".to_string(), Float(0.9))]))
+                ),
+            ]))
+        );
+        // numpy values are taken as the Python values they stand for
+        assert_eq!(
+            config_value("a", "Scalar()").unwrap(),
+            List(vec![Int(1), Float(2.5)])
+        );
+    }
+
+    #[test]
+    fn names_the_config_value_that_is_unsupported() {
+        pyo3::prepare_freethreaded_python();
+        for (value, expected) in [
+            (
+                "{'layers': [{'units': 1}, {'units': object()}]}",
+                "model.layers[1].units has the unsupported type object",
+            ),
+            ("{1: 'one'}", "model has the key 1"),
+            ("2 ** 64", "model is too large"),
+            ("{'x': {1, 2}}", "model.x has the unsupported type set"),
+        ] {
+            let e = config_value("model", value).unwrap_err();
+            Python::with_gil(|py| {
+                assert!(e.is_instance_of::<PyTypeError>(py), "{}", value);
+                let message = e.value(py).to_string();
+                assert!(message.contains(expected), "{}", message);
+            });
+        }
+    }
+
+    #[test]
+    fn config_values_become_json() {
+        use ConfigValue::*;
+        let value = Map(BTreeMap::from([
+            ("none".to_string(), None),
+            ("flags".to_string(), List(vec![Bool(false), Int(-1)])),
+            ("lr".to_string(), Float(f64::NAN)),
+            ("name".to_string(), Str("adam".to_string())),
+        ]));
+        assert_eq!(
+            value.to_json(),
+            serde_json::json!({
+                "none": null,
+                "flags": [false, -1],
+                "lr": "NaN",
+                "name": "adam",
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_config_values_set_nothing() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("config2".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let data = PyDict::new(py);
+            data.set_item("epochs", 10).unwrap();
+            data.set_item("callback", py.eval("print", None, None).unwrap())
+                .unwrap();
+            let e = run.update_config(data).unwrap_err();
+            assert!(e.is_instance_of::<PyTypeError>(py));
+            assert!(e.value(py).to_string().contains("callback"), "{}", e);
+            assert!(run.get_config(py, "epochs").is_none());
+
+            let data = PyDict::new(py);
+            data.set_item(3, 10).unwrap();
+            let e = run.update_config(data).unwrap_err();
+            assert!(e.is_instance_of::<PyTypeError>(py));
+
+            let data = PyDict::new(py);
+            data.set_item("layers", (32, 64)).unwrap();
+            run.update_config(data).unwrap();
+            let layers = run.get_config(py, "layers").unwrap();
+            assert_eq!(layers.extract::<Vec<i64>>(py).unwrap(), [32, 64]);
+        });
+
+        let records = sync_file_records(&run);
+        let configs = config_records(&records);
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].update[0].value_json, "[32,64]");
+    }
+
+    #[test]
+    fn config_collision_sets_nothing() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("config1".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let data = PyDict::new(py);
+            data.set_item("epochs", 10).unwrap();
+            data.set_item("optimizer", "adam").unwrap();
+            data.set_item("optimizer.lr", 0.1).unwrap();
+            let e = run.update_config(data).unwrap_err();
+            assert!(e.is_instance_of::<PyValueError>(py));
+            assert!(run.get_config(py, "epochs").is_none());
+            assert!(run.get_config(py, "optimizer").is_none());
+
+            let data = PyDict::new(py);
+            data.set_item("optimizer.lr", 0.1).unwrap();
+            run.update_config(data).unwrap();
+            let lr = run.get_config(py, "optimizer.lr").unwrap();
+            assert_eq!(lr.extract::<f64>(py).unwrap(), 0.1);
+        });
+
+        let records = sync_file_records(&run);
+        let configs = config_records(&records);
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].update[0].nested_key, vec!["optimizer", "lr"]);
+    }
+
+    #[test]
+    fn records_the_git_state_in_the_config() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("metadata2".to_string())).unwrap();
+        run.send_metadata(&Metadata {
+            git: Some(crate::metadata::Git {
+                commit: "abc".to_string(),
+                branch: Some("main".to_string()),
+                dirty: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        // the state isn't part of the run's own config
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| assert!(run.get_config(py, "_wandb").is_none()));
+
+        let records = sync_file_records(&run);
+        let configs = config_records(&records);
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].update[0].nested_key, vec!["_wandb", "git"]);
+        assert_eq!(
+            configs[0].update[0].value_json,
+            r#"{"branch":"main","dirty":true}"#
+        );
+        run.finish(None, None).unwrap();
+    }
+
+    fn online_run(nexus: &MockNexus, configure: impl FnOnce(&mut Settings)) -> Run {
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.heartbeat_interval_secs = 0.0;
+        configure(&mut settings);
+        Run::new(settings, nexus.interface())
+    }
+
+    /// Answers like nexus would if the run existed at `starting_step` with a
+    /// summary, or else didn't exist, failing to resume it with "must".
+    fn resuming_nexus(exists: bool) -> MockNexus {
+        MockNexus::start(move |record| match &record.record_type {
+            Some(RecordType::Run(run)) if exists => {
+                let mut run = run.clone();
+                run.starting_step = 5;
+                run.summary = Some(wandb_internal::SummaryRecord {
+                    update: vec![wandb_internal::SummaryItem {
+                        key: "loss".to_string(),
+                        value_json: "0.5".to_string(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                });
+                Some(wandb_internal::Result {
+                    result_type: Some(wandb_internal::result::ResultType::RunResult(
+                        wandb_internal::RunUpdateResult {
+                            run: Some(run),
+                            error: None,
+                        },
+                    )),
+                    ..Default::default()
+                })
+            }
+            Some(RecordType::Run(_)) => Some(wandb_internal::Result {
+                result_type: Some(wandb_internal::result::ResultType::RunResult(
+                    wandb_internal::RunUpdateResult {
+                        run: None,
+                        error: Some(wandb_internal::ErrorInfo {
+                            message: "run resume1 does not exist".to_string(),
+                            ..Default::default()
+                        }),
+                    },
+                )),
+                ..Default::default()
+            }),
+            _ => Some(testing::echo(record)),
+        })
+    }
+
+    fn resume_policy_sent(nexus: &MockNexus) -> Option<String> {
+        nexus
+            .received()
+            .into_iter()
+            .find_map(|request| match request.server_request_type {
+                Some(wandb_internal::server_request::ServerRequestType::InformInit(init)) => {
+                    init.settings.and_then(|settings| settings.resume)
+                }
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn must_resume_fails_for_a_missing_run() {
+        let _cwd = TempCwd::new();
+        let nexus = resuming_nexus(false);
+        let mut run = online_run(&nexus, |settings| {
+            settings.set_resume(Some("must".to_string())).unwrap()
+        });
+        let e = run.init(Some("resume1".to_string())).unwrap_err();
+        assert!(e.to_string().contains("does not exist"), "{}", e);
+        assert_eq!(resume_policy_sent(&nexus).as_deref(), Some("must"));
+    }
+
+    #[test]
+    fn resumes_an_existing_run() {
+        for policy in ["allow", "must"] {
+            let _cwd = TempCwd::new();
+            let nexus = resuming_nexus(true);
+            let mut run = online_run(&nexus, |settings| {
+                settings.set_resume(Some(policy.to_string())).unwrap()
+            });
+            run.init(Some("resume2".to_string())).unwrap();
+            assert_eq!(run.history.lock().unwrap().step, 5, "{}", policy);
+            assert_eq!(run.summary.get("loss"), Some(&serde_json::json!(0.5)));
+            assert_eq!(resume_policy_sent(&nexus).as_deref(), Some(policy));
+            run.finish(None, Some(5.0)).unwrap();
+        }
+    }
+
+    #[test]
+    fn allow_resume_creates_a_missing_run() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |settings| {
+            settings.set_resume(Some("allow".to_string())).unwrap()
+        });
+        run.init(Some("resume3".to_string())).unwrap();
+        assert_eq!(run.history.lock().unwrap().step, 0);
+        assert!(run.summary.is_empty());
+        run.finish(None, Some(5.0)).unwrap();
+    }
+
+    #[test]
+    fn restores_what_nexus_found() {
+        let mut run = run_in_mode("offline");
+        let item = |key: &str, value_json: &str| wandb_internal::SummaryItem {
+            key: key.to_string(),
+            value_json: value_json.to_string(),
+            ..Default::default()
+        };
+        run.restore(&wandb_internal::RunRecord {
+            run_id: "resume6".to_string(),
+            starting_step: 3,
+            summary: Some(wandb_internal::SummaryRecord {
+                update: vec![
+                    item("_wandb", "{\"runtime\": 10}"),
+                    item("acc", "{\"max\": 0.8}"),
+                    item("broken", "{"),
+                ],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(run.history.lock().unwrap().step, 3);
+        // internal and invalid values are left out
+        assert_eq!(
+            serde_json::Value::Object(run.summary.clone()),
+            serde_json::json!({"acc": {"max": 0.8}})
+        );
+    }
+
+    #[test]
+    fn restores_nothing_without_a_previous_state() {
+        let mut run = run_in_mode("offline");
+        run.history.lock().unwrap().set_step(2).unwrap();
+        run.restore(&wandb_internal::RunRecord {
+            run_id: "resume7".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(run.history.lock().unwrap().step, 2);
+        assert!(run.summary.is_empty());
+    }
+
+    #[test]
+    fn never_resume_starts_fresh() {
+        let _cwd = TempCwd::new();
+        let nexus = resuming_nexus(true);
+        let mut run = online_run(&nexus, |settings| {
+            settings.set_resume(Some("never".to_string())).unwrap()
+        });
+        run.init(Some("resume4".to_string())).unwrap();
+        assert_eq!(run.history.lock().unwrap().step, 0);
+        assert!(run.summary.is_empty());
+        run.finish(None, Some(5.0)).unwrap();
+    }
+
+    #[test]
+    fn confirmed_runs_get_a_url() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::start(|record| match &record.record_type {
+            Some(RecordType::Run(run)) => {
+                let mut run = run.clone();
+                // as resolved by nexus
+                run.entity = "entity".to_string();
+                run.project = "project".to_string();
+                run.display_name = "sunny-dawn-1".to_string();
+                Some(testing::echo(&wandb_internal::Record {
+                    record_type: Some(RecordType::Run(run)),
+                    ..Default::default()
+                }))
+            }
+            _ => Some(testing::echo(record)),
+        });
+        let mut run = online_run(&nexus, |settings| {
+            settings.proto.base_url = Some("https://wandb.example.com".to_string())
+        });
+        run.init(Some("url1".to_string())).unwrap();
+        assert_eq!(
+            run.settings.proto.run_url.as_deref(),
+            Some("https://wandb.example.com/entity/project/runs/url1")
+        );
+        assert_eq!(run.settings.proto.run_name.as_deref(), Some("sunny-dawn-1"));
+        run.finish(None, Some(5.0)).unwrap();
+    }
+
+    #[test]
+    fn offline_runs_have_no_url() {
+        for mode in ["offline", "disabled"] {
+            let _cwd = TempCwd::new();
+            let mut run = run_in_mode(mode);
+            run.init(Some("url2".to_string())).unwrap();
+            assert_eq!(run.settings.proto.run_url, None, "{}", mode);
+            run.finish(None, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn logs_again_after_reconnecting() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |_| {});
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::ZERO);
+        run.init(Some("reconnect2".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = Py::new(py, run).unwrap();
+            Run::log(
+                run.borrow_mut(py),
+                scalars(&[("loss", 1.0)]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            run.borrow(py).interface.break_connection();
+            Run::log(
+                run.borrow_mut(py),
+                scalars(&[("loss", 2.0)]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            // which may be written by the history writer until finishing
+            run.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+            assert_eq!(run.borrow(py).interface.stats().reconnects, 1);
+        });
+        let steps = history_steps(&nexus.records());
+        assert_eq!(steps, vec![0, 1]);
+    }
+
+    fn keepalives(nexus: &MockNexus) -> usize {
+        nexus
+            .records()
+            .iter()
+            .filter(|record| {
+                matches!(
+                    &record.record_type,
+                    Some(RecordType::Request(wandb_internal::Request {
+                        request_type: Some(wandb_internal::request::RequestType::Keepalive(_)),
+                    }))
+                )
+            })
+            .count()
+    }
+
+    #[test]
+    fn sends_heartbeats_while_idle() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |settings| settings.heartbeat_interval_secs = 0.02);
+        run.init(Some("heartbeat1".to_string())).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(keepalives(&nexus) >= 1);
+
+        run.finish(None, Some(5.0)).unwrap();
+        let sent = keepalives(&nexus);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(keepalives(&nexus), sent);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn samples_system_metrics() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |settings| {
+            settings.sample_system_metrics = true;
+            settings.proto.stats_sample_rate_seconds = Some(0.02);
+        });
+        run.init(Some("stats2".to_string())).unwrap();
+        // nexus must not sample as well
+        assert_eq!(run.settings.proto.disable_stats, Some(true));
+        std::thread::sleep(Duration::from_millis(200));
+        run.finish(None, Some(5.0)).unwrap();
+
+        let keys: Vec<String> = nexus
+            .records()
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Stats(stats)) => Some(stats.item),
+                _ => None,
+            })
+            .flatten()
+            .map(|item| item.key)
+            .collect();
+        assert!(!keys.is_empty());
+        #[cfg(target_os = "linux")]
+        assert!(
+            keys.iter().any(|key| key == "proc.memory.rssMB"),
+            "{:?}",
+            keys
+        );
+    }
+
+    fn exit_codes(run: &Run) -> Vec<i32> {
+        sync_file_records(run)
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Exit(exit)) => Some(exit.exit_code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Runs `code` with `run` bound to an initialized offline run.
+    fn with_python_run(id: &str, code: &str) -> Vec<i32> {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some(id.to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = Py::new(py, run).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("run", run.clone_ref(py)).unwrap();
+            py.run(code, None, Some(locals)).unwrap();
+            let run = run.borrow(py);
+            assert!(run.finished);
+            exit_codes(&run)
+        })
+    }
+
+    #[test]
+    fn with_block_finishes_the_run() {
+        let codes = with_python_run(
+            "context1",
+            "with run as entered:\n    assert entered is run\n",
+        );
+        assert_eq!(codes, vec![0]);
+    }
+
+    #[test]
+    fn exception_in_with_block_crashes_the_run() {
+        let codes = with_python_run(
+            "context2",
+            "try:\n    with run:\n        raise ValueError('boom')\nexcept ValueError:\n    pass\nelse:\n    raise AssertionError('exception was suppressed')\n",
+        );
+        assert_eq!(codes, vec![1]);
+    }
+
+    #[test]
+    fn finish_inside_with_block_finishes_once() {
+        let codes = with_python_run("context3", "with run:\n    run.finish(2)\n");
+        assert_eq!(codes, vec![2]);
+    }
+
+    #[test]
+    fn exit_after_finish_sends_nothing() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("context4".to_string())).unwrap();
+        run.finish(None, None).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| assert!(!run.__exit__(py, None, None, None).unwrap()));
+        assert_eq!(exit_codes(&run), vec![0]);
+    }
+
+    fn run_records(records: &[wandb_internal::Record]) -> Vec<&wandb_internal::RunRecord> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Run(run)) => Some(run),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn run_record_carries_tags_group_and_job_type() {
+        let _cwd = TempCwd::new();
+        let mut settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+        settings.set_tags(vec!["a".to_string(), "b".to_string(), "a".to_string()]);
+        settings.set_group(Some("ablation".to_string()));
+        settings.set_job_type(Some("train".to_string()));
+        let mut run = Run::new(settings, Interface::detached());
+        run.init(Some("tags1".to_string())).unwrap();
+
+        let records = sync_file_records(&run);
+        let runs = run_records(&records);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].tags, vec!["a", "b"]);
+        assert_eq!(runs[0].run_group, "ablation");
+        assert_eq!(runs[0].job_type, "train");
+        run.finish(None, None).unwrap();
+    }
+
+    #[test]
+    fn setting_tags_updates_the_run() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        // nothing to update before init
+        run.set_tags(vec!["early".to_string()]).unwrap();
+        run.init(Some("tags2".to_string())).unwrap();
+        run.set_tags(vec!["c".to_string(), "c".to_string(), "d".to_string()])
+            .unwrap();
+        assert_eq!(run.tags(), vec!["c", "d"]);
+
+        let records = sync_file_records(&run);
+        let runs = run_records(&records);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].tags, vec!["early"]);
+        assert_eq!(runs[1].run_id, "tags2");
+        assert_eq!(runs[1].tags, vec!["c", "d"]);
+        run.finish(None, None).unwrap();
+    }
+
+    #[test]
+    fn renaming_updates_the_run() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("rename1".to_string())).unwrap();
+        run.set_name("  bright-star-7 ".to_string()).unwrap();
+        run.set_notes("tuned the learning rate".to_string())
+            .unwrap();
+        assert_eq!(
+            run.settings.proto.run_name.as_deref(),
+            Some("bright-star-7")
+        );
+
+        let records = sync_file_records(&run);
+        let runs = run_records(&records);
+        assert_eq!(runs.len(), 3);
+        assert_eq!(runs[1].display_name, "bright-star-7");
+        assert_eq!(runs[1].notes, "");
+        assert_eq!(runs[2].notes, "tuned the learning rate");
+        assert_eq!(runs[2].display_name, "");
+
+        run.set_notes(String::new()).unwrap();
+        assert_eq!(
+            run.settings.proto.run_notes.as_deref(),
+            Some("tuned the learning rate")
+        );
+        assert_eq!(run_records(&sync_file_records(&run)).len(), 3);
+        run.finish(None, None).unwrap();
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("rename2".to_string())).unwrap();
+        run.set_name("first".to_string()).unwrap();
+        for name in ["", "   "] {
+            assert!(run.set_name(name.to_string()).is_err());
+        }
+        assert_eq!(run.settings.proto.run_name.as_deref(), Some("first"));
+        assert_eq!(run_records(&sync_file_records(&run)).len(), 2);
+        run.finish(None, None).unwrap();
+    }
+
+    #[test]
+    fn sends_alerts_once_per_window() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("alert1".to_string())).unwrap();
+        for _ in 0..3 {
+            run.alert(
+                "NaN".to_string(),
+                "loss is NaN".to_string(),
+                Some("error".to_string()),
+                None,
+            )
+            .unwrap();
+        }
+        assert!(run
+            .alert(
+                "NaN".to_string(),
+                "".to_string(),
+                Some("fatal".to_string()),
+                None
+            )
+            .is_err());
+        assert!(run
+            .alert("NaN".to_string(), "".to_string(), None, Some(-1.0))
+            .is_err());
+
+        let alerts: Vec<_> = sync_file_records(&run)
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Alert(alert)) => Some(alert),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].level, "ERROR");
+        assert_eq!(alerts[0].wait_duration, 60);
+        run.finish(None, None).unwrap();
+    }
+
+    #[test]
+    fn logs_images_as_files() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("image1".to_string())).unwrap();
+
+        let buffer = image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 255]));
+        let mut data = std::io::Cursor::new(Vec::new());
+        buffer.write_to(&mut data, image::ImageFormat::Png).unwrap();
+        let image = Image::new(None, Some(data.into_inner()), None).unwrap();
+        let data = HashMap::from([("sample".to_string(), Value::Image(image))]);
+        run.add_history(data, None, None, None).unwrap();
+        run.finish(None, None).unwrap();
+
+        let records = sync_file_records(&run);
+        let value: serde_json::Value = records
+            .iter()
+            .find_map(|record| match &record.record_type {
+                Some(RecordType::Request(wandb_internal::Request {
+                    request_type:
+                        Some(wandb_internal::request::RequestType::PartialHistory(history)),
+                })) => history
+                    .item
+                    .iter()
+                    .find(|item| item.key == "sample")
+                    .map(|item| serde_json::from_str(&item.value_json).unwrap()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(value["_type"], "image-file");
+        let path = value["path"].as_str().unwrap();
+        assert!(Path::new(&run.settings.files_dir()).join(path).is_file());
+
+        // and the file is saved with the run
+        assert!(records.iter().any(|record| matches!(
+            &record.record_type,
+            Some(RecordType::Files(files)) if files.files.iter().any(|file| file.path == path)
+        )));
+    }
+
+    fn files_records(run: &Run) -> Vec<wandb_internal::FilesRecord> {
+        sync_file_records(run)
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Files(files)) => Some(files),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn saves_matching_files() {
+        let cwd = TempCwd::new();
+        std::fs::create_dir_all(cwd.path().join("plots")).unwrap();
+        std::fs::write(cwd.path().join("plots/loss.png"), "png").unwrap();
+        std::fs::write(cwd.path().join("plots/acc.png"), "png").unwrap();
+        std::fs::write(cwd.path().join("model.pt"), "weights").unwrap();
+
+        let mut run = run_in_mode("offline");
+        run.init(Some("save1".to_string())).unwrap();
+        assert_eq!(
+            run.save("plots/*.png", "end").unwrap(),
+            ["acc.png", "loss.png"]
+        );
+        assert_eq!(run.save("model.pt", "now").unwrap(), ["model.pt"]);
+        // nothing to save is not an error
+        assert!(run.save("*.ckpt", "now").unwrap().is_empty());
+        assert!(run.save("model.pt", "sometime").is_err());
+        run.finish(None, None).unwrap();
+
+        let files_dir = PathBuf::from(run.settings.files_dir());
+        assert!(files_dir.join("acc.png").exists());
+        assert!(files_dir.join("model.pt").exists());
+
+        let saved: Vec<_> = files_records(&run)
+            .iter()
+            .flat_map(|record| record.files.clone())
+            .map(|file| (file.path, file.policy))
+            .collect();
+        assert_eq!(
+            saved,
+            [
+                ("acc.png".to_string(), PolicyType::End as i32),
+                ("loss.png".to_string(), PolicyType::End as i32),
+                ("model.pt".to_string(), PolicyType::Now as i32),
+            ]
+        );
+    }
+
+    #[test]
+    fn live_files_are_watched() {
+        let cwd = TempCwd::new();
+        std::fs::write(cwd.path().join("live.txt"), "v1").unwrap();
+        let mut run = run_in_mode("offline");
+        run.init(Some("save2".to_string())).unwrap();
+        assert!(run.file_watcher.is_none());
+        run.save("live.txt", "live").unwrap();
+        assert!(run.file_watcher.is_some());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        let file = std::fs::File::options()
+            .write(true)
+            .open(cwd.path().join("live.txt"))
+            .unwrap();
+        file.set_modified(later).unwrap();
+        // uploaded again, right away, once the watcher notices
+        let deadline = SystemTime::now() + LIVE_FILES_POLL_INTERVAL * 3;
+        let resynced = || {
+            files_records(&run).iter().any(|record| {
+                record
+                    .files
+                    .iter()
+                    .any(|file| file.path == "live.txt" && file.policy == PolicyType::Now as i32)
+            })
+        };
+        while !resynced() && SystemTime::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(resynced());
+        run.finish(None, None).unwrap();
+    }
+
+    /// The steps nexus is sent, with the keys logged at each, `_timestamp` aside.
+    fn history_rows(records: &[wandb_internal::Record]) -> Vec<(i64, Vec<String>)> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Request(wandb_internal::Request {
+                    request_type:
+                        Some(wandb_internal::request::RequestType::PartialHistory(history)),
+                })) => {
+                    let mut keys: Vec<String> = history
+                        .item
+                        .iter()
+                        .map(|item| item.key.clone())
+                        .filter(|key| key != "_timestamp")
+                        .collect();
+                    keys.sort();
+                    Some((history.step.as_ref()?.num, keys))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accumulates_uncommitted_values() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("steps1".to_string())).unwrap();
+        run.add_history(scalars(&[("loss", 1.0)]), None, Some(false), None)
+            .unwrap();
+        run.add_history(scalars(&[("acc", 0.5)]), None, Some(false), None)
+            .unwrap();
+        run.add_history(scalars(&[("lr", 0.1)]), None, None, None)
+            .unwrap();
+        run.add_history(scalars(&[("loss", 0.5)]), None, None, None)
+            .unwrap();
+        run.finish(None, None).unwrap();
+
+        assert_eq!(
+            history_rows(&sync_file_records(&run)),
+            [
+                (
+                    0,
+                    vec!["acc".to_string(), "loss".to_string(), "lr".to_string()]
+                ),
+                (1, vec!["loss".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn explicit_steps_commit_the_previous_one() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("steps2".to_string())).unwrap();
+        // values for the same explicit step accumulate until it moves on
+        run.add_history(scalars(&[("loss", 1.0)]), Some(5), None, None)
+            .unwrap();
+        run.add_history(scalars(&[("acc", 0.5)]), Some(5), None, None)
+            .unwrap();
+        run.add_history(scalars(&[("loss", 0.5)]), Some(7), None, None)
+            .unwrap();
+        // and without a step, logging goes on from the last one
+        run.add_history(scalars(&[("loss", 0.2)]), None, None, None)
+            .unwrap();
+        run.add_history(scalars(&[("loss", 0.1)]), None, None, None)
+            .unwrap();
+        run.finish(None, None).unwrap();
+
+        let steps: Vec<i64> = history_rows(&sync_file_records(&run))
+            .into_iter()
+            .map(|(step, _)| step)
+            .collect();
+        assert_eq!(steps, [5, 7, 8]);
+        assert_eq!(history_rows(&sync_file_records(&run))[0].1, ["acc", "loss"]);
+    }
+
+    #[test]
+    fn rejects_steps_moving_backward() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("steps3".to_string())).unwrap();
+        run.add_history(scalars(&[("loss", 1.0)]), Some(10), Some(true), None)
+            .unwrap();
+        assert!(run
+            .add_history(scalars(&[("loss", 2.0)]), Some(3), None, None)
+            .is_err());
+        run.finish(None, None).unwrap();
+        assert_eq!(history_steps(&sync_file_records(&run)), [10]);
+    }
+
+    fn summary_updates(records: &[wandb_internal::Record]) -> Vec<(String, String)> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Summary(summary)) => Some(summary.update.clone()),
+                _ => None,
+            })
+            .flatten()
+            .map(|item| (item.key, item.value_json))
+            .collect()
+    }
+
+    #[test]
+    fn summary_is_not_history() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("summary1".to_string())).unwrap();
+        run.set_summary("best_acc".to_string(), Value::Float(0.8), false)
+            .unwrap();
+        run.set_summary("best_acc".to_string(), Value::Float(0.9), false)
+            .unwrap();
+        run.set_summary("model".to_string(), Value::Str("resnet".to_string()), false)
+            .unwrap();
+        run.finish(None, None).unwrap();
+
+        let records = sync_file_records(&run);
+        assert!(history_steps(&records).is_empty());
+        assert_eq!(
+            summary_updates(&records),
+            [
+                ("best_acc".to_string(), "0.8".to_string()),
+                ("best_acc".to_string(), "0.9".to_string()),
+                ("model".to_string(), "\"resnet\"".to_string()),
+            ]
+        );
+        assert_eq!(run.summary["best_acc"], 0.9);
+    }
+
+    #[test]
+    fn aggregated_summaries_need_force() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("summary2".to_string())).unwrap();
+        run.define_metric("loss".to_string(), None, Some("min".to_string()))
+            .unwrap();
+        run.define_metric("acc".to_string(), None, Some("none".to_string()))
+            .unwrap();
+        assert!(run
+            .set_summary("loss".to_string(), Value::Float(0.1), false)
+            .is_err());
+        // left to the user if not aggregated
+        run.set_summary("acc".to_string(), Value::Float(0.7), false)
+            .unwrap();
+        run.set_summary("loss".to_string(), Value::Float(0.1), true)
+            .unwrap();
+        run.finish(None, None).unwrap();
+
+        let keys: Vec<String> = summary_updates(&sync_file_records(&run))
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["acc", "loss"]);
+    }
+
+    #[test]
+    fn summary_includes_values_from_nexus() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::start(|record| match &record.record_type {
+            Some(RecordType::Request(wandb_internal::Request {
+                request_type: Some(wandb_internal::request::RequestType::GetSummary(_)),
+            })) => Some(wandb_internal::Result {
+                result_type: Some(wandb_internal::result::ResultType::Response(
+                    wandb_internal::Response {
+                        response_type: Some(
+                            wandb_internal::response::ResponseType::GetSummaryResponse(
+                                wandb_internal::GetSummaryResponse {
+                                    item: vec![
+                                        wandb_internal::SummaryItem {
+                                            key: "loss".to_string(),
+                                            value_json: "0.25".to_string(),
+                                            ..Default::default()
+                                        },
+                                        wandb_internal::SummaryItem {
+                                            key: "_wandb".to_string(),
+                                            value_json: "{}".to_string(),
+                                            ..Default::default()
+                                        },
+                                    ],
+                                },
+                            ),
+                        ),
+                    },
+                )),
+                ..Default::default()
+            }),
+            _ => Some(testing::echo(record)),
+        });
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("summary3".to_string())).unwrap();
+        run.set_summary("best".to_string(), Value::Int(3), false)
+            .unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let summary = run.summary(py);
+            let summary: HashMap<String, f64> = summary.extract(py).unwrap();
+            assert_eq!(
+                summary,
+                HashMap::from([("loss".to_string(), 0.25), ("best".to_string(), 3.0)])
+            );
+        });
+        run.finish(None, Some(5.0)).unwrap();
+    }
+
+    /// The settings of the run as nexus was told them.
+    fn init_settings(nexus: &MockNexus) -> wandb_internal::Settings {
+        nexus
+            .received()
+            .into_iter()
+            .find_map(|request| match request.server_request_type {
+                Some(wandb_internal::server_request::ServerRequestType::InformInit(init)) => {
+                    init.settings
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn requests_anonymous_runs() {
+        for (anonymous, api_key, sent_key) in [
+            ("never", None, None),
+            ("never", Some("key"), Some("key")),
+            ("allow", None, None),
+            ("allow", Some("key"), Some("key")),
+            // even with a key
+            ("must", Some("key"), None),
+        ] {
+            let _cwd = TempCwd::new();
+            let nexus = MockNexus::echo();
+            let mut run = online_run(&nexus, |settings| {
+                settings.set_anonymous(Some(anonymous.to_string())).unwrap();
+                settings.proto.api_key = api_key.map(str::to_string);
+            });
+            run.init(Some(format!("anonymous-{}", anonymous))).unwrap();
+            run.finish(None, Some(5.0)).unwrap();
+
+            let settings = init_settings(&nexus);
+            assert_eq!(settings.anonymous.as_deref(), Some(anonymous));
+            assert_eq!(
+                settings.api_key.as_deref(),
+                sent_key,
+                "{:?}",
+                (anonymous, api_key)
+            );
+        }
+    }
+
+    /// Answers every artifact logged with the id of a new version, `artifact1`, `artifact2`...
+    fn artifact_nexus() -> MockNexus {
+        let versions = std::sync::atomic::AtomicUsize::new(0);
+        MockNexus::start(move |record| match &record.record_type {
+            Some(RecordType::Request(wandb_internal::Request {
+                request_type: Some(wandb_internal::request::RequestType::LogArtifact(_)),
+            })) => {
+                let version = versions.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                Some(wandb_internal::Result {
+                    result_type: Some(wandb_internal::result::ResultType::Response(
+                        wandb_internal::Response {
+                            response_type: Some(
+                                wandb_internal::response::ResponseType::LogArtifactResponse(
+                                    wandb_internal::LogArtifactResponse {
+                                        artifact_id: format!("artifact{}", version),
+                                        ..Default::default()
+                                    },
+                                ),
+                            ),
+                        },
+                    )),
+                    ..Default::default()
+                })
+            }
+            _ => Some(testing::echo(record)),
+        })
+    }
+
+    fn logged_artifacts(nexus: &MockNexus) -> Vec<wandb_internal::ArtifactRecord> {
+        nexus
+            .records()
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Request(wandb_internal::Request {
+                    request_type: Some(wandb_internal::request::RequestType::LogArtifact(request)),
+                })) => request.artifact,
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn references_unchanged_artifact_files() {
+        let cwd = TempCwd::new();
+        std::fs::create_dir_all(cwd.path().join("dataset/images")).unwrap();
+        std::fs::write(cwd.path().join("dataset/labels.csv"), "cat,dog").unwrap();
+        std::fs::write(cwd.path().join("dataset/images/1.png"), "png").unwrap();
+
+        let nexus = artifact_nexus();
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("artifact1".to_string())).unwrap();
+        let paths = vec!["dataset".to_string()];
+        let first = run.log_artifact("dataset", "data", paths.clone()).unwrap();
+        std::fs::write(cwd.path().join("dataset/labels.csv"), "cat,dog,bird").unwrap();
+        let second = run.log_artifact("dataset", "data", paths).unwrap();
+        assert_eq!(first.as_deref(), Some("artifact1"));
+        assert_eq!(second.as_deref(), Some("artifact2"));
+        run.finish(None, Some(5.0)).unwrap();
+
+        let artifacts = logged_artifacts(&nexus);
+        assert_eq!(artifacts.len(), 2);
+        assert_eq!(artifacts[0].name, "dataset");
+        assert_eq!(artifacts[0].r#type, "data");
+        assert_ne!(artifacts[0].digest, artifacts[1].digest);
+        let entries = |artifact: &wandb_internal::ArtifactRecord| {
+            artifact
+                .manifest
+                .as_ref()
+                .unwrap()
+                .contents
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.path.clone(),
+                        entry.birth_artifact_id.clone(),
+                        !entry.local_path.is_empty(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            entries(&artifacts[0]),
+            [
+                ("images/1.png".to_string(), String::new(), true),
+                ("labels.csv".to_string(), String::new(), true),
+            ]
+        );
+        // only the changed file is uploaded again
+        assert_eq!(
+            entries(&artifacts[1]),
+            [
+                ("images/1.png".to_string(), "artifact1".to_string(), false),
+                ("labels.csv".to_string(), String::new(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_artifacts() {
+        let cwd = TempCwd::new();
+        std::fs::create_dir(cwd.path().join("empty")).unwrap();
+        let nexus = artifact_nexus();
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("artifact2".to_string())).unwrap();
+        assert!(run
+            .log_artifact("empty", "data", vec!["empty".to_string()])
+            .is_err());
+        assert!(run
+            .log_artifact("missing", "data", vec!["missing".to_string()])
+            .is_err());
+        run.finish(None, Some(5.0)).unwrap();
+        assert!(logged_artifacts(&nexus).is_empty());
+
+        let mut offline = run_in_mode("offline");
+        offline.init(Some("artifact3".to_string())).unwrap();
+        std::fs::write(cwd.path().join("file.txt"), "x").unwrap();
+        assert!(offline
+            .log_artifact("file", "data", vec!["file.txt".to_string()])
+            .is_err());
+        offline.finish(None, None).unwrap();
+    }
+
+    fn preempting_records(records: &[wandb_internal::Record]) -> usize {
+        records
+            .iter()
+            .filter(|record| matches!(record.record_type, Some(RecordType::Preempting(_))))
+            .count()
+    }
+
+    #[test]
+    fn marks_the_run_preempting_once() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("preempt1".to_string())).unwrap();
+        run.mark_preempting().unwrap();
+        run.mark_preempting().unwrap();
+        assert!(!run.finished);
+
+        // still logs, and finishes as usual
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, None)
+            .unwrap();
+        run.finish(None, None).unwrap();
+        assert!(run.finished);
+        run.mark_preempting().unwrap();
+
+        let records = sync_file_records(&run);
+        assert_eq!(preempting_records(&records), 1);
+        assert_eq!(history_steps(&records), [0]);
+        assert_eq!(exit_codes(&run), [0]);
+    }
+
+    fn rate_limited_run(rate: f64, policy: &str) -> Run {
+        let mut settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+        settings.history_rate_limit = rate;
+        settings.history_rate_limit_policy = policy.to_string();
+        Run::new(settings, Interface::detached())
+    }
+
+    #[test]
+    fn drops_history_over_the_rate_limit() {
+        let _cwd = TempCwd::new();
+        let mut run = rate_limited_run(5.0, "drop");
+        run.init(Some("limited1".to_string())).unwrap();
+        for i in 0..50 {
+            run.add_history(scalars(&[("loss", i as f64)]), None, None, None)
+                .unwrap();
+        }
+        // a dropped log doesn't move the step either
+        run.add_history(scalars(&[("loss", 0.0)]), Some(100), None, None)
+            .unwrap();
+        assert_eq!(run.history.lock().unwrap().step, 5);
+        run.finish(None, None).unwrap();
+        assert_eq!(history_steps(&sync_file_records(&run)), [0, 1, 2, 3, 4]);
+        assert!(run.warned_rate_limit);
+    }
+
+    #[test]
+    fn blocks_history_over_the_rate_limit() {
+        let _cwd = TempCwd::new();
+        let mut run = rate_limited_run(100.0, "block");
+        run.init(Some("limited2".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        let run = Python::with_gil(|py| {
+            let run = Py::new(py, run).unwrap();
+            let started = std::time::Instant::now();
+            for i in 0..120 {
+                Run::log(
+                    run.borrow_mut(py),
+                    scalars(&[("loss", i as f64)]),
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            }
+            assert!(started.elapsed() >= Duration::from_millis(150));
+            run.borrow_mut(py).finish(None, None).unwrap();
+            run
+        });
+        let records = Python::with_gil(|py| sync_file_records(&run.borrow(py)));
+        assert_eq!(history_steps(&records).len(), 120);
+    }
+
+    #[test]
+    fn blocked_logs_leave_other_threads_running() {
+        let _cwd = TempCwd::new();
+        let mut run = rate_limited_run(1.0, "block");
+        run.init(Some("limited3".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("run", Py::new(py, run).unwrap()).unwrap();
+            py.run(
+                r#"
+import threading, time
+
+ticks = []
+def tick():
+    end = time.time() + 0.5
+    while time.time() < end:
+        ticks.append(1)
+        time.sleep(0.01)
+
+ticker = threading.Thread(target=tick)
+ticker.start()
+run.log({"loss": 1.0})
+# waits for about a second
+run.log({"loss": 2.0})
+ticker.join()
+run.finish()
+"#,
+                Some(globals),
+                None,
+            )
+            .unwrap();
+            let ticks = globals.get_item("ticks").unwrap().unwrap().len().unwrap();
+            assert!(ticks >= 20, "{}", ticks);
+        });
+    }
+
+    #[test]
+    fn partial_logs_are_not_limited() {
+        let _cwd = TempCwd::new();
+        let mut run = rate_limited_run(1.0, "drop");
+        run.init(Some("limited4".to_string())).unwrap();
+        for i in 0..10 {
+            run.add_history(
+                scalars(&[(&format!("part{}", i), 1.0)]),
+                None,
+                Some(false),
+                None,
+            )
+            .unwrap();
+        }
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, None)
+            .unwrap();
+        assert!(!run.warned_rate_limit);
+        run.finish(None, None).unwrap();
+        let rows = history_rows(&sync_file_records(&run));
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].1.len(), 11);
+    }
+
+    #[test]
+    fn online_runs_keep_a_transaction_log() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("txlog1".to_string())).unwrap();
+        for i in 0..3 {
+            run.add_history(scalars(&[("loss", i as f64)]), None, None, None)
+                .unwrap();
+        }
+        run.set_summary("best".to_string(), Value::Float(0.5), false)
+            .unwrap();
+        run.finish(None, Some(5.0)).unwrap();
+
+        let sync_dir = run.settings.proto.sync_dir.clone().unwrap();
+        let path = transaction_log::online_path(&sync_dir, "txlog1");
+        let logged = sync::read_log(Path::new(&path), run.settings.max_frame_size).unwrap();
+        // everything worth replaying that nexus got, in the same order, logged
+        // before the mailbox of the reply is set
+        let sent: Vec<_> = nexus
+            .records()
+            .into_iter()
+            .filter(transaction_log::is_persisted)
+            .map(|mut record| {
+                record.control = None;
+                record
+            })
+            .collect();
+        assert_eq!(logged, sent);
+        assert_eq!(history_steps(&logged), [0, 1, 2]);
+        assert!(logged
+            .iter()
+            .any(|record| matches!(record.record_type, Some(RecordType::Run(_)))));
+        assert_eq!(summary_updates(&logged).len(), 1);
+        assert!(logged
+            .iter()
+            .any(|record| matches!(record.record_type, Some(RecordType::Exit(_)))));
+    }
+
+    #[test]
+    fn normalizes_images_ignoring_nonfinite_values() {
+        assert!(normalize(&[]).is_err());
+        assert_eq!(normalize(&[1.0, 3.0, 2.0]).unwrap(), [0.0, 1.0, 0.5]);
+        assert_eq!(
+            normalize(&[f64::NAN, 2.0, f64::INFINITY, 4.0]).unwrap(),
+            [0.0, 0.0, 0.0, 1.0]
+        );
+        assert_eq!(normalize(&[5.0, 5.0]).unwrap(), [0.0, 0.0]);
+        assert_eq!(
+            normalize(&[f64::NAN, f64::NEG_INFINITY]).unwrap(),
+            [0.0, 0.0]
+        );
+    }
+
+    /// The values logged at each step, as JSON, by key, `_timestamp` aside.
+    fn history_values(records: &[wandb_internal::Record]) -> Vec<HashMap<String, String>> {
+        let mut rows = history_values_with_timestamps(records);
+        for row in &mut rows {
+            row.remove("_timestamp");
+        }
+        rows
+    }
+
+    #[test]
+    fn logs_nonfinite_values_as_sentinels() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("nonfinite1".to_string())).unwrap();
+        let data = scalars(&[
+            ("nan", f64::NAN),
+            ("inf", f64::INFINITY),
+            ("-inf", f64::NEG_INFINITY),
+            ("acc", 0.5),
+        ]);
+        let (_, nonfinite) = run.add_history(data, None, None, None).unwrap();
+        assert!(nonfinite.is_empty());
+        run.finish(None, None).unwrap();
+
+        let values = history_values(&sync_file_records(&run));
+        assert_eq!(
+            values,
+            [HashMap::from([
+                ("nan".to_string(), "\"NaN\"".to_string()),
+                ("inf".to_string(), "\"Infinity\"".to_string()),
+                ("-inf".to_string(), "\"-Infinity\"".to_string()),
+                ("acc".to_string(), "0.5".to_string()),
+            ])]
+        );
+    }
+
+    #[test]
+    fn raises_on_nonfinite_values_after_logging_the_rest() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.settings.raise_on_nonfinite = true;
+        run.init(Some("nonfinite2".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = Py::new(py, run).unwrap();
+            let data = scalars(&[("loss", f64::NAN), ("grad", f64::INFINITY), ("acc", 0.5)]);
+            let err = Run::log(run.borrow_mut(py), data, None, None, None).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+            assert_eq!(
+                err.value(py).to_string(),
+                "Not logging non-finite values of grad, loss"
+            );
+            run.borrow_mut(py).finish(None, None).unwrap();
+
+            let values = history_values(&sync_file_records(&run.borrow(py)));
+            assert_eq!(
+                values,
+                [HashMap::from([("acc".to_string(), "0.5".to_string())])]
+            );
+        });
+    }
+
+    fn artifact_paths(artifact: &wandb_internal::ArtifactRecord) -> Vec<&str> {
+        artifact
+            .manifest
+            .as_ref()
+            .unwrap()
+            .contents
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn logs_code_as_an_artifact() {
+        let cwd = TempCwd::new();
+        std::fs::create_dir_all(cwd.path().join("lib")).unwrap();
+        std::fs::write(cwd.path().join("train.py"), "import lib").unwrap();
+        std::fs::write(cwd.path().join("lib/util.py"), "pass").unwrap();
+        std::fs::write(cwd.path().join("lib/test_util.py"), "pass").unwrap();
+        std::fs::write(cwd.path().join("lib/data.bin"), b"\0\0").unwrap();
+
+        let nexus = artifact_nexus();
+        let mut run = online_run(&nexus, |settings| {
+            settings.proto.project = Some("proj".to_string())
+        });
+        run.init(Some("code1".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            py.import("sys")
+                .unwrap()
+                .setattr("argv", vec!["train.py", "--lr", "0.1"])
+                .unwrap();
+            // the script that was run, by default
+            assert_eq!(
+                run.log_code(py, None, None, None).unwrap().as_deref(),
+                Some("artifact1")
+            );
+            run.log_code(
+                py,
+                None,
+                Some("**/*.py".to_string()),
+                Some("**/test_*.py".to_string()),
+            )
+            .unwrap();
+            assert!(run
+                .log_code(py, None, Some("*.rs".to_string()), None)
+                .is_err());
+
+            py.import("sys").unwrap().setattr("argv", vec![""]).unwrap();
+            assert!(run.log_code(py, None, None, None).is_err());
+        });
+        run.finish(None, Some(5.0)).unwrap();
+
+        let artifacts = logged_artifacts(&nexus);
+        assert_eq!(artifacts.len(), 2);
+        assert!(artifacts.iter().all(|artifact| artifact.r#type == "code"));
+        assert_eq!(artifacts[0].name, "source-proj-train.py");
+        assert_eq!(artifact_paths(&artifacts[0]), ["train.py"]);
+        assert_eq!(artifact_paths(&artifacts[1]), ["lib/util.py", "train.py"]);
+    }
+
+    #[test]
+    fn logs_from_many_threads() {
+        const THREADS: usize = 8;
+        const LOGS: usize = 200;
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |_| {});
+        // a write for every log, for as many concurrent writes as possible
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::ZERO);
+        run.init(Some("threads1".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = Py::new(py, run).unwrap();
+            let globals = pyo3::types::IntoPyDict::into_py_dict([("run", run.clone_ref(py))], py);
+            py.run(
+                &format!(
+                    r#"
+import threading
+
+def work(thread):
+    for i in range({logs}):
+        run.log({{"thread_%d" % thread: float(i)}})
+
+threads = [threading.Thread(target=work, args=(t,)) for t in range({threads})]
+for thread in threads:
+    thread.start()
+for thread in threads:
+    thread.join()
+"#,
+                    logs = LOGS,
+                    threads = THREADS
+                ),
+                Some(globals),
+                None,
+            )
+            .unwrap();
+            run.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+        });
+
+        // every record made it, intact, each thread's in the order it logged them
+        let values = history_values(&nexus.records());
+        assert_eq!(values.len(), THREADS * LOGS);
+        let mut logged: HashMap<String, Vec<f64>> = HashMap::new();
+        for step in &values {
+            assert_eq!(step.len(), 1, "{:?}", step);
+            for (key, value) in step {
+                logged
+                    .entry(key.clone())
+                    .or_default()
+                    .push(value.parse().unwrap());
+            }
+        }
+        assert_eq!(logged.len(), THREADS);
+        for values in logged.values() {
+            let expected: Vec<f64> = (0..LOGS).map(|i| i as f64).collect();
+            assert_eq!(values, &expected);
+        }
+        let steps = history_steps(&nexus.records());
+        assert_eq!(steps, (0..(THREADS * LOGS) as i64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn finish_gives_up_after_the_timeout() {
+        let _cwd = TempCwd::new();
+        // answers until the run exits, and never again
+        let exited = std::sync::atomic::AtomicBool::new(false);
+        let nexus = MockNexus::start(move |record| {
+            if matches!(record.record_type, Some(RecordType::Exit(_))) {
+                exited.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            if exited.load(std::sync::atomic::Ordering::SeqCst) {
+                return None;
+            }
+            Some(testing::echo(record))
+        });
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("timeout1".to_string())).unwrap();
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, None)
+            .unwrap();
+        assert!(run.finish(None, Some(0.0)).is_err());
+        assert!(run.finish(None, Some(f64::NAN)).is_err());
+        assert!(!run.finished);
+
+        let started = std::time::Instant::now();
+        let err = run.finish(None, Some(0.3)).unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            assert!(err.is_instance_of::<pyo3::exceptions::PyConnectionError>(py));
+            assert!(err.value(py).to_string().contains("within 0.3s"));
+        });
+        assert!(run.finished);
+
+        // the run can still be synced from its transaction log
+        let sync_dir = run.settings.proto.sync_dir.clone().unwrap();
+        let path = transaction_log::online_path(&sync_dir, "timeout1");
+        let logged = sync::read_log(Path::new(&path), run.settings.max_frame_size).unwrap();
+        assert_eq!(history_steps(&logged), [0]);
+        assert!(logged
+            .iter()
+            .any(|record| matches!(record.record_type, Some(RecordType::Exit(_)))));
+    }
+
+    #[test]
+    fn associates_metrics_with_their_step_metric() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("stepmetric1".to_string())).unwrap();
+        run.define_metric("epoch".to_string(), None, None).unwrap();
+        run.define_metric("val/*".to_string(), Some("epoch".to_string()), None)
+            .unwrap();
+        run.define_metric("test/acc".to_string(), Some("epoch".to_string()), None)
+            .unwrap();
+        run.define_metric("train/loss".to_string(), None, None)
+            .unwrap();
+
+        for batch in 0..4 {
+            run.add_history(scalars(&[("train/loss", batch as f64)]), None, None, None)
+                .unwrap();
+            if batch % 2 == 1 {
+                // the step metric along with the metric plotted against it
+                let epoch = (batch / 2) as f64;
+                run.add_history(
+                    scalars(&[("val/acc", 0.5 + epoch), ("epoch", epoch)]),
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            }
+        }
+        run.finish(None, None).unwrap();
+        let records = sync_file_records(&run);
+
+        // nexus adds the last epoch to steps logging them without it
+        let associations: Vec<_> = metric_records(&records)
+            .iter()
+            .map(|metric| {
+                (
+                    if metric.glob_name.is_empty() {
+                        metric.name.clone()
+                    } else {
+                        metric.glob_name.clone()
+                    },
+                    metric.step_metric.clone(),
+                    metric.options.as_ref().unwrap().step_sync,
+                )
+            })
+            .collect();
+        assert_eq!(
+            associations,
+            [
+                ("epoch".to_string(), String::new(), false),
+                ("val/*".to_string(), "epoch".to_string(), true),
+                ("test/acc".to_string(), "epoch".to_string(), true),
+                ("train/loss".to_string(), String::new(), false),
+            ]
+        );
+
+        // the global step goes on for every log, the epoch only with its metrics
+        let rows = history_rows(&records);
+        assert_eq!(
+            rows,
+            [
+                (0, vec!["train/loss".to_string()]),
+                (1, vec!["train/loss".to_string()]),
+                (2, vec!["epoch".to_string(), "val/acc".to_string()]),
+                (3, vec!["train/loss".to_string()]),
+                (4, vec!["train/loss".to_string()]),
+                (5, vec!["epoch".to_string(), "val/acc".to_string()]),
+            ]
+        );
+        let epochs: Vec<String> = history_values(&records)
+            .into_iter()
+            .filter_map(|row| row.get("epoch").cloned())
+            .collect();
+        assert_eq!(epochs, ["0.0", "1.0"]);
+    }
+
+    #[test]
+    fn reports_what_was_sent() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("stats3".to_string())).unwrap();
+        for step in 0..3 {
+            run.add_history(scalars(&[("loss", step as f64)]), None, None, None)
+                .unwrap();
+        }
+        // history goes out in batches, and the counters are kept once finished
+        run.finish(None, Some(5.0)).unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let stats = run.get_stats(py);
+            let stats: &PyDict = stats.downcast(py).unwrap();
+            let sent: &PyDict = stats.get_item("sent").unwrap().unwrap().downcast().unwrap();
+            let histories: u64 = sent
+                .get_item("partial_history")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(histories, 3);
+            assert!(sent.get_item("run").unwrap().is_some());
+            assert!(sent.get_item("exit").unwrap().is_some());
+            let bytes: u64 = stats
+                .get_item("bytes_sent")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert!(bytes > 0);
+            let reconnects: u64 = stats
+                .get_item("reconnects")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(reconnects, 0);
+        });
+    }
+
+    #[test]
+    fn offline_runs_send_nothing() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("stats4".to_string())).unwrap();
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, None)
+            .unwrap();
+        assert!(run.interface.stats().sent.is_empty());
+        run.finish(None, None).unwrap();
+    }
+
+    /// The `_timestamp` logged at each step nexus is sent.
+    fn history_timestamps(records: &[wandb_internal::Record]) -> Vec<f64> {
+        history_values_with_timestamps(records)
+            .into_iter()
+            .map(|row| row["_timestamp"].parse().unwrap())
+            .collect()
+    }
+
+    fn history_values_with_timestamps(
+        records: &[wandb_internal::Record],
+    ) -> Vec<HashMap<String, String>> {
+        records
+            .iter()
+            .filter_map(|record| match &record.record_type {
+                Some(RecordType::Request(wandb_internal::Request {
+                    request_type:
+                        Some(wandb_internal::request::RequestType::PartialHistory(history)),
+                })) => Some(
+                    history
+                        .item
+                        .iter()
+                        .map(|item| (item.key.clone(), item.value_json.clone()))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn logs_the_given_timestamp() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("timestamp1".to_string())).unwrap();
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, None)
+            .unwrap();
+        // replayed with the time it was recorded at
+        run.add_history(scalars(&[("loss", 2.0)]), None, None, Some(1.5e9))
+            .unwrap();
+        // the last log of a step sets its timestamp
+        run.add_history(scalars(&[("acc", 0.5)]), None, Some(false), Some(1.7e9))
+            .unwrap();
+        run.add_history(scalars(&[("loss", 3.0)]), None, None, Some(1.7e9 + 1.0))
+            .unwrap();
+        run.finish(None, None).unwrap();
+        let after = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        let timestamps = history_timestamps(&sync_file_records(&run));
+        assert_eq!(timestamps.len(), 3);
+        assert!(
+            before <= timestamps[0] && timestamps[0] <= after,
+            "{:?}",
+            timestamps
+        );
+        assert_eq!(timestamps[1..], [1.5e9, 1.7e9 + 1.0]);
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        let _cwd = TempCwd::new();
+        let mut run = run_in_mode("offline");
+        run.init(Some("timestamp2".to_string())).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        pyo3::prepare_freethreaded_python();
+        for timestamp in [
+            f64::NAN,
+            f64::INFINITY,
+            -1.0,
+            now + 2.0 * 24.0 * 60.0 * 60.0,
+        ] {
+            let Err(e) = run.add_history(scalars(&[("loss", 1.0)]), None, None, Some(timestamp))
+            else {
+                panic!("logged at {}", timestamp);
+            };
+            Python::with_gil(|py| {
+                assert!(e.is_instance_of::<PyValueError>(py));
+                assert!(
+                    e.value(py).to_string().contains("Invalid timestamp"),
+                    "{}",
+                    e
+                );
+            });
+        }
+        // within a day from now is fine
+        run.add_history(scalars(&[("loss", 1.0)]), None, None, Some(now + 60.0))
+            .unwrap();
+        run.finish(None, None).unwrap();
+
+        let records = sync_file_records(&run);
+        assert_eq!(history_rows(&records), [(0, vec!["loss".to_string()])]);
+        assert_eq!(history_timestamps(&records), [now + 60.0]);
+    }
+
+    #[test]
+    fn finish_gives_up_on_a_nexus_that_stops_reading() {
+        let _cwd = TempCwd::new();
+        // stops reading for good once asked for the summary
+        let nexus = MockNexus::start(|record| match &record.record_type {
+            Some(RecordType::Request(wandb_internal::Request {
+                request_type: Some(wandb_internal::request::RequestType::GetSummary(_)),
+            })) => loop {
+                std::thread::sleep(Duration::from_secs(60));
+            },
+            _ => Some(testing::echo(record)),
+        });
+        let mut run = online_run(&nexus, |_| {});
+        *run.history.lock().unwrap() = HistoryBuffer::new(Duration::from_secs(3600));
+        run.init(Some("stalled2".to_string())).unwrap();
+        run.interface
+            .set_deadline(Some(Instant::now() + Duration::from_millis(100)));
+        assert!(run.get_summary().is_none());
+        run.interface.set_deadline(None);
+        // more than the socket buffers hold, all of it left for finish to send
+        let blob = "x".repeat(16 << 20);
+        for _ in 0..4 {
+            run.add_history(
+                HashMap::from([("blob".to_string(), Value::Str(blob.clone()))]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+
+        let started = Instant::now();
+        let err = run.finish(None, Some(0.5)).unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            assert!(err.is_instance_of::<pyo3::exceptions::PyConnectionError>(py));
+        });
+    }
+
+    #[test]
+    fn finish_leaves_other_threads_running() {
+        let _cwd = TempCwd::new();
+        // never confirms the end of the run
+        let nexus = MockNexus::start(|record| match &record.record_type {
+            Some(RecordType::Exit(_)) => None,
+            _ => Some(testing::echo(record)),
+        });
+        let mut run = online_run(&nexus, |_| {});
+        run.init(Some("stalled3".to_string())).unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("run", Py::new(py, run).unwrap()).unwrap();
+            py.run(
+                r#"
+import threading, time
+
+ticks = []
+def tick():
+    end = time.time() + 0.4
+    while time.time() < end:
+        ticks.append(1)
+        time.sleep(0.01)
+
+ticker = threading.Thread(target=tick)
+ticker.start()
+try:
+    run.finish(timeout=0.5)
+except ConnectionError:
+    pass
+ticker.join()
+"#,
+                Some(globals),
+                None,
+            )
+            .unwrap();
+            let ticks = globals.get_item("ticks").unwrap().unwrap().len().unwrap();
+            assert!(ticks >= 20, "{}", ticks);
+        });
     }
 }
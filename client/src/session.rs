@@ -1,88 +1,810 @@
+use pyo3::exceptions::{PyKeyboardInterrupt, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{PyCFunction, PyDict, PyTuple};
 
-use core::panic;
-use std::io;
-use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use sentry;
 use std::env;
 use std::path::Path;
 use tracing;
 
-use crate::connection::{Connection, Interface};
+use crate::connection::{
+    connect_with_retry, Address, Connection, Interface, Reconnect, SharedConnection, Stream,
+};
+use crate::error::{self, Error};
 use crate::launcher::Launcher;
-use crate::run::Run;
-use crate::settings::Settings;
+use crate::metadata::Metadata;
+use crate::run::{Run, FINISH_TIMEOUT_SECS};
+use crate::settings::{Mode, Settings};
+use crate::sync;
 
 #[pyclass]
 pub struct Session {
     settings: Settings,
-    addr: String,
+    // only set in online mode
+    addr: Option<Address>,
+    // shared by all runs of the session, opened by the first one
+    connection: Mutex<Option<SharedConnection>>,
+    // whose signal handlers are installed along with the first of them
+    signal_runs: SignalRuns,
+    handling_signals: AtomicBool,
 }
 
-pub fn get_core_address() -> String {
+/// The nexus binary: the configured one, or else the one shipped with the package.
+pub fn core_path(settings: &Settings) -> error::Result<PathBuf> {
+    if let Some(path) = &settings.core_path {
+        return Ok(PathBuf::from(path));
+    }
     // TODO: get and set WANDB_CORE env variable to handle multiprocessing
-    let current_dir =
-        env::var("_WANDB_CORE_PATH").expect("Environment variable _WANDB_CORE_PATH is not set");
-    // Create a Path from the current_dir
-    let core_cmd = Path::new(&current_dir)
-        .join("wandb-core")
-        .into_os_string()
-        .into_string()
-        .expect("Failed to convert path to string");
-    print!("core_cmd: {}", core_cmd);
-    // print if it exists
-    if let Ok(core_cmd) = std::fs::read_to_string(&core_cmd) {
-        print!("IT EXISTS: {}", core_cmd);
-    }
-
-    let launcher = Launcher { command: core_cmd };
-    let port = launcher.start();
-    format!("127.0.0.1:{}", port)
+    let current_dir = env::var("_WANDB_CORE_PATH").map_err(|_| {
+        Error::Settings("Environment variable _WANDB_CORE_PATH is not set".to_string())
+    })?;
+    Ok(Path::new(&current_dir).join("wandb-core"))
+}
+
+pub fn get_core_address(settings: &Settings) -> error::Result<String> {
+    // nexus makes the requests to the backend, explicit variables win
+    let mut env = settings.proxies().env();
+    env.extend(settings.core_env.clone());
+
+    let launcher = Launcher {
+        command: core_path(settings)?,
+        args: settings.core_args.clone(),
+        env,
+        // validated with the rest of the settings
+        timeout: Duration::from_secs_f64(settings.core_startup_timeout_secs),
+    };
+    let port = launcher.start()?;
+    Ok(format!("127.0.0.1:{}", port))
+}
+
+const SIGNALS: [&str; 2] = ["SIGINT", "SIGTERM"];
+
+/// The unfinished runs of a session by id, which are finished as crashed on
+/// SIGINT/SIGTERM. A run leaves once it is finished.
+pub type SignalRuns = Arc<Mutex<Vec<(String, Py<Run>)>>>;
+
+/// Finishes `runs` as crashed on SIGINT/SIGTERM, then passes the signal on
+/// to whatever handler was installed before.
+fn install_signal_handlers(py: Python<'_>, runs: &SignalRuns) -> PyResult<()> {
+    let signal = py.import("signal")?;
+    for name in SIGNALS {
+        let signum: i32 = signal.getattr(name)?.extract()?;
+        let previous: PyObject = signal.call_method1("getsignal", (signum,))?.into();
+        let runs = runs.clone();
+        let handler = PyCFunction::new_closure(
+            py,
+            None,
+            None,
+            move |args: &PyTuple, _kwargs: Option<&PyDict>| -> PyResult<()> {
+                let py = args.py();
+                tracing::warn!("Received signal {}, finishing runs", signum);
+                finish_runs(py, &runs, signum);
+                chain_signal(py, signum, &previous, args)
+            },
+        )?;
+        signal.call_method1("signal", (signum, handler))?;
+    }
+    Ok(())
+}
+
+fn finish_runs(py: Python<'_>, runs: &SignalRuns, signum: i32) {
+    // finishing a run takes it out of the list
+    let runs: Vec<Py<Run>> = runs
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, run)| run.clone_ref(py))
+        .collect();
+    for run in runs {
+        match run.try_borrow_mut(py) {
+            // same convention as shells for processes killed by a signal
+            Ok(mut run) => {
+                let run = &mut *run;
+                let finished =
+                    py.allow_threads(|| run.finish(Some(128 + signum), Some(FINISH_TIMEOUT_SECS)));
+                if let Err(e) = finished {
+                    tracing::error!("Failed to finish run: {}", e);
+                }
+            }
+            Err(_) => tracing::error!("Run is busy, cannot finish it"),
+        }
+    }
+}
+
+fn chain_signal(py: Python<'_>, signum: i32, previous: &PyObject, args: &PyTuple) -> PyResult<()> {
+    let previous = previous.as_ref(py);
+    if previous.is_callable() {
+        // e.g. signal.default_int_handler, which raises KeyboardInterrupt
+        previous.call1(args)?;
+        return Ok(());
+    }
+
+    let signal = py.import("signal")?;
+    let default = signal.getattr("SIG_DFL")?;
+    if previous.eq(default)? {
+        // restore the default disposition and re-raise, so the process ends as it would have
+        signal.call_method1("signal", (signum, default))?;
+        let os = py.import("os")?;
+        os.call_method1("kill", (os.call_method0("getpid")?, signum))?;
+    }
+    // the signal was ignored, or its handler was not installed from Python
+    Ok(())
 }
 
 #[pymethods]
 impl Session {
     #[new]
-    pub fn new(settings: Settings) -> Session {
-        let addr = get_core_address();
-        let session = Session { settings, addr };
+    pub fn new(settings: Settings) -> PyResult<Session> {
+        // before launching nexus, which would fail less clearly
+        settings.validate()?;
+        let addr = match settings.mode_kind() {
+            // a socket path means nexus is already serving there
+            Mode::Online => Some(match &settings.core_socket_path {
+                Some(path) => Address::Unix(PathBuf::from(path)),
+                None => Address::Tcp(get_core_address(&settings)?),
+            }),
+            Mode::Offline | Mode::Disabled => None,
+        };
+        let session = Session {
+            settings,
+            addr,
+            connection: Mutex::new(None),
+            signal_runs: Arc::new(Mutex::new(Vec::new())),
+            handling_signals: AtomicBool::new(false),
+        };
         tracing::debug!("Session created");
 
-        session
+        Ok(session)
     }
 
-    pub fn init_run(&self, run_id: Option<String>) -> Run {
-        let conn = Connection::new(self.connect());
-        let interface = Interface::new(conn);
+    pub fn init_run(&self, py: Python<'_>, run_id: Option<String>) -> PyResult<Py<Run>> {
+        self.start_run(py, self.settings.clone(), run_id)
+    }
 
-        let mut run = Run {
-            settings: self.settings.clone(),
-            interface,
-        };
+    /// Acts as an agent of sweep `sweep_id`: runs `function` with a new run
+    /// for every config `suggest` returns, until it returns `None` or `count`
+    /// runs are done. The sweep reads the objective from the run summaries.
+    /// A failing run is finished as crashed and the agent moves on to the next.
+    /// Nexus has no sweep requests yet, so the suggestions come from
+    /// `suggest` rather than from nexus, and no objective is reported to it.
+    /// Returns how many runs were done.
+    #[pyo3(signature = (sweep_id, function, suggest, count=None))]
+    pub fn agent(
+        &self,
+        py: Python<'_>,
+        sweep_id: String,
+        function: &PyAny,
+        suggest: &PyAny,
+        count: Option<usize>,
+    ) -> PyResult<usize> {
+        let mut done = 0;
+        while count.is_none_or(|count| done < count) {
+            let suggestion = suggest.call0()?;
+            if suggestion.is_none() {
+                tracing::debug!("Sweep {} has no more suggestions", sweep_id);
+                break;
+            }
+            let params: &PyDict = suggestion.downcast()?;
+
+            let mut settings = self.settings.clone();
+            settings.proto.sweep_id = Some(sweep_id.clone());
+            // every suggestion gets a run of its own
+            settings.proto.run_id = None;
+            settings.proto.resume = None;
+            let run = self.start_run(py, settings, None)?;
+            run.borrow_mut(py).update_config(params)?;
 
-        run.init(run_id);
+            let result = function.call1((run.clone_ref(py),));
+            if let Err(e) = run.borrow_mut(py).py_finish(
+                py,
+                Some(if result.is_ok() { 0 } else { 1 }),
+                Some(FINISH_TIMEOUT_SECS),
+            ) {
+                tracing::error!("Failed to finish sweep run: {}", e);
+            }
+            done += 1;
+            if let Err(e) = result {
+                if e.is_instance_of::<PyKeyboardInterrupt>(py) {
+                    return Err(e);
+                }
+                tracing::error!("Sweep run failed: {}", e);
+                e.print(py);
+            }
+        }
+        Ok(done)
+    }
 
-        return run;
+    /// Uploads a run recorded in offline mode from its directory, like
+    /// `wandb sync`. Returns false if it was synced already.
+    pub fn sync(&self, dir: &str) -> PyResult<bool> {
+        let Some(addr) = &self.addr else {
+            return Err(PyValueError::new_err("Syncing a run requires online mode"));
+        };
+        let mut interface = self.interface(addr)?;
+        let synced = sync::sync_dir(&mut interface, &self.settings, Path::new(dir));
+        interface.close();
+        Ok(synced?)
     }
 }
 
 impl Session {
-    fn connect(&self) -> TcpStream {
-        tracing::debug!("Connecting to {}", self.addr);
-
-        if let Ok(stream) = TcpStream::connect(&self.addr) {
-            tracing::debug!("Stream peer address: {}", stream.peer_addr().unwrap());
-            tracing::debug!("Stream local address: {}", stream.local_addr().unwrap());
-
-            return stream;
-        } else {
-            sentry::capture_error(&io::Error::new(
-                io::ErrorKind::Other,
-                "Couldn't connect to server...",
+    fn start_run(
+        &self,
+        py: Python<'_>,
+        settings: Settings,
+        run_id: Option<String>,
+    ) -> PyResult<Py<Run>> {
+        let run_id = run_id.or_else(|| settings.proto.run_id.clone());
+        if settings.proto.resume.as_deref() == Some("must") && run_id.is_none() {
+            return Err(PyValueError::new_err(
+                "resume=\"must\" requires the id of the run to resume",
             ));
-            tracing::error!("Couldn't connect to server...");
-            panic!();
         }
+
+        let interface = match &self.addr {
+            Some(addr) => self.interface(addr)?,
+            None => Interface::detached(),
+        };
+
+        let disabled = settings.mode_kind() == Mode::Disabled;
+        let handle_signals = settings.handle_signals && !disabled;
+        let with_metadata = !disabled && settings.proto.disable_meta != Some(true);
+        let with_git = settings.proto.disable_git != Some(true);
+        let mut run = Run::new(settings, interface);
+
+        run.init(run_id)?;
+        if with_metadata {
+            // the run is fine without it
+            match Metadata::collect(py, with_git) {
+                Ok(metadata) => run.send_metadata(&metadata)?,
+                Err(e) => tracing::warn!("Failed to gather metadata: {}", e),
+            }
+        }
+
+        if handle_signals {
+            run.signal_runs = Some(self.signal_runs.clone());
+        }
+        let run = Py::new(py, run)?;
+        if handle_signals {
+            let id = run.borrow(py).settings.run_id().unwrap_or_default();
+            self.signal_runs
+                .lock()
+                .unwrap()
+                .push((id, run.clone_ref(py)));
+            // once per session, and only from the main thread
+            if !self.handling_signals.swap(true, Ordering::SeqCst) {
+                if let Err(e) = install_signal_handlers(py, &self.signal_runs) {
+                    tracing::warn!("Failed to install signal handlers: {}", e);
+                    self.handling_signals.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(run)
+    }
+
+    /// An interface over the shared connection to nexus, which is opened
+    /// again if all runs using it have finished.
+    fn interface(&self, addr: &Address) -> error::Result<Interface> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(interface) = connection.as_ref().and_then(SharedConnection::interface) {
+            return Ok(interface);
+        }
+        let shared = SharedConnection::new(
+            Connection::new(self.connect(addr)?, self.settings.max_frame_size),
+            Some(Reconnect {
+                addr: addr.clone(),
+                max_retries: self.settings.connect_max_retries,
+                base_delay: Duration::from_millis(self.settings.connect_base_delay_ms),
+            }),
+        )?;
+        // can't be closed before its first interface exists
+        let interface = shared.interface().unwrap();
+        *connection = Some(shared);
+        Ok(interface)
+    }
+
+    fn connect(&self, addr: &Address) -> error::Result<Stream> {
+        tracing::debug!("Connecting to {}", addr);
+
+        match connect_with_retry(
+            addr,
+            self.settings.connect_max_retries,
+            Duration::from_millis(self.settings.connect_base_delay_ms),
+        ) {
+            Ok(stream) => {
+                tracing::debug!("Stream peer address: {}", stream.peer());
+
+                Ok(stream)
+            }
+            Err(e) => {
+                sentry::capture_error(&e);
+                tracing::error!("{}", e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::Value;
+    use crate::testing::{MockNexus, TempCwd};
+    use crate::wandb_internal::record::RecordType;
+    use pyo3::types::{IntoPyDict, PyList};
+    use std::collections::HashMap;
+
+    fn offline_session() -> Session {
+        let mut settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+        settings.proto.disable_meta = Some(true);
+        Session::new(settings).unwrap()
+    }
+
+    fn exit_code(run: &Run) -> Option<i32> {
+        let path = run.settings.proto.sync_file.clone().unwrap();
+        sync::read_log(Path::new(&path), run.settings.max_frame_size)
+            .unwrap()
+            .into_iter()
+            .find_map(|record| match record.record_type {
+                Some(RecordType::Exit(exit)) => Some(exit.exit_code),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn signal_finishes_runs_as_crashed() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let session = offline_session();
+            let first = session.init_run(py, Some("signal1".to_string())).unwrap();
+            let second = session.init_run(py, Some("signal2".to_string())).unwrap();
+            assert_eq!(session.signal_runs.lock().unwrap().len(), 2);
+
+            finish_runs(py, &session.signal_runs, 15);
+            assert!(session.signal_runs.lock().unwrap().is_empty());
+            for run in [first, second] {
+                let run = run.borrow(py);
+                assert!(run.finished);
+                assert_eq!(exit_code(&run), Some(128 + 15));
+            }
+        });
+    }
+
+    #[test]
+    fn finished_runs_are_released() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let session = offline_session();
+            for id in ["released1", "released2", "released3"] {
+                let run = session.init_run(py, Some(id.to_string())).unwrap();
+                run.borrow_mut(py).finish(None, None).unwrap();
+                assert!(session.signal_runs.lock().unwrap().is_empty());
+                assert_eq!(run.get_refcnt(py), 1);
+            }
+        });
+    }
+
+    #[test]
+    fn runs_without_signal_handling_are_not_kept() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+            settings.proto.disable_meta = Some(true);
+            settings.handle_signals = false;
+            let session = Session::new(settings).unwrap();
+            let run = session.init_run(py, None).unwrap();
+            assert!(session.signal_runs.lock().unwrap().is_empty());
+            assert!(run.borrow(py).signal_runs.is_none());
+            run.borrow_mut(py).finish(None, None).unwrap();
+        });
+    }
+
+    #[test]
+    fn chains_to_the_previous_handler() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let calls = PyList::empty(py);
+            let previous: PyObject = py
+                .eval(
+                    "lambda signum, frame, calls=calls: calls.append(signum)",
+                    None,
+                    Some([("calls", calls)].into_py_dict(py)),
+                )
+                .unwrap()
+                .into();
+            let args = PyTuple::new(py, [15.into_py(py), py.None()]);
+            chain_signal(py, 15, &previous, args).unwrap();
+            assert_eq!(calls.extract::<Vec<i32>>().unwrap(), vec![15]);
+
+            // an ignored signal stays ignored
+            let ignored: PyObject = py
+                .import("signal")
+                .unwrap()
+                .getattr("SIG_IGN")
+                .unwrap()
+                .into();
+            chain_signal(py, 15, &ignored, args).unwrap();
+        });
+    }
+
+    #[test]
+    fn connects_to_a_socket_path() {
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.core_socket_path = Some("/tmp/nexus.sock".to_string());
+        let session = Session::new(settings).unwrap();
+        assert!(matches!(
+            &session.addr,
+            Some(Address::Unix(path)) if path == Path::new("/tmp/nexus.sock")
+        ));
+    }
+
+    fn online_session(nexus: &MockNexus) -> Session {
+        session_at(nexus.addr.to_string())
+    }
+
+    fn session_at(addr: String) -> Session {
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.proto.disable_meta = Some(true);
+        settings.heartbeat_interval_secs = 0.0;
+        settings.connect_max_retries = 1;
+        settings.connect_base_delay_ms = 1;
+        Session {
+            settings,
+            addr: Some(Address::Tcp(addr)),
+            connection: Mutex::new(None),
+            signal_runs: Arc::new(Mutex::new(Vec::new())),
+            handling_signals: AtomicBool::new(false),
+        }
+    }
+
+    fn log(py: Python<'_>, run: &Py<Run>, key: &str, value: f64) {
+        let data = HashMap::from([(key.to_string(), Value::Float(value))]);
+        Run::log(run.borrow_mut(py), data, None, None, None).unwrap();
+        run.borrow_mut(py).flush().unwrap();
+    }
+
+    /// The stream ids of the history records nexus received, in order.
+    fn history_stream_ids(nexus: &MockNexus) -> Vec<String> {
+        nexus
+            .records()
+            .into_iter()
+            .filter(|record| {
+                matches!(
+                    &record.record_type,
+                    Some(RecordType::Request(crate::wandb_internal::Request {
+                        request_type: Some(
+                            crate::wandb_internal::request::RequestType::PartialHistory(_)
+                        ),
+                    }))
+                )
+            })
+            .map(|record| record.info.unwrap().stream_id)
+            .collect()
+    }
+
+    #[test]
+    fn runs_share_the_connection() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let session = online_session(&nexus);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let first = session.init_run(py, Some("shared1".to_string())).unwrap();
+            let second = session.init_run(py, Some("shared2".to_string())).unwrap();
+            log(py, &first, "loss", 1.0);
+            log(py, &second, "acc", 0.5);
+            // one connection, so the same counts
+            assert_eq!(
+                first.borrow(py).interface.stats().bytes_sent,
+                second.borrow(py).interface.stats().bytes_sent
+            );
+
+            first.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+            log(py, &second, "acc", 0.75);
+            let shared = session.connection.lock().unwrap().clone().unwrap();
+            // still open for the second run
+            shared.interface().unwrap().close();
+
+            second.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+            assert!(shared.interface().is_none());
+        });
+        assert_eq!(
+            history_stream_ids(&nexus),
+            vec!["shared1", "shared2", "shared2"]
+        );
+    }
+
+    #[test]
+    fn runs_count_steps_of_their_own() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::echo();
+        let session = online_session(&nexus);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let first = session.init_run(py, Some("steps1".to_string())).unwrap();
+            let second = session.init_run(py, Some("steps2".to_string())).unwrap();
+            for i in 0..3 {
+                log(py, &first, "loss", i as f64);
+            }
+            log(py, &second, "loss", 0.0);
+            assert_eq!(first.borrow(py).history.lock().unwrap().step, 3);
+            assert_eq!(second.borrow(py).history.lock().unwrap().step, 1);
+            for run in [first, second] {
+                run.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn unreachable_nexus_raises_connection_error() {
+        let _cwd = TempCwd::new();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let session = session_at(addr.to_string());
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let err = session.init_run(py, Some("down1".to_string())).unwrap_err();
+            assert!(err.is_instance_of::<pyo3::exceptions::PyConnectionError>(py));
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_settings_before_launching() {
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.proto.base_url = Some("api.wandb.ai".to_string());
+        settings.proto.project = Some("a/b".to_string());
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let err = Session::new(settings).err().unwrap();
+            assert!(err.is_instance_of::<pyo3::exceptions::PyValueError>(py));
+            let message = err.value(py).to_string();
+            assert!(message.contains("base_url"), "{}", message);
+            assert!(message.contains("project"), "{}", message);
+        });
+    }
+
+    #[test]
+    fn resolves_the_nexus_binary() {
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut settings = Settings::new(None, None, None, None, None);
+        env::remove_var("_WANDB_CORE_PATH");
+        assert!(matches!(core_path(&settings), Err(Error::Settings(_))));
+
+        env::set_var("_WANDB_CORE_PATH", "/opt/wandb");
+        assert_eq!(
+            core_path(&settings).unwrap(),
+            Path::new("/opt/wandb/wandb-core")
+        );
+        // a configured binary wins
+        settings.core_path = Some("/usr/local/bin/custom-core".to_string());
+        assert_eq!(
+            core_path(&settings).unwrap(),
+            Path::new("/usr/local/bin/custom-core")
+        );
+        env::remove_var("_WANDB_CORE_PATH");
+    }
+
+    #[test]
+    fn fails_for_a_missing_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.core_path = Some(dir.path().join("missing").display().to_string());
+        let err = get_core_address(&settings).unwrap_err();
+        assert!(matches!(err, Error::Settings(_)));
+        assert!(err.to_string().contains("not found"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn launches_the_configured_binary() {
+        use std::os::unix::fs::PermissionsExt;
+        let _lock = crate::testing::ENV_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("seen");
+        let nexus = dir.path().join("custom-core");
+        std::fs::write(
+            &nexus,
+            format!(
+                "#!/bin/sh\necho \"$3 $INHERITED $OVERRIDDEN\" > {}\nprintf 'sock=4321\\nEOF\\n' > \"$2\"\n",
+                seen.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&nexus, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut settings = Settings::new(None, None, None, None, None);
+        settings.core_path = Some(nexus.display().to_string());
+        settings.core_args = vec!["--debug".to_string()];
+        settings.core_env = HashMap::from([("OVERRIDDEN".to_string(), "new".to_string())]);
+        env::set_var("INHERITED", "kept");
+        env::set_var("OVERRIDDEN", "old");
+        let addr = get_core_address(&settings);
+        env::remove_var("INHERITED");
+        env::remove_var("OVERRIDDEN");
+
+        assert_eq!(addr.unwrap(), "127.0.0.1:4321");
+        assert_eq!(std::fs::read_to_string(seen).unwrap(), "--debug kept new\n");
+    }
+
+    fn sync_records(run: &Run) -> Vec<crate::wandb_internal::Record> {
+        let path = run.settings.proto.sync_file.clone().unwrap();
+        sync::read_log(Path::new(&path), run.settings.max_frame_size).unwrap()
+    }
+
+    #[test]
+    fn agent_runs_every_suggestion() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let session = offline_session();
+            let sweep = PyModule::from_code(
+                py,
+                r#"
+configs = iter([{"lr": 0.1, "layers": 2}, {"lr": 0.2, "layers": 3}, {"lr": 0.3, "layers": 4}])
+
+def suggest():
+    return next(configs, None)
+
+runs = []
+
+def train(run):
+    runs.append(run)
+    lr = run.get_config("lr")
+    if lr > 0.25:
+        raise ValueError("diverged")
+    run.log({"loss": lr * 2})
+    run.set_summary("accuracy", 1 - lr)
+"#,
+                "sweep.py",
+                "sweep",
+            )
+            .unwrap();
+            let suggest = sweep.getattr("suggest").unwrap();
+            let train = sweep.getattr("train").unwrap();
+
+            let done = session
+                .agent(py, "sweep1".to_string(), train, suggest, Some(2))
+                .unwrap();
+            assert_eq!(done, 2);
+            // the rest, until there are no more suggestions
+            let done = session
+                .agent(py, "sweep1".to_string(), train, suggest, None)
+                .unwrap();
+            assert_eq!(done, 1);
+
+            let runs: Vec<Py<Run>> = sweep.getattr("runs").unwrap().extract().unwrap();
+            let mut ids = Vec::new();
+            for (run, (lr, layers, accuracy, exit)) in runs.iter().zip([
+                (0.1, 2, Some("0.9"), 0),
+                (0.2, 3, Some("0.8"), 0),
+                (0.3, 4, None, 1),
+            ]) {
+                let run = run.borrow(py);
+                assert!(run.finished);
+                ids.push(run.settings.run_id());
+                assert_eq!(run.config.get("lr"), Some(&serde_json::json!(lr)));
+                assert_eq!(run.config.get("layers"), Some(&serde_json::json!(layers)));
+                assert_eq!(exit_code(&run), Some(exit));
+
+                let records = sync_records(&run);
+                assert!(records.iter().any(|record| matches!(
+                    &record.record_type,
+                    Some(RecordType::Run(run)) if run.sweep_id == "sweep1"
+                )));
+                // the objective, for the sweep to read from the summary
+                let reported = records.iter().find_map(|record| match &record.record_type {
+                    Some(RecordType::Summary(summary)) => summary
+                        .update
+                        .iter()
+                        .find(|item| item.key == "accuracy")
+                        .map(|item| item.value_json.clone()),
+                    _ => None,
+                });
+                assert_eq!(reported.as_deref(), accuracy);
+            }
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids.len(), 3);
+        });
+    }
+
+    #[test]
+    fn sends_metadata_at_run_start() {
+        let _cwd = TempCwd::new();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+            let session = Session::new(settings).unwrap();
+            let run = session.init_run(py, Some("metadata1".to_string())).unwrap();
+            run.borrow_mut(py).finish(None, None).unwrap();
+
+            let metadata = sync_records(&run.borrow(py))
+                .into_iter()
+                .find_map(|record| match record.record_type {
+                    Some(RecordType::Request(crate::wandb_internal::Request {
+                        request_type:
+                            Some(crate::wandb_internal::request::RequestType::Metadata(metadata)),
+                    })) => Some(metadata),
+                    _ => None,
+                })
+                .unwrap();
+            assert!(!metadata.host.is_empty());
+            assert!(!metadata.os.is_empty());
+            // the working directory is not in a repository
+            assert_eq!(metadata.git, None);
+        });
+    }
+
+    #[test]
+    fn resumed_runs_start_where_they_left_off() {
+        let _cwd = TempCwd::new();
+        let nexus = MockNexus::start(|record| match &record.record_type {
+            Some(RecordType::Run(run)) => {
+                let mut run = run.clone();
+                run.starting_step = 7;
+                run.summary = Some(crate::wandb_internal::SummaryRecord {
+                    update: vec![crate::wandb_internal::SummaryItem {
+                        key: "best_acc".to_string(),
+                        value_json: "0.9".to_string(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                });
+                Some(crate::testing::echo(&crate::wandb_internal::Record {
+                    record_type: Some(RecordType::Run(run)),
+                    ..Default::default()
+                }))
+            }
+            _ => Some(crate::testing::echo(record)),
+        });
+        let mut session = online_session(&nexus);
+        session
+            .settings
+            .set_resume(Some("allow".to_string()))
+            .unwrap();
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = session.init_run(py, Some("resume5".to_string())).unwrap();
+            let summary = run.borrow_mut(py).summary(py);
+            let best_acc: f64 = summary
+                .as_ref(py)
+                .get_item("best_acc")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(best_acc, 0.9);
+
+            log(py, &run, "loss", 0.1);
+            run.borrow_mut(py).finish(None, Some(5.0)).unwrap();
+        });
+        let steps: Vec<i64> = nexus
+            .records()
+            .into_iter()
+            .filter_map(|record| match record.record_type {
+                Some(RecordType::Request(crate::wandb_internal::Request {
+                    request_type:
+                        Some(crate::wandb_internal::request::RequestType::PartialHistory(history)),
+                })) => history.step.map(|step| step.num),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(steps, [7]);
     }
 }
@@ -0,0 +1,357 @@
+use prost::Message;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::connection::{read_frame, Interface};
+use crate::error::{self, Error};
+use crate::printer;
+use crate::settings::{self, Settings};
+use crate::wandb_internal::{self, record::RecordType};
+
+/// The transaction log of the run in `dir`. The directory of an online run
+/// holds nexus's log along with the client's, which has all that was logged
+/// even if nexus didn't get it, so the client's is the one replayed.
+pub fn find_log(dir: &Path) -> io::Result<PathBuf> {
+    let logs: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("run-") && name.ends_with(".wandb")
+        })
+        .collect();
+    let (client, nexus): (Vec<PathBuf>, Vec<PathBuf>) = logs.into_iter().partition(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        name.ends_with(".client.wandb")
+    });
+    let mut logs = if client.is_empty() { nexus } else { client };
+    match logs.len() {
+        1 => Ok(logs.remove(0)),
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No run in {}", dir.display()),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("More than one run in {}", dir.display()),
+        )),
+    }
+}
+
+/// The file whose presence means the log was synced, like `wandb sync` leaves.
+pub fn marker_path(log: &Path) -> PathBuf {
+    let mut marker = log.as_os_str().to_owned();
+    marker.push(".synced");
+    PathBuf::from(marker)
+}
+
+pub fn read_log(path: &Path, max_frame_size: usize) -> error::Result<Vec<wandb_internal::Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    while let Some(frame) = read_frame(&mut reader, max_frame_size)? {
+        let record = wandb_internal::Record::decode(frame.as_slice())
+            .map_err(|e| Error::Protocol(format!("Invalid record in {}: {}", path.display(), e)))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn request(
+    request_type: wandb_internal::request::RequestType,
+    stream_id: &str,
+) -> wandb_internal::Record {
+    wandb_internal::Record {
+        record_type: Some(RecordType::Request(wandb_internal::Request {
+            request_type: Some(request_type),
+        })),
+        info: Some(wandb_internal::RecordInfo {
+            stream_id: stream_id.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn inform(
+    request_type: wandb_internal::server_request::ServerRequestType,
+) -> wandb_internal::ServerRequest {
+    wandb_internal::ServerRequest {
+        server_request_type: Some(request_type),
+    }
+}
+
+/// Replays the offline run in `dir` to nexus, which creates it online.
+/// Returns false without sending anything if it was synced already.
+pub fn sync_dir(interface: &mut Interface, settings: &Settings, dir: &Path) -> error::Result<bool> {
+    let log = find_log(dir)?;
+    let marker = marker_path(&log);
+    if marker.exists() {
+        tracing::info!("{} was synced already", dir.display());
+        return Ok(false);
+    }
+    let records = read_log(&log, settings.max_frame_size)?;
+    let Some(run) = records.iter().find_map(|record| match &record.record_type {
+        Some(RecordType::Run(run)) => Some(run.clone()),
+        _ => None,
+    }) else {
+        return Err(Error::Protocol(format!("No run in {}", log.display())));
+    };
+    let run_id = run.run_id.clone();
+    tracing::debug!("Syncing run {} from {}", run_id, log.display());
+
+    let mut settings = settings.clone();
+    settings.proto.run_id = Some(run_id.clone());
+    if !run.project.is_empty() {
+        settings.proto.project = Some(run.project.clone());
+    }
+    if !run.entity.is_empty() {
+        settings.proto.entity = Some(run.entity.clone());
+    }
+    settings.proto.offline = Some(false);
+    // nexus keeps no log of its own of a synced run
+    settings.proto.sync = Some(true);
+    settings.proto.sync_dir = Some(dir.to_string_lossy().to_string());
+    settings.proto.sync_file = Some(log.to_string_lossy().to_string());
+    settings.proto.files_dir = Some(dir.join("files").to_string_lossy().to_string());
+
+    interface.send_message(&inform(
+        wandb_internal::server_request::ServerRequestType::InformInit(
+            wandb_internal::ServerInformInitRequest {
+                settings: Some(settings.proto.clone()),
+                info: Some(wandb_internal::RecordInfo {
+                    stream_id: run_id.clone(),
+                    ..Default::default()
+                }),
+            },
+        ),
+    ))?;
+
+    for mut record in records {
+        match &record.record_type {
+            Some(RecordType::Run(_)) => {
+                let result = interface.send_and_recv_message(&mut record);
+                let Some(wandb_internal::result::ResultType::RunResult(run_result)) =
+                    result.and_then(|result| result.result_type)
+                else {
+                    return Err(Error::Protocol(format!("No result for run {}", run_id)));
+                };
+                if let Some(error) = run_result.error {
+                    return Err(Error::Protocol(error.message));
+                }
+                if let Some(run) = run_result.run {
+                    let url =
+                        settings::run_url(&settings.base_url(), &run.entity, &run.project, &run_id);
+                    printer::print_header(&run.display_name, &url);
+                }
+                let mut run_start = request(
+                    wandb_internal::request::RequestType::RunStart(
+                        wandb_internal::RunStartRequest {
+                            run: Some(wandb_internal::RunRecord {
+                                run_id: run_id.clone(),
+                                ..Default::default()
+                            }),
+                            info: Some(wandb_internal::RequestInfo {
+                                stream_id: run_id.clone(),
+                            }),
+                        },
+                    ),
+                    &run_id,
+                );
+                interface.send_and_recv_message(&mut run_start);
+            }
+            // waits for everything before it to be sent
+            Some(RecordType::Exit(_)) => {
+                interface.send_and_recv_message(&mut record);
+            }
+            _ => interface.send_message(&inform(
+                wandb_internal::server_request::ServerRequestType::RecordPublish(record),
+            ))?,
+        }
+    }
+
+    let mut shutdown = request(
+        wandb_internal::request::RequestType::Shutdown(wandb_internal::ShutdownRequest {
+            info: Some(wandb_internal::RequestInfo {
+                stream_id: run_id.clone(),
+            }),
+        }),
+        &run_id,
+    );
+    if interface.send_and_recv_message(&mut shutdown).is_none() {
+        return Err(Error::Protocol(format!(
+            "Run {} was not confirmed to be synced",
+            run_id
+        )));
+    }
+    interface.send_message(&inform(
+        wandb_internal::server_request::ServerRequestType::InformFinish(
+            wandb_internal::ServerInformFinishRequest {
+                info: Some(wandb_internal::RecordInfo {
+                    stream_id: run_id.clone(),
+                    ..Default::default()
+                }),
+            },
+        ),
+    ))?;
+
+    File::create(&marker)?;
+    tracing::info!("Synced run {} from {}", run_id, dir.display());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::{Run, Value};
+    use crate::testing::{MockNexus, TempCwd};
+
+    /// Records an offline run, returning its directory and its records.
+    fn offline_run(id: &str) -> (PathBuf, Vec<wandb_internal::Record>) {
+        let settings = Settings::new(None, Some("offline".to_string()), None, None, None);
+        let mut run = Run::new(settings, Interface::detached());
+        run.init(Some(id.to_string())).unwrap();
+        for step in 0..3 {
+            run.set_summary("loss".to_string(), Value::Float(step as f64), false)
+                .unwrap();
+        }
+        run.finish(None, None).unwrap();
+        let dir = PathBuf::from(run.settings.proto.sync_dir.clone().unwrap());
+        let records = read_log(&find_log(&dir).unwrap(), run.settings.max_frame_size).unwrap();
+        (dir, records)
+    }
+
+    /// The records nexus got from the log, without the requests syncing adds.
+    fn replayed(nexus: &MockNexus) -> Vec<wandb_internal::Record> {
+        nexus
+            .records()
+            .into_iter()
+            .filter(|record| {
+                !matches!(
+                    &record.record_type,
+                    Some(RecordType::Request(wandb_internal::Request {
+                        request_type: Some(
+                            wandb_internal::request::RequestType::RunStart(_)
+                                | wandb_internal::request::RequestType::Shutdown(_)
+                        ),
+                    }))
+                )
+            })
+            .map(|mut record| {
+                record.control = None;
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_offline_runs_once() {
+        let _cwd = TempCwd::new();
+        let (dir, records) = offline_run("sync1");
+        assert!(records.len() > 3);
+
+        let nexus = MockNexus::echo();
+        let settings = Settings::new(None, None, None, None, None);
+        let mut interface = nexus.interface();
+        assert!(sync_dir(&mut interface, &settings, &dir).unwrap());
+        assert_eq!(replayed(&nexus), records);
+        assert!(marker_path(&find_log(&dir).unwrap()).exists());
+
+        let init = nexus
+            .received()
+            .into_iter()
+            .find_map(|request| match request.server_request_type {
+                Some(wandb_internal::server_request::ServerRequestType::InformInit(init)) => {
+                    init.settings
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(init.run_id.as_deref(), Some("sync1"));
+        assert_eq!(init.sync, Some(true));
+        assert_eq!(init.offline, Some(false));
+
+        // already synced
+        let received = nexus.received().len();
+        assert!(!sync_dir(&mut interface, &settings, &dir).unwrap());
+        assert_eq!(nexus.received().len(), received);
+        interface.close();
+    }
+
+    #[test]
+    fn finds_exactly_one_log() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            find_log(dir.path()).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::write(dir.path().join("run-a.wandb"), "").unwrap();
+        fs::write(dir.path().join("output.log"), "").unwrap();
+        assert_eq!(
+            find_log(dir.path()).unwrap(),
+            dir.path().join("run-a.wandb")
+        );
+        fs::write(dir.path().join("run-b.wandb"), "").unwrap();
+        assert_eq!(
+            find_log(dir.path()).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn prefers_the_client_log() {
+        let dir = tempfile::tempdir().unwrap();
+        // as nexus and the client leave them for an online run
+        fs::write(dir.path().join("run-a.wandb"), "").unwrap();
+        fs::write(dir.path().join("run-a.client.wandb"), "").unwrap();
+        assert_eq!(
+            find_log(dir.path()).unwrap(),
+            dir.path().join("run-a.client.wandb")
+        );
+        fs::write(dir.path().join("run-b.client.wandb"), "").unwrap();
+        assert_eq!(
+            find_log(dir.path()).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn replays_online_runs_from_the_client_log() {
+        let _cwd = TempCwd::new();
+        let online = MockNexus::echo();
+        let settings = Settings::new(None, None, None, None, None);
+        let mut run = Run::new(settings, online.interface());
+        run.init(Some("sync2".to_string())).unwrap();
+        run.set_summary("loss".to_string(), Value::Float(0.5), false)
+            .unwrap();
+        // the process dies, leaving the run unfinished
+        run.interface.close();
+        let dir = PathBuf::from(run.settings.proto.sync_dir.clone().unwrap());
+        let client_log = dir.join("run-sync2.client.wandb");
+        let records = read_log(&client_log, run.settings.max_frame_size).unwrap();
+        assert!(!records.is_empty());
+        // what nexus would have written of the run
+        fs::write(dir.join("run-sync2.wandb"), "").unwrap();
+
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let settings = Settings::new(None, None, None, None, None);
+        assert!(sync_dir(&mut interface, &settings, &dir).unwrap());
+        assert_eq!(replayed(&nexus), records);
+        assert!(marker_path(&client_log).exists());
+        interface.close();
+    }
+
+    #[test]
+    fn rejects_logs_without_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("run-empty.wandb"), "").unwrap();
+        let nexus = MockNexus::echo();
+        let mut interface = nexus.interface();
+        let settings = Settings::new(None, None, None, None, None);
+        let err = sync_dir(&mut interface, &settings, dir.path()).unwrap_err();
+        assert!(matches!(err, Error::Protocol(_)));
+        assert!(!marker_path(&dir.path().join("run-empty.wandb")).exists());
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::process::Command;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::wandb_internal;
+
+// clock ticks per second used by /proc, fixed at 100 on all common Linux architectures
+const CLOCK_TICKS: f64 = 100.0;
+
+/// Samples CPU, memory and, where available, NVIDIA GPU usage of the user
+/// process. Keys match the ones nexus uses, so they end up under `system/*`
+/// in the UI. Whatever can't be read on this platform is left out.
+pub struct SystemMonitor {
+    pid: i32,
+    cpu_count: f64,
+    // process CPU time in ticks, and when it was read
+    last_cpu: Option<(u64, Instant)>,
+    gpu: bool,
+}
+
+impl SystemMonitor {
+    pub fn new(pid: i32) -> Self {
+        SystemMonitor {
+            pid,
+            cpu_count: std::thread::available_parallelism().map_or(1, |n| n.get()) as f64,
+            last_cpu: None,
+            gpu: true,
+        }
+    }
+
+    pub fn sample(&mut self) -> BTreeMap<String, f64> {
+        let mut metrics = BTreeMap::new();
+        self.sample_cpu(&mut metrics);
+        self.sample_memory(&mut metrics);
+        if self.gpu {
+            // no driver or no GPU: don't try again on every sample
+            self.gpu = sample_gpu(&mut metrics);
+        }
+        metrics
+    }
+
+    fn sample_cpu(&mut self, metrics: &mut BTreeMap<String, f64>) {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", self.pid)) else {
+            return;
+        };
+        // the command name may contain spaces, the fields after it don't
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            return;
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        // utime and stime, fields 14 and 15 counting from the pid
+        let ticks = match (fields.get(11), fields.get(12)) {
+            (Some(utime), Some(stime)) => match (utime.parse::<u64>(), stime.parse::<u64>()) {
+                (Ok(utime), Ok(stime)) => utime + stime,
+                _ => return,
+            },
+            _ => return,
+        };
+        let now = Instant::now();
+        if let Some((last_ticks, last_time)) = self.last_cpu {
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            if elapsed > 0.0 {
+                let busy = ticks.saturating_sub(last_ticks) as f64 / CLOCK_TICKS;
+                metrics.insert("cpu".to_string(), 100.0 * busy / elapsed / self.cpu_count);
+            }
+        }
+        self.last_cpu = Some((ticks, now));
+
+        if let Some(threads) = fields.get(17).and_then(|n| n.parse::<f64>().ok()) {
+            metrics.insert("proc.cpu.threads".to_string(), threads);
+        }
+    }
+
+    fn sample_memory(&self, metrics: &mut BTreeMap<String, f64>) {
+        let rss_kb = fs::read_to_string(format!("/proc/{}/status", self.pid))
+            .ok()
+            .and_then(|status| proc_kb(&status, "VmRSS:"));
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        let total_kb = proc_kb(&meminfo, "MemTotal:");
+
+        if let Some(rss_kb) = rss_kb {
+            metrics.insert("proc.memory.rssMB".to_string(), rss_kb / 1024.0);
+            if let Some(total_kb) = total_kb.filter(|&total| total > 0.0) {
+                metrics.insert("proc.memory.percent".to_string(), 100.0 * rss_kb / total_kb);
+            }
+        }
+        if let Some(available_kb) = proc_kb(&meminfo, "MemAvailable:") {
+            metrics.insert("proc.memory.availableMB".to_string(), available_kb / 1024.0);
+            if let Some(total_kb) = total_kb.filter(|&total| total > 0.0) {
+                metrics.insert(
+                    "memory".to_string(),
+                    100.0 * (1.0 - available_kb / total_kb),
+                );
+            }
+        }
+    }
+}
+
+/// Reads a `Key:   1234 kB` line of a /proc file.
+fn proc_kb(contents: &str, key: &str) -> Option<f64> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix(key))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Queries `nvidia-smi` for every GPU. Returns whether it could be queried.
+fn sample_gpu(metrics: &mut BTreeMap<String, f64>) -> bool {
+    let output = Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=utilization.gpu,utilization.memory,memory.used,memory.total",
+            "--format=csv,noheader,nounits",
+        ])
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(_) | Err(_) => {
+            tracing::debug!("nvidia-smi is not available, not sampling GPUs");
+            return false;
+        }
+    };
+
+    for (i, line) in String::from_utf8_lossy(&output.stdout).lines().enumerate() {
+        let values: Vec<Option<f64>> = line.split(',').map(|v| v.trim().parse().ok()).collect();
+        if let [gpu, memory, used, total] = values[..] {
+            if let Some(gpu) = gpu {
+                metrics.insert(format!("gpu.{}.gpu", i), gpu);
+            }
+            if let Some(memory) = memory {
+                metrics.insert(format!("gpu.{}.memory", i), memory);
+            }
+            if let (Some(used), Some(total)) = (used, total) {
+                if total > 0.0 {
+                    metrics.insert(format!("gpu.{}.memoryAllocated", i), 100.0 * used / total);
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Wraps a sample into a system stats record.
+pub fn stats_record(metrics: BTreeMap<String, f64>, stream_id: &str) -> wandb_internal::Record {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    wandb_internal::Record {
+        record_type: Some(wandb_internal::record::RecordType::Stats(
+            wandb_internal::StatsRecord {
+                stats_type: wandb_internal::stats_record::StatsType::System as i32,
+                timestamp: Some(prost_types::Timestamp {
+                    seconds: now.as_secs() as i64,
+                    nanos: now.subsec_nanos() as i32,
+                }),
+                item: metrics
+                    .into_iter()
+                    .map(|(key, value)| wandb_internal::StatsItem {
+                        key,
+                        value_json: serde_json::to_string(&value).unwrap(),
+                    })
+                    .collect(),
+                ..Default::default()
+            },
+        )),
+        control: Some(wandb_internal::Control {
+            always_send: true,
+            ..Default::default()
+        }),
+        info: Some(wandb_internal::RecordInfo {
+            stream_id: stream_id.to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_proc_values() {
+        let status = "Name:\tpython\nVmPeak:\t  2048 kB\nVmRSS:\t  1024 kB\n";
+        assert_eq!(proc_kb(status, "VmRSS:"), Some(1024.0));
+        assert_eq!(proc_kb(status, "VmSwap:"), None);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn samples_cpu_and_memory() {
+        let mut monitor = SystemMonitor::new(std::process::id() as i32);
+        let first = monitor.sample();
+        assert!(first.contains_key("proc.memory.rssMB"), "{:?}", first);
+        assert!(first.contains_key("memory"), "{:?}", first);
+        // CPU usage needs two readings
+        assert!(!first.contains_key("cpu"));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let second = monitor.sample();
+        assert!(second.contains_key("cpu"), "{:?}", second);
+        if !monitor.gpu {
+            assert!(second.keys().all(|key| !key.starts_with("gpu.")));
+        }
+    }
+
+    #[test]
+    fn wraps_samples_in_stats_records() {
+        let metrics = BTreeMap::from([("cpu".to_string(), 12.5), ("memory".to_string(), 50.0)]);
+        let record = stats_record(metrics, "stats1");
+        let Some(wandb_internal::record::RecordType::Stats(stats)) = record.record_type else {
+            panic!("not a stats record");
+        };
+        assert_eq!(
+            stats.stats_type,
+            wandb_internal::stats_record::StatsType::System as i32
+        );
+        let items: Vec<(&str, &str)> = stats
+            .item
+            .iter()
+            .map(|item| (item.key.as_str(), item.value_json.as_str()))
+            .collect();
+        assert_eq!(items, vec![("cpu", "12.5"), ("memory", "50.0")]);
+        assert_eq!(record.info.unwrap().stream_id, "stats1");
+    }
+}
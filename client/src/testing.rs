@@ -0,0 +1,165 @@
+//! Helpers for the tests of several modules, among them a stand-in for nexus.
+
+use prost::Message;
+use std::env;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::connection::{
+    read_frame, write_frame, Address, Connection, Interface, Reconnect, SharedConnection, Stream,
+    DEFAULT_MAX_FRAME_SIZE,
+};
+use crate::wandb_internal::{self, server_request::ServerRequestType};
+
+/// Held by tests that set environment variables or the working directory,
+/// which all threads share.
+pub static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// Moves into a new working directory, where runs keep their files, and
+/// back once dropped. Holds the lock on the environment meanwhile.
+pub struct TempCwd {
+    dir: tempfile::TempDir,
+    previous: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TempCwd {
+    pub fn new() -> Self {
+        let lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let previous = env::current_dir().unwrap();
+        env::set_current_dir(dir.path()).unwrap();
+        TempCwd {
+            dir,
+            previous,
+            _lock: lock,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for TempCwd {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.previous);
+    }
+}
+
+type Respond = dyn Fn(&wandb_internal::Record) -> Option<wandb_internal::Result> + Send + Sync;
+
+/// Records what it receives, and answers the records waiting for a result.
+pub struct MockNexus {
+    pub addr: SocketAddr,
+    received: Arc<Mutex<Vec<wandb_internal::ServerRequest>>>,
+}
+
+impl MockNexus {
+    /// Answers each record sent with `RecordCommunicate` with what `respond`
+    /// returns for it, or not at all for `None`.
+    pub fn start<F>(respond: F) -> Self
+    where
+        F: Fn(&wandb_internal::Record) -> Option<wandb_internal::Result> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let respond: Arc<Respond> = Arc::new(respond);
+        let accepted = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let received = accepted.clone();
+                let respond = respond.clone();
+                thread::spawn(move || serve(stream, &received, &*respond));
+            }
+        });
+        MockNexus { addr, received }
+    }
+
+    /// Answers runs with the run itself, and everything else with an empty result.
+    pub fn echo() -> Self {
+        MockNexus::start(|record| Some(echo(record)))
+    }
+
+    /// An interface for a run over a new connection, which reconnects like
+    /// the ones of a session.
+    pub fn interface(&self) -> Interface {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        let conn = Connection::new(Stream::Tcp(stream), DEFAULT_MAX_FRAME_SIZE);
+        let reconnect = Reconnect {
+            addr: Address::Tcp(self.addr.to_string()),
+            max_retries: 3,
+            base_delay: Duration::from_millis(10),
+        };
+        SharedConnection::new(conn, Some(reconnect))
+            .unwrap()
+            .interface()
+            .unwrap()
+    }
+
+    pub fn received(&self) -> Vec<wandb_internal::ServerRequest> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The records received, in order.
+    pub fn records(&self) -> Vec<wandb_internal::Record> {
+        self.received()
+            .into_iter()
+            .filter_map(|request| match request.server_request_type {
+                Some(ServerRequestType::RecordPublish(record))
+                | Some(ServerRequestType::RecordCommunicate(record)) => Some(record),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The result nexus would give most records: the run for a run record, and
+/// none in particular for everything else.
+pub fn echo(record: &wandb_internal::Record) -> wandb_internal::Result {
+    match &record.record_type {
+        Some(wandb_internal::record::RecordType::Run(run)) => wandb_internal::Result {
+            result_type: Some(wandb_internal::result::ResultType::RunResult(
+                wandb_internal::RunUpdateResult {
+                    run: Some(run.clone()),
+                    error: None,
+                },
+            )),
+            ..Default::default()
+        },
+        _ => wandb_internal::Result::default(),
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    received: &Mutex<Vec<wandb_internal::ServerRequest>>,
+    respond: &Respond,
+) {
+    let mut reader = &stream;
+    while let Ok(Some(frame)) = read_frame(&mut reader, DEFAULT_MAX_FRAME_SIZE) {
+        let request = wandb_internal::ServerRequest::decode(frame.as_slice()).unwrap();
+        received.lock().unwrap().push(request.clone());
+        let Some(ServerRequestType::RecordCommunicate(record)) = request.server_request_type else {
+            continue;
+        };
+        let Some(mut result) = respond(&record) else {
+            continue;
+        };
+        result.control = record.control.clone();
+        let response = wandb_internal::ServerResponse {
+            server_response_type: Some(
+                wandb_internal::server_response::ServerResponseType::ResultCommunicate(result),
+            ),
+        };
+        let mut writer = &stream;
+        if write_frame(&mut writer, &response.encode_to_vec()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use prost::Message;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::connection::write_frame;
+use crate::wandb_internal;
+
+// at most this much of the log is lost if the machine goes down
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where an online run keeps its transaction log. The sync file is taken by
+/// the one nexus writes, in a format of its own.
+pub fn online_path(sync_dir: &str, run_id: &str) -> String {
+    format!("{}/run-{}.client.wandb", sync_dir, run_id)
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    last_sync: Instant,
+}
+
+/// Local log of the records of a run, written before they are sent so that
+/// they survive the process or nexus dying and can be synced later. Records
+/// are framed the same way as on the wire.
+pub struct TransactionLog {
+    writer: Mutex<Writer>,
+}
+
+impl TransactionLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(TransactionLog {
+            writer: Mutex::new(Writer {
+                file: BufWriter::new(file),
+                last_sync: Instant::now(),
+            }),
+        })
+    }
+
+    /// Appends a record. It reaches the OS right away, and the disk within
+    /// a second.
+    pub fn append(&self, record: &wandb_internal::Record) -> io::Result<()> {
+        let buf = record.encode_to_vec();
+        let mut writer = self.writer.lock().unwrap();
+        write_frame(&mut writer.file, &buf)?;
+        writer.file.flush()?;
+        if writer.last_sync.elapsed() >= SYNC_INTERVAL {
+            writer.file.get_ref().sync_data()?;
+            writer.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Makes sure everything appended is on disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.file.flush()?;
+        writer.file.get_ref().sync_all()?;
+        writer.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+/// Whether a record should end up in the transaction log. Requests are only
+/// meaningful to a live nexus, except for history and metadata which are
+/// replayed on sync.
+pub fn is_persisted(record: &wandb_internal::Record) -> bool {
+    match &record.record_type {
+        Some(wandb_internal::record::RecordType::Request(request)) => matches!(
+            request.request_type,
+            Some(
+                wandb_internal::request::RequestType::PartialHistory(_)
+                    | wandb_internal::request::RequestType::Metadata(_)
+            )
+        ),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::read_frame;
+    use std::fs;
+
+    fn request(request_type: wandb_internal::request::RequestType) -> wandb_internal::Record {
+        wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::Request(
+                wandb_internal::Request {
+                    request_type: Some(request_type),
+                },
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn persists_records_and_replayed_requests() {
+        let run = wandb_internal::Record {
+            record_type: Some(wandb_internal::record::RecordType::Run(Default::default())),
+            ..Default::default()
+        };
+        assert!(is_persisted(&run));
+        assert!(is_persisted(&request(
+            wandb_internal::request::RequestType::PartialHistory(Default::default())
+        )));
+        assert!(!is_persisted(&request(
+            wandb_internal::request::RequestType::Shutdown(Default::default())
+        )));
+        assert!(!is_persisted(&wandb_internal::Record::default()));
+    }
+
+    #[test]
+    fn appends_framed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run-test.wandb");
+        let log = TransactionLog::create(&path).unwrap();
+        let records: Vec<wandb_internal::Record> = (0..3)
+            .map(|num| wandb_internal::Record {
+                num,
+                ..Default::default()
+            })
+            .collect();
+        for record in &records {
+            log.append(record).unwrap();
+        }
+        log.sync().unwrap();
+
+        let contents = fs::read(&path).unwrap();
+        let mut reader = contents.as_slice();
+        let mut read = Vec::new();
+        while let Some(frame) = read_frame(&mut reader, 1 << 20).unwrap() {
+            read.push(wandb_internal::Record::decode(frame.as_slice()).unwrap());
+        }
+        assert_eq!(read, records);
+    }
+}
@@ -20,13 +20,24 @@ type DefaultFileTransfer struct {
 
 	// logger is the logger for the file transfer
 	logger *observability.CoreLogger
+
+	// files larger than partThreshold are uploaded in parts of partSize,
+	// none are if partSize is 0
+	partThreshold int64
+	partSize      int64
+
+	// stateDir is where the state of uploads in parts is kept, so that they
+	// can be resumed; the temporary directory if empty
+	stateDir string
 }
 
 // NewDefaultFileTransfer creates a new fileTransfer
 func NewDefaultFileTransfer(logger *observability.CoreLogger, client *retryablehttp.Client) *DefaultFileTransfer {
 	fileTransfer := &DefaultFileTransfer{
-		logger: logger,
-		client: client,
+		logger:        logger,
+		client:        client,
+		partThreshold: defaultPartThreshold,
+		partSize:      defaultPartSize,
 	}
 	return fileTransfer
 }
@@ -53,6 +64,9 @@ func (ft *DefaultFileTransfer) Upload(task *Task) error {
 		return err
 	}
 	task.Size = stat.Size()
+	if ft.partSize > 0 && task.Size > ft.partThreshold {
+		return ft.uploadParts(task, file, stat)
+	}
 
 	progressReader, err := NewProgressReader(file, task.Size, task.ProgressCallback)
 	if err != nil {
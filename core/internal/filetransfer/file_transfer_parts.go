@@ -0,0 +1,173 @@
+package filetransfer
+
+import (
+	"crypto/sha256"
+	"encoding/hex"
+	"encoding/json"
+	"fmt"
+	"io"
+	"net/http"
+	"os"
+	"path/filepath"
+	"strings"
+
+	"github.com/hashicorp/go-retryablehttp"
+)
+
+const (
+	// files larger than this are uploaded in parts
+	defaultPartThreshold = 2 << 30
+	defaultPartSize      = 100 << 20
+)
+
+// uploadState is what is known of an upload in parts, kept on disk so that
+// an interrupted upload picks up from the parts that weren't sent yet
+type uploadState struct {
+	Url      string `json:"url"`
+	Size     int64  `json:"size"`
+	ModTime  int64  `json:"mod_time"`
+	PartSize int64  `json:"part_size"`
+	Done     []bool `json:"done"`
+}
+
+// matches reports whether the state is that of uploading this version of the file
+func (s *uploadState) matches(other *uploadState) bool {
+	return s.Url == other.Url &&
+		s.Size == other.Size &&
+		s.ModTime == other.ModTime &&
+		s.PartSize == other.PartSize &&
+		len(s.Done) == len(other.Done)
+}
+
+// partReader reads one part of the file, from its start on every retry
+type partReader struct {
+	*io.SectionReader
+}
+
+func (pr partReader) Len() int {
+	return int(pr.Size())
+}
+
+// statePath is where the state of uploading the task's file to its URL is kept
+func (ft *DefaultFileTransfer) statePath(task *Task) string {
+	dir := ft.stateDir
+	if dir == "" {
+		dir = filepath.Join(os.TempDir(), "wandb-uploads")
+	}
+	sum := sha256.Sum256([]byte(task.Path + "\n" + task.Url))
+	return filepath.Join(dir, hex.EncodeToString(sum[:])+".json")
+}
+
+// loadState returns the parts sent by a previous attempt at the same upload,
+// or a fresh state if there was none or the file changed since
+func (ft *DefaultFileTransfer) loadState(path string, fresh *uploadState) *uploadState {
+	data, err := os.ReadFile(path)
+	if err != nil {
+		return fresh
+	}
+	var state uploadState
+	if err := json.Unmarshal(data, &state); err != nil || !state.matches(fresh) {
+		ft.logger.Debug("file transfer: upload: discarding the state of a previous upload", "path", path)
+		return fresh
+	}
+	return &state
+}
+
+func saveState(path string, state *uploadState) error {
+	data, err := json.Marshal(state)
+	if err != nil {
+		return err
+	}
+	if err := os.MkdirAll(filepath.Dir(path), 0o700); err != nil {
+		return err
+	}
+	// written whole, so that an interrupted write leaves the previous state
+	tmp := path + ".tmp"
+	if err := os.WriteFile(tmp, data, 0o600); err != nil {
+		return err
+	}
+	return os.Rename(tmp, path)
+}
+
+// uploadParts uploads the file in parts of partSize, each with a Content-Range
+// naming its bytes, as resumable upload sessions take them. Each part is
+// retried on its own by the client; the parts sent are recorded, so that
+// uploading the file again after a failure only sends the missing ones.
+func (ft *DefaultFileTransfer) uploadParts(task *Task, file *os.File, stat os.FileInfo) error {
+	numParts := (task.Size + ft.partSize - 1) / ft.partSize
+	statePath := ft.statePath(task)
+	state := ft.loadState(statePath, &uploadState{
+		Url:      task.Url,
+		Size:     task.Size,
+		ModTime:  stat.ModTime().UnixNano(),
+		PartSize: ft.partSize,
+		Done:     make([]bool, numParts),
+	})
+
+	var uploaded int64
+	for i, done := range state.Done {
+		if done {
+			uploaded += ft.partLen(task.Size, int64(i))
+		}
+	}
+	if uploaded > 0 {
+		ft.logger.Debug("file transfer: upload: resuming", "path", task.Path, "uploaded", uploaded)
+	}
+
+	for i, done := range state.Done {
+		if done {
+			continue
+		}
+		part := int64(i)
+		if err := ft.uploadPart(task, file, part); err != nil {
+			ft.logger.CaptureError("file transfer: upload: error uploading part", err, "path", task.Path, "part", part)
+			return err
+		}
+		state.Done[i] = true
+		if err := saveState(statePath, state); err != nil {
+			// the upload goes on, it only can't be resumed
+			ft.logger.CaptureError("file transfer: upload: error saving the upload state", err, "path", task.Path)
+		}
+		uploaded += ft.partLen(task.Size, part)
+		if task.ProgressCallback != nil {
+			task.ProgressCallback(int(uploaded), int(task.Size))
+		}
+	}
+
+	if err := os.Remove(statePath); err != nil && !os.IsNotExist(err) {
+		ft.logger.CaptureError("file transfer: upload: error removing the upload state", err, "path", task.Path)
+	}
+	return nil
+}
+
+// partLen is the length of the given part of a file of the given size
+func (ft *DefaultFileTransfer) partLen(size int64, part int64) int64 {
+	return min(ft.partSize, size-part*ft.partSize)
+}
+
+func (ft *DefaultFileTransfer) uploadPart(task *Task, file *os.File, part int64) error {
+	start := part * ft.partSize
+	length := ft.partLen(task.Size, part)
+	body := partReader{io.NewSectionReader(file, start, length)}
+	req, err := retryablehttp.NewRequest(http.MethodPut, task.Url, body)
+	if err != nil {
+		return err
+	}
+	req.ContentLength = length
+	for _, header := range task.Headers {
+		parts := strings.Split(header, ":")
+		req.Header.Set(parts[0], parts[1])
+	}
+	req.Header.Set("Content-Range", fmt.Sprintf("bytes %d-%d/%d", start, start+length-1, task.Size))
+
+	resp, err := ft.client.Do(req)
+	if err != nil {
+		return err
+	}
+	defer resp.Body.Close()
+	// resumable uploads answer the parts before the last with 308
+	if resp.StatusCode >= http.StatusBadRequest {
+		return fmt.Errorf("uploading bytes %d-%d: %s", start, start+length-1, resp.Status)
+	}
+	return nil
+}
@@ -0,0 +1,168 @@
+package filetransfer
+
+import (
+	"io"
+	"net/http"
+	"net/http/httptest"
+	"os"
+	"path/filepath"
+	"sync"
+	"testing"
+	"time"
+
+	"github.com/stretchr/testify/assert"
+	"github.com/stretchr/testify/require"
+	"github.com/wandb/wandb/core/internal/clients"
+	"github.com/wandb/wandb/core/pkg/observability"
+)
+
+// partServer takes uploads in parts, failing the attempts fail says it should
+type partServer struct {
+	sync.Mutex
+	// attempts by Content-Range, and the bytes received of each
+	attempts map[string]int
+	received map[string][]byte
+	fail     func(contentRange string, attempt int) bool
+}
+
+func (s *partServer) ServeHTTP(w http.ResponseWriter, r *http.Request) {
+	body, _ := io.ReadAll(r.Body)
+	s.Lock()
+	defer s.Unlock()
+	contentRange := r.Header.Get("Content-Range")
+	s.attempts[contentRange]++
+	if s.fail != nil && s.fail(contentRange, s.attempts[contentRange]) {
+		w.WriteHeader(http.StatusInternalServerError)
+		return
+	}
+	s.received[contentRange] = body
+	w.WriteHeader(http.StatusOK)
+}
+
+func newPartServer(t *testing.T, fail func(string, int) bool) (*partServer, string) {
+	s := &partServer{
+		attempts: map[string]int{},
+		received: map[string][]byte{},
+		fail:     fail,
+	}
+	server := httptest.NewServer(s)
+	t.Cleanup(server.Close)
+	return s, server.URL
+}
+
+// newPartTransfer uploads files over 10 bytes in parts of 4
+func newPartTransfer(t *testing.T, retryMax int) *DefaultFileTransfer {
+	logger := observability.NewNoOpLogger()
+	client := clients.NewRetryClient(
+		clients.WithRetryClientLogger(logger),
+		clients.WithRetryClientRetryMax(retryMax),
+		clients.WithRetryClientRetryWaitMin(time.Millisecond),
+		clients.WithRetryClientRetryWaitMax(time.Millisecond),
+	)
+	return &DefaultFileTransfer{
+		client:        client,
+		logger:        logger,
+		partThreshold: 10,
+		partSize:      4,
+		stateDir:      t.TempDir(),
+	}
+}
+
+func writeFile(t *testing.T, content string) string {
+	path := filepath.Join(t.TempDir(), "checkpoint.bin")
+	require.NoError(t, os.WriteFile(path, []byte(content), 0o644))
+	return path
+}
+
+func TestUploadParts_RetriesOnlyTheFailedPart(t *testing.T) {
+	// the second part fails midway through its first attempt
+	server, url := newPartServer(t, func(contentRange string, attempt int) bool {
+		return contentRange == "bytes 4-7/14" && attempt == 1
+	})
+	ft := newPartTransfer(t, 3)
+	var progress []int
+	task := &Task{
+		Path: writeFile(t, "0123456789abcd"),
+		Url:  url,
+		ProgressCallback: func(processed, total int) {
+			progress = append(progress, processed)
+		},
+	}
+
+	require.NoError(t, ft.Upload(task))
+	assert.Equal(t, map[string]int{
+		"bytes 0-3/14":   1,
+		"bytes 4-7/14":   2,
+		"bytes 8-11/14":  1,
+		"bytes 12-13/14": 1,
+	}, server.attempts)
+	assert.Equal(t, map[string][]byte{
+		"bytes 0-3/14":   []byte("0123"),
+		"bytes 4-7/14":   []byte("4567"),
+		"bytes 8-11/14":  []byte("89ab"),
+		"bytes 12-13/14": []byte("cd"),
+	}, server.received)
+	assert.Equal(t, []int{4, 8, 12, 14}, progress)
+	// nothing is left to resume
+	_, err := os.Stat(ft.statePath(task))
+	assert.True(t, os.IsNotExist(err))
+}
+
+func TestUploadParts_ResumesAnInterruptedUpload(t *testing.T) {
+	down := true
+	server, url := newPartServer(t, func(contentRange string, attempt int) bool {
+		return down && contentRange == "bytes 8-11/14"
+	})
+	ft := newPartTransfer(t, 1)
+	task := &Task{Path: writeFile(t, "0123456789abcd"), Url: url}
+
+	require.Error(t, ft.Upload(task))
+	assert.Equal(t, map[string]int{
+		"bytes 0-3/14":  1,
+		"bytes 4-7/14":  1,
+		"bytes 8-11/14": 2,
+	}, server.attempts)
+
+	server.Lock()
+	down = false
+	server.Unlock()
+	require.NoError(t, ft.Upload(task))
+	// the parts sent before aren't sent again
+	assert.Equal(t, map[string]int{
+		"bytes 0-3/14":   1,
+		"bytes 4-7/14":   1,
+		"bytes 8-11/14":  3,
+		"bytes 12-13/14": 1,
+	}, server.attempts)
+}
+
+func TestUploadParts_RestartsForAChangedFile(t *testing.T) {
+	down := true
+	server, url := newPartServer(t, func(contentRange string, attempt int) bool {
+		return down && contentRange == "bytes 4-7/14"
+	})
+	ft := newPartTransfer(t, 0)
+	task := &Task{Path: writeFile(t, "0123456789abcd"), Url: url}
+	require.Error(t, ft.Upload(task))
+
+	require.NoError(t, os.WriteFile(task.Path, []byte("ABCDEFGHIJKLMN"), 0o644))
+	// a different modification time, however coarse the clock
+	later := time.Now().Add(time.Hour)
+	require.NoError(t, os.Chtimes(task.Path, later, later))
+	server.Lock()
+	down = false
+	server.Unlock()
+	require.NoError(t, ft.Upload(task))
+	assert.Equal(t, 2, server.attempts["bytes 0-3/14"])
+	assert.Equal(t, []byte("ABCD"), server.received["bytes 0-3/14"])
+}
+
+func TestUpload_SendsSmallFilesWhole(t *testing.T) {
+	server, url := newPartServer(t, nil)
+	ft := newPartTransfer(t, 0)
+	task := &Task{Path: writeFile(t, "0123456789"), Url: url}
+
+	require.NoError(t, ft.Upload(task))
+	assert.Equal(t, map[string]int{"": 1}, server.attempts)
+	assert.Equal(t, []byte("0123456789"), server.received[""])
+}
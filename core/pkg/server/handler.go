@@ -10,6 +10,7 @@ import (
 	"github.com/wandb/wandb/core/pkg/monitor"
 	"google.golang.org/protobuf/encoding/protojson"
 	"google.golang.org/protobuf/proto"
+	"google.golang.org/protobuf/reflect/protoreflect"
 
 	"github.com/wandb/wandb/core/internal/corelib"
 	"github.com/wandb/wandb/core/internal/version"
@@ -115,6 +116,9 @@ type Handler struct {
 	// runRecord is the runRecord record received from the server
 	runRecord *service.RunRecord
 
+	// metadata is the metadata of the run, as far as it is known
+	metadata *service.MetadataRequest
+
 	// summaryHandler is the summary handler for the stream
 	summaryHandler *SummaryHandler
 
@@ -294,6 +298,9 @@ func (h *Handler) handleRequest(record *service.Record) {
 	case *service.Request_PythonPackages:
 		h.handlePythonPackages(record, x.PythonPackages)
 		response = nil
+	case *service.Request_Metadata:
+		h.handleMetadata(x.Metadata)
+		response = nil
 	case *service.Request_Shutdown:
 	case *service.Request_StopStatus:
 	case *service.Request_LogArtifact:
@@ -668,6 +675,19 @@ func (h *Handler) handlePatchSave() {
 	h.handleFiles(record)
 }
 
+// mergeMetadata adds the fields set in update to metadata. Unlike with
+// proto.Merge, the lists it sets, like args, replace the ones there.
+func mergeMetadata(metadata, update *service.MetadataRequest) {
+	target := metadata.ProtoReflect()
+	update.ProtoReflect().Range(func(fd protoreflect.FieldDescriptor, _ protoreflect.Value) bool {
+		if fd.IsList() {
+			target.Clear(fd)
+		}
+		return true
+	})
+	proto.Merge(metadata, update)
+}
+
 func (h *Handler) handleMetadata(request *service.MetadataRequest) {
 	// TODO: Sending metadata as a request for now, eventually this should be turned into
 	//  a record and stored in the transaction log
@@ -675,11 +695,18 @@ func (h *Handler) handleMetadata(request *service.MetadataRequest) {
 		return
 	}
 
+	// clients may add what they know about their environment later on
+	if h.metadata == nil {
+		h.metadata = request
+	} else {
+		mergeMetadata(h.metadata, request)
+	}
+
 	mo := protojson.MarshalOptions{
 		Indent: "  ",
 		// EmitUnpopulated: true,
 	}
-	jsonBytes, err := mo.Marshal(request)
+	jsonBytes, err := mo.Marshal(h.metadata)
 	if err != nil {
 		h.logger.CaptureError("error marshalling metadata", err)
 		return
@@ -2,10 +2,17 @@ package server_test
 
 import (
 	"context"
+	"os"
+	"path/filepath"
+	"testing"
+	"time"
 
+	"github.com/stretchr/testify/assert"
 	"github.com/wandb/wandb/core/pkg/observability"
 	server "github.com/wandb/wandb/core/pkg/server"
 	"github.com/wandb/wandb/core/pkg/service"
+	"google.golang.org/protobuf/encoding/protojson"
+	"google.golang.org/protobuf/types/known/wrapperspb"
 )
 
 func makeInboundChannels() (chan *service.Record, chan *service.Record) {
@@ -36,3 +43,50 @@ func makeHandler(
 
 	return h
 }
+
+func metadataRecord(metadata *service.MetadataRequest) *service.Record {
+	return &service.Record{
+		RecordType: &service.Record_Request{
+			Request: &service.Request{
+				RequestType: &service.Request_Metadata{Metadata: metadata},
+			},
+		},
+	}
+}
+
+func TestHandleMetadata_ReplacesLists(t *testing.T) {
+	filesDir := t.TempDir()
+	inChan, _ := makeInboundChannels()
+	fwdChan, outChan := makeOutboundChannels()
+	h := server.NewHandler(context.Background(),
+		observability.NewNoOpLogger(),
+		server.WithHandlerSettings(&service.Settings{
+			FilesDir: &wrapperspb.StringValue{Value: filesDir},
+		}),
+		server.WithHandlerFwdChannel(fwdChan),
+		server.WithHandlerOutChannel(outChan),
+	)
+	go h.Do(inChan)
+	defer close(inChan)
+
+	inChan <- metadataRecord(&service.MetadataRequest{
+		Host: "host",
+		Args: []string{"--lr", "0.1"},
+	})
+	inChan <- metadataRecord(&service.MetadataRequest{
+		Os:   "Linux",
+		Args: []string{"--lr", "0.2"},
+	})
+
+	// written again for the second request
+	written := &service.MetadataRequest{}
+	assert.Eventually(t, func() bool {
+		data, err := os.ReadFile(filepath.Join(filesDir, server.MetaFileName))
+		if err != nil {
+			return false
+		}
+		return protojson.Unmarshal(data, written) == nil && written.GetOs() == "Linux"
+	}, 5*time.Second, 10*time.Millisecond)
+	assert.Equal(t, "host", written.GetHost())
+	assert.Equal(t, []string{"--lr", "0.2"}, written.GetArgs())
+}
@@ -76,6 +76,58 @@ func TestSendRun(t *testing.T) {
 		func(vars coretest.RequestVars) {
 			assert.Equal(t, "testEntity", vars["entity"])
 			assert.Equal(t, "testProject", vars["project"])
+			assert.Nil(t, vars["sweep"])
+		},
+	))
+
+	sender.SendRecord(run)
+	<-sender.GetOutboundChannel()
+}
+
+func TestSendRun_PassesTheSweep(t *testing.T) {
+	// Verify that the sweep a run belongs to is passed through to graphql
+	to := coretest.MakeTestObject(t)
+	defer to.TeardownTest()
+
+	sender := makeSender(to.MockClient, make(chan *service.Result, 1))
+
+	run := &service.Record{
+		RecordType: &service.Record_Run{
+			Run: &service.RunRecord{
+				Config:  to.MakeConfig(),
+				Project: "testProject",
+				Entity:  "testEntity",
+				SweepId: "sweep1",
+			}},
+		Control: &service.Control{
+			MailboxSlot: "junk",
+		},
+	}
+
+	respEncode := &graphql.Response{
+		Data: &gql.UpsertBucketResponse{
+			UpsertBucket: &gql.UpsertBucketUpsertBucketUpsertBucketPayload{
+				Bucket: &gql.UpsertBucketUpsertBucketUpsertBucketPayloadBucketRun{
+					DisplayName: coretest.StrPtr("FakeName"),
+					Project: &gql.UpsertBucketUpsertBucketUpsertBucketPayloadBucketRunProject{
+						Name: "FakeProject",
+						Entity: gql.UpsertBucketUpsertBucketUpsertBucketPayloadBucketRunProjectEntity{
+							Name: "FakeEntity",
+						},
+					},
+				},
+			},
+		},
+	}
+
+	to.MockClient.EXPECT().MakeRequest(
+		gomock.Any(), // context.Context
+		gomock.Any(), // *graphql.Request
+		gomock.Any(), // *graphql.Response
+	).Return(nil).Do(coretest.InjectResponse(
+		respEncode,
+		func(vars coretest.RequestVars) {
+			assert.Equal(t, "sweep1", vars["sweep"])
 		},
 	))
 